@@ -9,8 +9,14 @@ mod llama_bindings;
 pub use llama_bindings::{
     llama_backend_free, llama_backend_init, llama_context, llama_context_default_params,
     llama_eval, llama_free, llama_free_model, llama_get_logits, llama_get_timings,
-    llama_load_model_from_file, llama_model, llama_n_vocab, llama_new_context_with_model,
-    llama_reset_timings, llama_sample_grammar, llama_sample_token, llama_sample_token_greedy,
-    llama_sample_top_k, llama_time_us, llama_token, llama_token_bos, llama_token_data,
-    llama_token_data_array, llama_token_eos, llama_token_get_text, llama_token_nl, llama_tokenize,
+    llama_load_model_from_file, llama_model, llama_n_ctx, llama_n_vocab,
+    llama_new_context_with_model, llama_print_system_info, llama_reset_timings,
+    llama_sample_frequency_and_presence_penalties, llama_sample_grammar,
+    llama_sample_repetition_penalty, llama_sample_temperature, llama_sample_token,
+    llama_sample_token_greedy, llama_sample_top_k, llama_sample_top_p, llama_set_rng_seed,
+    llama_time_us, llama_token, llama_token_bos, llama_token_data, llama_token_data_array,
+    llama_token_eos, llama_token_get_text, llama_token_get_type, llama_token_nl,
+    llama_token_to_piece, llama_token_type_LLAMA_TOKEN_TYPE_CONTROL,
+    llama_token_type_LLAMA_TOKEN_TYPE_UNKNOWN, llama_token_type_LLAMA_TOKEN_TYPE_USER_DEFINED,
+    llama_tokenize, llama_vocab_type, llama_vocab_type_LLAMA_VOCAB_TYPE_SPM,
 };
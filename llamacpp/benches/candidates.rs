@@ -0,0 +1,79 @@
+//! Compare building the sampling candidates with a fresh allocation for every token against reusing
+//! one buffer for the whole generation, as [llamacpp::bench::fill_candidates] does.
+//!
+//! The system allocator usually hands the same block straight back, so timings are close. The number
+//! of allocations per generation, which is printed before the timings, shows the difference.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use llamacpp::bench::fill_candidates;
+use llamacpp_sys::llama_token_data;
+
+/// Vocabulary size of the LLaMA family of models.
+const N_VOCAB: usize = 32_000;
+
+/// Number of tokens sampled per iteration, the length of a typical short generation.
+const N_TOKENS: usize = 20;
+
+/// Wraps the system allocator to count every allocation.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocate_per_token(logits: &[f32]) {
+    for _ in 0..N_TOKENS {
+        let mut candidates: Vec<llama_token_data> = Vec::with_capacity(N_VOCAB);
+        fill_candidates(&mut candidates, black_box(logits));
+        black_box(&candidates);
+    }
+}
+
+fn reuse_buffer(logits: &[f32]) {
+    let mut candidates: Vec<llama_token_data> = Vec::with_capacity(N_VOCAB);
+    for _ in 0..N_TOKENS {
+        fill_candidates(&mut candidates, black_box(logits));
+        black_box(&candidates);
+    }
+}
+
+fn count_allocations(name: &str, f: impl Fn(&[f32]), logits: &[f32]) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f(logits);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("{name}: {allocations} allocations for {N_TOKENS} tokens");
+}
+
+fn candidates(c: &mut Criterion) {
+    let logits: Vec<f32> = (0..N_VOCAB).map(|i| (i % 97) as f32 / 97.0).collect();
+
+    count_allocations("candidates/allocate_per_token", allocate_per_token, &logits);
+    count_allocations("candidates/reuse_buffer", reuse_buffer, &logits);
+
+    c.bench_function("candidates/allocate_per_token", |b| {
+        b.iter(|| allocate_per_token(&logits))
+    });
+    c.bench_function("candidates/reuse_buffer", |b| {
+        b.iter(|| reuse_buffer(&logits))
+    });
+}
+
+criterion_group!(benches, candidates);
+criterion_main!(benches);
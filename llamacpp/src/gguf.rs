@@ -0,0 +1,341 @@
+//! Reader for the metadata section of GGUF model files.
+//!
+//! This only reads the key-value metadata at the start of the file, not the tensors, so it's cheap
+//! enough to call before deciding how (or whether) to load a model.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// The first four bytes of every GGUF file.
+pub const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Quantizations llama.cpp can load, as `(general.file_type, name)`, ordered from fewest to most
+/// bits per weight. Smaller quantizations load faster and use less memory, at some cost in quality.
+const QUANTIZATIONS: &[(u64, &str)] = &[
+    (10, "Q2_K"),
+    (11, "Q3_K_S"),
+    (12, "Q3_K_M"),
+    (13, "Q3_K_L"),
+    (2, "Q4_0"),
+    (14, "Q4_K_S"),
+    (15, "Q4_K_M"),
+    (3, "Q4_1"),
+    (8, "Q5_0"),
+    (16, "Q5_K_S"),
+    (17, "Q5_K_M"),
+    (9, "Q5_1"),
+    (18, "Q6_K"),
+    (7, "Q8_0"),
+    (1, "F16"),
+    (0, "F32"),
+];
+
+/// Position of a quantization, e.g. `Q4_K_M`, when ordered from smallest to largest. Case-insensitive.
+pub fn quantization_rank(name: &str) -> Option<usize> {
+    QUANTIZATIONS
+        .iter()
+        .position(|(_, known)| known.eq_ignore_ascii_case(name))
+}
+
+/// Longest string we're willing to read, to fail fast on corrupt files rather than allocate wildly.
+const MAX_STRING_LEN: u64 = 16 * 1024 * 1024;
+
+/// Most items we'll read from an array. Vocabularies, the largest arrays in practice, have a few
+/// hundred thousand entries.
+const MAX_ARRAY_LEN: u64 = 16 * 1024 * 1024;
+
+/// Deepest nesting of arrays in arrays we'll read, so a corrupt file can't recurse until the stack
+/// runs out.
+const MAX_ARRAY_DEPTH: usize = 8;
+
+/// A metadata value. Integers keep the width they were stored with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    /// The value as a u64, if it's a non-negative integer of any width.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata from the header of a GGUF file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufMetadata {
+    pub version: u32,
+    pub tensor_count: u64,
+    pub kv: HashMap<String, GgufValue>,
+}
+
+impl GgufMetadata {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("failed to read GGUF metadata from {:?}", path))
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut reader = GgufReader { reader, version: 0 };
+
+        let mut magic = [0u8; 4];
+        reader.reader.read_exact(&mut magic)?;
+        if &magic != GGUF_MAGIC {
+            return Err(anyhow!("not a GGUF file"));
+        }
+
+        reader.version = reader.read_u32()?;
+        if !(1..=3).contains(&reader.version) {
+            return Err(anyhow!("unsupported GGUF version {}", reader.version));
+        }
+
+        let tensor_count = reader.read_len()?;
+        let kv_count = reader.read_len()?;
+        let mut kv = HashMap::new();
+        for _ in 0..kv_count {
+            let key = reader.read_string()?;
+            let value_type = reader.read_u32()?;
+            let value = reader
+                .read_value(value_type, 0)
+                .with_context(|| format!("failed to read value of {}", key))?;
+            kv.insert(key, value);
+        }
+
+        Ok(Self {
+            version: reader.version,
+            tensor_count,
+            kv,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.kv.get(key)
+    }
+
+    /// The model architecture, e.g. `llama`. Architecture-specific keys are prefixed with it.
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    /// Name of the quantization the weights are stored with, e.g. `Q4_K_M`, if it's one we know.
+    pub fn quantization(&self) -> Option<&'static str> {
+        let file_type = self.get("general.file_type")?.as_u64()?;
+        QUANTIZATIONS
+            .iter()
+            .find(|(known, _)| *known == file_type)
+            .map(|(_, name)| *name)
+    }
+
+    /// Look up an architecture-specific integer, e.g. `context_length` for `llama.context_length`.
+    pub fn arch_u64(&self, key: &str) -> Option<u64> {
+        let architecture = self.architecture()?;
+        self.get(&format!("{}.{}", architecture, key))?.as_u64()
+    }
+}
+
+struct GgufReader<R> {
+    reader: R,
+    version: u32,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes()?))
+    }
+
+    /// Counts and lengths were widened from 32 to 64 bits in version 2.
+    fn read_len(&mut self) -> Result<u64> {
+        if self.version == 1 {
+            Ok(self.read_u32()? as u64)
+        } else {
+            self.read_u64()
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_len()?;
+        if len > MAX_STRING_LEN {
+            return Err(anyhow!("string of {} bytes is too long", len));
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.reader.read_exact(&mut buf)?;
+        String::from_utf8(buf).context("string is not valid UTF-8")
+    }
+
+    /// Read a value of `value_type`, found inside `depth` arrays.
+    fn read_value(&mut self, value_type: u32, depth: usize) -> Result<GgufValue> {
+        Ok(match value_type {
+            0 => GgufValue::U8(u8::from_le_bytes(self.read_bytes()?)),
+            1 => GgufValue::I8(i8::from_le_bytes(self.read_bytes()?)),
+            2 => GgufValue::U16(u16::from_le_bytes(self.read_bytes()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.read_bytes()?)),
+            4 => GgufValue::U32(self.read_u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.read_bytes()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.read_bytes()?)),
+            7 => GgufValue::Bool(self.read_bytes::<1>()?[0] != 0),
+            8 => GgufValue::String(self.read_string()?),
+            9 => {
+                if depth >= MAX_ARRAY_DEPTH {
+                    return Err(anyhow!("arrays nested more than {} deep", MAX_ARRAY_DEPTH));
+                }
+                let item_type = self.read_u32()?;
+                let len = self.read_len()?;
+                if len > MAX_ARRAY_LEN {
+                    return Err(anyhow!("array of {} items is too long", len));
+                }
+
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    items.push(self.read_value(item_type, depth + 1)?);
+                }
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::U64(self.read_u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.read_bytes()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.read_bytes()?)),
+            _ => return Err(anyhow!("unknown value type {}", value_type)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{quantization_rank, GgufMetadata, GgufValue};
+
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend((s.len() as u64).to_le_bytes());
+        buf.extend(s.as_bytes());
+    }
+
+    /// Build a version 3 GGUF header with the given metadata.
+    fn header(kv: &[(&str, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = b"GGUF".to_vec();
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+        buf.extend((kv.len() as u64).to_le_bytes());
+        for (key, value_type, value) in kv {
+            string(&mut buf, key);
+            buf.extend(value_type.to_le_bytes());
+            buf.extend(value);
+        }
+
+        buf
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let mut architecture = Vec::new();
+        string(&mut architecture, "llama");
+        let mut tokens = 8u32.to_le_bytes().to_vec();
+        tokens.extend(2u64.to_le_bytes());
+        string(&mut tokens, "<s>");
+        string(&mut tokens, "</s>");
+
+        let buf = header(&[
+            ("general.architecture", 8, architecture),
+            ("llama.context_length", 4, 4096u32.to_le_bytes().to_vec()),
+            ("llama.block_count", 10, 32u64.to_le_bytes().to_vec()),
+            ("general.file_type", 5, 2i32.to_le_bytes().to_vec()),
+            ("tokenizer.ggml.tokens", 9, tokens),
+        ]);
+
+        let metadata = GgufMetadata::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(metadata.version, 3);
+        assert_eq!(metadata.architecture(), Some("llama"));
+        assert_eq!(metadata.arch_u64("context_length"), Some(4096));
+        assert_eq!(metadata.arch_u64("block_count"), Some(32));
+        assert_eq!(metadata.arch_u64("missing"), None);
+        assert_eq!(
+            metadata
+                .get("general.file_type")
+                .and_then(GgufValue::as_u64),
+            Some(2)
+        );
+        assert_eq!(metadata.quantization(), Some("Q4_0"));
+        assert_eq!(
+            metadata.get("tokenizer.ggml.tokens"),
+            Some(&GgufValue::Array(vec![
+                GgufValue::String("<s>".to_owned()),
+                GgufValue::String("</s>".to_owned()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_quantization_rank() {
+        assert!(quantization_rank("q4_k_m") < quantization_rank("Q5_K_M"));
+        assert!(quantization_rank("Q8_0") < quantization_rank("F16"));
+        assert_eq!(quantization_rank("Q9_X"), None);
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        assert!(GgufMetadata::from_reader(&b"ggjt\x03\x00\x00\x00"[..]).is_err());
+
+        // Truncated partway through the metadata.
+        let buf = header(&[("llama.context_length", 4, 4096u32.to_le_bytes().to_vec())]);
+        assert!(GgufMetadata::from_reader(&buf[..buf.len() - 2]).is_err());
+
+        // An array claiming more items than any real file has.
+        let mut array = 4u32.to_le_bytes().to_vec();
+        array.extend(u64::MAX.to_le_bytes());
+        let buf = header(&[("tokenizer.ggml.scores", 9, array)]);
+        assert!(GgufMetadata::from_reader(buf.as_slice()).is_err());
+
+        // Arrays of arrays, nested far deeper than the stack should have to go.
+        let mut nested = Vec::new();
+        for _ in 0..1000 {
+            nested.extend(9u32.to_le_bytes());
+            nested.extend(1u64.to_le_bytes());
+        }
+        let buf = header(&[("general.nested", 9, nested)]);
+        let err = GgufMetadata::from_reader(buf.as_slice()).unwrap_err();
+        assert!(format!("{:#}", err).contains("nested"));
+    }
+}
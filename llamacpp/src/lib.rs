@@ -1,19 +1,310 @@
-use anyhow::{Context, Error, Result};
+use anyhow::{anyhow, Context, Error, Result};
+use fs2::FileExt;
+use log::{info, warn};
 use std::{
-    ffi::{CStr, CString},
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    ffi::{c_char, CStr, CString},
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    ops::Range,
     path::{Path, PathBuf},
     ptr::NonNull,
+    slice,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc::Sender,
 };
-use tokio::sync::mpsc::Sender;
 
 use llamacpp_sys::{
     llama_backend_free, llama_backend_init, llama_context, llama_context_default_params,
-    llama_eval, llama_free, llama_free_model, llama_get_logits, llama_load_model_from_file,
-    llama_model, llama_n_vocab, llama_new_context_with_model, llama_sample_token, llama_token,
-    llama_token_bos, llama_token_data, llama_token_data_array, llama_token_eos,
-    llama_token_get_text, llama_token_nl, llama_tokenize,
+    llama_eval, llama_free, llama_free_model, llama_get_logits, llama_get_timings,
+    llama_load_model_from_file, llama_model, llama_n_ctx, llama_n_vocab,
+    llama_new_context_with_model, llama_reset_timings,
+    llama_sample_frequency_and_presence_penalties, llama_sample_repetition_penalty,
+    llama_sample_temperature, llama_sample_token, llama_sample_token_greedy, llama_sample_top_p,
+    llama_set_rng_seed, llama_time_us, llama_token, llama_token_bos, llama_token_data,
+    llama_token_data_array, llama_token_eos, llama_token_get_text, llama_token_get_type,
+    llama_token_nl, llama_token_to_piece, llama_token_type_LLAMA_TOKEN_TYPE_CONTROL,
+    llama_token_type_LLAMA_TOKEN_TYPE_UNKNOWN, llama_token_type_LLAMA_TOKEN_TYPE_USER_DEFINED,
+    llama_tokenize, llama_vocab_type, llama_vocab_type_LLAMA_VOCAB_TYPE_SPM,
 };
 
+/// Upper bound on the number of tokens generated per request.
+const MAX_NEW_TOKENS: usize = 20;
+
+/// Number of threads used to evaluate the model.
+pub const N_THREADS: i32 = 4;
+
+/// Number of tokens [Model::generate_to_writer] writes between flushes.
+const FLUSH_EVERY_TOKENS: usize = 8;
+
+/// Number of most recent tokens considered by the repetition, frequency and presence penalties.
+const PENALTY_LAST_N: usize = 64;
+
+/// Memory set aside for llama.cpp's scratch buffers when fitting [ContextSize::Auto] to a budget.
+const CONTEXT_OVERHEAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// [ContextSize::Auto] rounds down to a multiple of this, and won't pick anything smaller.
+const AUTO_CONTEXT_GRANULARITY: u64 = 256;
+
+/// Most alternatives [GenerateParams::top_alternatives] may ask for at each step.
+pub const MAX_TOP_ALTERNATIVES: u32 = 20;
+
+/// Text longer than this many bytes is tokenized in segments of about this size, see
+/// [Model::tokenize_chunks].
+pub const TOKENIZE_CHUNK_BYTES: usize = 64 * 1024;
+
+pub mod gguf;
+pub mod system;
+
+/// How big a context window to create when loading a model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContextSize {
+    /// Whatever llama.cpp defaults to.
+    #[default]
+    Default,
+    /// Exactly this many tokens.
+    Explicit(u32),
+    /// The largest context whose KV cache fits in the budget alongside the model weights, up to
+    /// the context length the model was trained with. Only supported for GGUF models.
+    Auto { memory_budget_bytes: u64 },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadParams {
+    pub context_size: ContextSize,
+
+    /// Refuse to load a model whose estimated footprint, its weights plus the KV cache for its
+    /// context, is over this many bytes, failing with [MemoryLimitExceeded] instead.
+    pub max_memory_bytes: Option<u64>,
+
+    /// Load from a model store that other processes share and nothing may write to, e.g. an NFS or
+    /// EFS mount several servers read their weights from.
+    ///
+    /// llama.cpp is loaded with `use_mmap = true` and `use_mlock = false`: it maps the file
+    /// `PROT_READ`/`MAP_SHARED`, so every process is served the same read-only pages from the page
+    /// cache, there is no private copy-on-write mapping, and nothing is written back to the file or
+    /// next to it. A shared advisory lock is held on the file while the model is loaded, so a process
+    /// replacing files in the store can take an exclusive lock to wait until no server has them
+    /// mapped, and loads wait for a writer holding one to finish.
+    pub read_only: bool,
+}
+
+impl LoadParams {
+    /// The context size, in tokens, a model at `path` gets when loaded with these params, the same
+    /// as [Model::n_ctx] once it is. Reads the model's GGUF metadata for [ContextSize::Auto].
+    pub fn n_ctx(&self, path: &Path) -> Result<u32> {
+        match self.context_size {
+            ContextSize::Default => Ok(unsafe { llama_context_default_params() }.n_ctx as u32),
+            ContextSize::Explicit(n_ctx) => Ok(n_ctx),
+            ContextSize::Auto {
+                memory_budget_bytes,
+            } => auto_context_size(path, memory_budget_bytes),
+        }
+    }
+}
+
+/// Returned when loading a model would take more memory than [LoadParams::max_memory_bytes] allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryLimitExceeded {
+    pub estimated_bytes: u64,
+    pub limit_bytes: u64,
+}
+
+impl fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "model needs an estimated {} bytes of memory, over the limit of {} bytes",
+            self.estimated_bytes, self.limit_bytes
+        )
+    }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+/// Error for a prompt that can't be generated from with the context it would run in, as opposed to a
+/// failure of the model itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptError {
+    /// The prompt tokenized to nothing.
+    Empty,
+
+    /// [GenerateParams::reserve_tokens] leaves no room in the context for the prompt.
+    ReserveTooLarge { reserve_tokens: usize, n_ctx: usize },
+
+    /// The prompt fills the context, leaving no room to generate anything.
+    TooLong { n_tokens: usize, n_ctx: usize },
+}
+
+impl fmt::Display for PromptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PromptError::Empty => write!(f, "prompt produced no tokens"),
+            PromptError::ReserveTooLarge {
+                reserve_tokens,
+                n_ctx,
+            } => write!(
+                f,
+                "cannot reserve {} tokens in a context of {} tokens",
+                reserve_tokens, n_ctx
+            ),
+            PromptError::TooLong { n_tokens, n_ctx } => write!(
+                f,
+                "prompt of {} tokens does not fit in a context of {} tokens",
+                n_tokens, n_ctx
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PromptError {}
+
+/// What a model needs memory for, from its GGUF metadata.
+struct MemoryProfile {
+    weights_bytes: u64,
+    kv_bytes_per_token: u64,
+    trained_n_ctx: u64,
+}
+
+impl MemoryProfile {
+    /// Weights, KV cache and scratch buffers for a context of `n_ctx` tokens.
+    fn footprint(&self, n_ctx: u64) -> u64 {
+        self.weights_bytes + CONTEXT_OVERHEAD_BYTES + self.kv_bytes_per_token * n_ctx
+    }
+}
+
+/// Estimated memory taken by the model at `path` once loaded with a context of `n_ctx` tokens. Without
+/// readable GGUF metadata, the size of the KV cache is unknown and only the weights are counted.
+pub fn estimate_memory(path: &Path, n_ctx: u32) -> Result<u64> {
+    match memory_profile(path) {
+        Ok(profile) => Ok(profile.footprint(n_ctx as u64)),
+        Err(err) => {
+            warn!(
+                "can't estimate KV cache size of {:?}, counting only its weights: {:#}",
+                path, err
+            );
+            let weights_bytes = fs::metadata(path)
+                .with_context(|| format!("failed to stat {:?}", path))?
+                .len();
+            Ok(weights_bytes + CONTEXT_OVERHEAD_BYTES)
+        }
+    }
+}
+
+fn check_memory_limit(estimated_bytes: u64, limit_bytes: u64) -> Result<(), MemoryLimitExceeded> {
+    if estimated_bytes > limit_bytes {
+        return Err(MemoryLimitExceeded {
+            estimated_bytes,
+            limit_bytes,
+        });
+    }
+
+    Ok(())
+}
+
+/// Open `path` and take a shared lock on it, waiting for any process holding an exclusive lock, e.g.
+/// one writing a new version of the file into a shared store. The lock lasts until the file is closed.
+fn lock_shared(path: &Path) -> Result<fs::File> {
+    let file = fs::File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    if FileExt::try_lock_shared(&file).is_err() {
+        info!("waiting for the writer of {:?} to release its lock", path);
+        FileExt::lock_shared(&file).with_context(|| format!("failed to lock {:?}", path))?;
+    }
+
+    Ok(file)
+}
+
+/// Read what a model needs memory for from its GGUF metadata.
+fn memory_profile(path: &Path) -> Result<MemoryProfile> {
+    let metadata = gguf::GgufMetadata::read(path)?;
+    let required = |key: &str| {
+        metadata
+            .arch_u64(key)
+            .ok_or_else(|| anyhow!("GGUF metadata is missing {}", key))
+    };
+
+    let trained_n_ctx = required("context_length")?;
+    let n_layer = required("block_count")?;
+    let n_embd = required("embedding_length")?;
+    let n_head = required("attention.head_count")?;
+    // Models without grouped-query attention don't bother to record this.
+    let n_head_kv = metadata
+        .arch_u64("attention.head_count_kv")
+        .unwrap_or(n_head);
+    if n_head == 0 {
+        return Err(anyhow!("GGUF metadata has zero attention heads"));
+    }
+
+    // One f16 key and one f16 value per layer for every token in the context.
+    let kv_bytes_per_token = 2 * n_layer * (n_embd * n_head_kv / n_head) * 2;
+    let weights_bytes = fs::metadata(path)
+        .with_context(|| format!("failed to stat {:?}", path))?
+        .len();
+
+    Ok(MemoryProfile {
+        weights_bytes,
+        kv_bytes_per_token,
+        trained_n_ctx,
+    })
+}
+
+/// Pick a context size for [ContextSize::Auto] from the model's GGUF metadata.
+fn auto_context_size(path: &Path, memory_budget_bytes: u64) -> Result<u32> {
+    let profile = memory_profile(path)?;
+    let trained_n_ctx = profile.trained_n_ctx;
+    let n_ctx = fit_context_size(
+        memory_budget_bytes,
+        profile.weights_bytes,
+        profile.kv_bytes_per_token,
+        trained_n_ctx,
+    )?;
+
+    info!(
+        "auto context size for {:?}: {} tokens ({} byte budget, trained with {})",
+        path, n_ctx, memory_budget_bytes, trained_n_ctx
+    );
+    if (n_ctx as u64) < trained_n_ctx {
+        warn!(
+            "{:?} was trained with a {} token context but only {} fit in the memory budget",
+            path, trained_n_ctx, n_ctx
+        );
+    }
+
+    Ok(n_ctx)
+}
+
+/// The largest context, rounded down to [AUTO_CONTEXT_GRANULARITY], whose KV cache fits in what's
+/// left of the budget after the weights, capped at the trained context length.
+fn fit_context_size(
+    memory_budget_bytes: u64,
+    weights_bytes: u64,
+    kv_bytes_per_token: u64,
+    trained_n_ctx: u64,
+) -> Result<u32> {
+    let available = memory_budget_bytes
+        .saturating_sub(weights_bytes)
+        .saturating_sub(CONTEXT_OVERHEAD_BYTES);
+    let fits = available / kv_bytes_per_token.max(1);
+    let n_ctx = fits.min(trained_n_ctx) / AUTO_CONTEXT_GRANULARITY * AUTO_CONTEXT_GRANULARITY;
+
+    if n_ctx == 0 {
+        return Err(anyhow!(
+            "memory budget of {} bytes leaves no room for a context after {} bytes of weights",
+            memory_budget_bytes,
+            weights_bytes
+        ));
+    }
+
+    u32::try_from(n_ctx).context("context size is too large")
+}
+
 pub struct Backend;
 
 impl Backend {
@@ -26,6 +317,10 @@ impl Backend {
     pub fn load_model(&self, path: &PathBuf) -> Result<Model> {
         Model::new(path)
     }
+
+    pub fn load_model_with_params(&self, path: &Path, params: &LoadParams) -> Result<Model> {
+        Model::with_params(path, params)
+    }
 }
 
 impl Default for Backend {
@@ -44,10 +339,20 @@ pub struct Model {
     source: PathBuf,
     ctx: NonNull<llama_context>,
     model: NonNull<llama_model>,
+    n_ctx: i32,
     n_vocab: i32,
     token_bos: llama_token,
     token_eos: llama_token,
     token_nl: llama_token,
+
+    /// Control, user-defined and unknown tokens by their vocabulary text, see [Model::find_token].
+    special_tokens: HashMap<String, llama_token>,
+
+    /// What later generations may reuse from the KV cache, see [Model::reset_context].
+    kv: KvState,
+
+    /// The model file, open to hold a shared lock on it while loaded, see [LoadParams::read_only].
+    _store_lock: Option<fs::File>,
 }
 
 unsafe impl Send for Model {}
@@ -64,8 +369,31 @@ impl Drop for Model {
 
 impl Model {
     pub fn new(path: &Path) -> Result<Self> {
-        let (ctx, model, n_vocab, token_bos, token_eos, token_nl) = unsafe {
-            let params = llama_context_default_params();
+        Self::with_params(path, &LoadParams::default())
+    }
+
+    pub fn with_params(path: &Path, load_params: &LoadParams) -> Result<Self> {
+        let requested_n_ctx = load_params.n_ctx(path)?;
+
+        // Checked before loading, since loading is what would exhaust the memory.
+        if let Some(limit_bytes) = load_params.max_memory_bytes {
+            let estimated_bytes = estimate_memory(path, requested_n_ctx)?;
+            check_memory_limit(estimated_bytes, limit_bytes)?;
+        }
+
+        let store_lock = if load_params.read_only {
+            Some(lock_shared(path)?)
+        } else {
+            None
+        };
+
+        let (ctx, model, n_ctx, n_vocab, token_bos, token_eos, token_nl) = unsafe {
+            let mut params = llama_context_default_params();
+            params.n_ctx = i32::try_from(requested_n_ctx).context("context size is too large")?;
+            if load_params.read_only {
+                params.use_mmap = true;
+                params.use_mlock = false;
+            }
             let path_c_str = CString::new(path.to_str().expect("Could not convert PathBuf to str"))
                 .expect("Could not convert to CString");
 
@@ -80,6 +408,7 @@ impl Model {
                 return Err(Error::msg("llama_context is NULL"));
             }
 
+            let n_ctx = llama_n_ctx(ctx);
             let n_vocab = llama_n_vocab(ctx);
             let token_bos = llama_token_bos(ctx);
             let token_eos = llama_token_eos(ctx);
@@ -89,6 +418,7 @@ impl Model {
             (
                 NonNull::new_unchecked(ctx),
                 NonNull::new_unchecked(model),
+                n_ctx,
                 n_vocab,
                 token_bos,
                 token_eos,
@@ -96,136 +426,560 @@ impl Model {
             )
         };
 
-        Ok(Model {
+        let mut model = Model {
             source: path.to_path_buf(),
             ctx,
             model,
+            n_ctx,
             n_vocab,
             token_bos,
             token_eos,
             token_nl,
-        })
+            special_tokens: HashMap::new(),
+            kv: KvState::default(),
+            _store_lock: store_lock,
+        };
+        model.special_tokens = model.collect_special_tokens();
+
+        Ok(model)
     }
 
-    pub fn generate(&mut self, prompt: &str) -> String {
-        // Tokenize the prompt, set it, and then run EVAL to get the target outputs
-        let mut tokens = [0i32; 4096];
-        let prompt_c_str = CString::new(prompt).expect("unable to cast &str to CString");
-        let prompt_tokens = unsafe {
-            llama_tokenize(
-                self.ctx.as_mut(),
-                prompt_c_str.as_ptr(),
-                tokens.as_mut_ptr(),
-                4096,
-                false,
-            )
-        };
-        assert!(prompt_tokens > 0, "No tokens generated");
+    fn collect_special_tokens(&self) -> HashMap<String, llama_token> {
+        (0..self.n_vocab)
+            .filter(|&token_id| {
+                let token_type = unsafe { llama_token_get_type(self.ctx.as_ptr(), token_id) };
+                token_type == llama_token_type_LLAMA_TOKEN_TYPE_CONTROL
+                    || token_type == llama_token_type_LLAMA_TOKEN_TYPE_USER_DEFINED
+                    || token_type == llama_token_type_LLAMA_TOKEN_TYPE_UNKNOWN
+            })
+            .map(|token_id| (self.token_text(token_id, true), token_id))
+            .collect()
+    }
+
+    /// Size of the context window, in tokens. Prompt and completion together must fit within it.
+    pub fn n_ctx(&self) -> u32 {
+        self.n_ctx as u32
+    }
+
+    /// Forget everything the KV cache holds from earlier generations, so the next one evaluates its
+    /// whole prompt from scratch. llama.cpp overwrites the cache from the first position a generation
+    /// evaluates onwards, so once nothing is marked reusable, nothing from an earlier request can leak
+    /// into a later one. Generations call this themselves unless they ask to reuse the cache through
+    /// [GenerateParams::shared_prefix_tokens] or [GenerateParams::continuation_key].
+    pub fn reset_context(&mut self) {
+        self.kv.reset();
+    }
+
+    /// Number of tokens in the model's vocabulary. Token IDs range from 0 up to this value.
+    pub fn n_vocab(&self) -> u32 {
+        self.n_vocab as u32
+    }
+
+    /// Convert text into the model's tokens. No BOS token is prepended. Long text is tokenized in
+    /// segments, see [Model::tokenize_chunks], which comes out the same as doing it in one go.
+    pub fn tokenize(&mut self, text: &str) -> Result<Vec<llama_token>> {
+        if text.len() <= TOKENIZE_CHUNK_BYTES {
+            return self.tokenize_segment(text);
+        }
+
+        let mut tokens = Vec::new();
+        for chunk in self.tokenize_chunks(text, TOKENIZE_CHUNK_BYTES) {
+            tokens.extend(chunk?.tokens);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Tokenize `text` a segment of about `chunk_bytes` at a time, so very long text doesn't need a
+    /// token buffer sized for all of it up front, and callers can report progress or give up between
+    /// segments. Segments are split where it doesn't change the tokens, see [segment_end].
+    pub fn tokenize_chunks<'a>(&'a mut self, text: &'a str, chunk_bytes: usize) -> TokenChunks<'a> {
+        // SentencePiece puts a space in front of every text it tokenizes, BPE doesn't.
+        let adds_space =
+            unsafe { llama_vocab_type(self.ctx.as_ptr()) } == llama_vocab_type_LLAMA_VOCAB_TYPE_SPM;
+        TokenChunks {
+            model: self,
+            text,
+            pos: 0,
+            chunk_bytes: chunk_bytes.max(1),
+            adds_space,
+        }
+    }
+
+    fn tokenize_segment(&mut self, text: &str) -> Result<Vec<llama_token>> {
+        let text_c_str = CString::new(text).context("text contains a NUL byte")?;
+
+        // Start with one token per byte, which is enough for nearly all text. If not, llama_tokenize
+        // tells us how many tokens it actually needs as a negative number.
+        let mut tokens: Vec<llama_token> = vec![0; text.len() + 1];
+        loop {
+            let n_tokens = unsafe {
+                llama_tokenize(
+                    self.ctx.as_mut(),
+                    text_c_str.as_ptr(),
+                    tokens.as_mut_ptr(),
+                    tokens.len() as i32,
+                    false,
+                )
+            };
+
+            if n_tokens >= 0 {
+                tokens.truncate(n_tokens as usize);
+                return Ok(tokens);
+            }
+            tokens.resize(n_tokens.unsigned_abs() as usize, 0);
+        }
+    }
+
+    pub fn generate(&mut self, prompt: &str, params: &GenerateParams) -> Result<Completion> {
+        let mut generation = self.start_generation(prompt, params)?;
+
+        let started_at_us = params.timings.then(|| unsafe {
+            llama_reset_timings(self.ctx.as_mut());
+            llama_time_us()
+        });
+        let mut token_ms = Vec::new();
+        // Where each token's text starts in the completion, to line alternatives up with what's left
+        // of it after a stop sequence is cut off.
+        let mut token_starts = Vec::new();
 
         let mut completion = String::from("");
-        for i in 0..20 {
-            unsafe {
-                assert_eq!(
-                    0,
-                    llama_eval(self.ctx.as_mut(), tokens.as_ptr(), prompt_tokens + i, i, 4),
-                    "llama_eval returned non-zero"
-                );
+        for _ in 0..MAX_NEW_TOKENS {
+            match self.next_token(&mut generation)? {
+                Some(next_token) => {
+                    if let Some(started_at_us) = started_at_us {
+                        let elapsed_us = unsafe { llama_time_us() } - started_at_us;
+                        token_ms.push(elapsed_us as f64 / 1000.0);
+                    }
+                    token_starts.push(completion.len());
+                    let token_text = self.token_text(next_token, params.raw_tokens);
+                    completion.push_str(&token_text);
+
+                    if let Some(stop_at) =
+                        find_stop(&completion, &params.stop_sequences, token_text.len())
+                    {
+                        completion.truncate(stop_at);
+                        generation.finish_reason = Some(FinishReason::Stop);
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
 
-                let logits = llama_get_logits(self.ctx.as_mut());
-                let mut candidates: Vec<llama_token_data> =
-                    Vec::with_capacity(self.n_vocab as usize);
-                for tok_id in 0..self.n_vocab {
-                    candidates.push(llama_token_data {
-                        id: tok_id,
-                        logit: *logits.offset(tok_id as isize),
-                        // NOTE(aduffy): We'd set this if we used top-p sampling
-                        p: 0.0f32,
+        let n_kept = token_starts
+            .iter()
+            .take_while(|&&start| start < completion.len())
+            .count();
+        generation.alternatives.truncate(n_kept);
+        let alternatives = generation
+            .alternatives
+            .iter()
+            .map(|step| {
+                step.iter()
+                    .map(|&(token, probability)| TokenAlternative {
+                        token,
+                        text: self.token_text(token, params.raw_tokens),
+                        probability,
                     })
+                    .collect()
+            })
+            .collect();
+
+        let timings = started_at_us.map(|_| {
+            let totals = unsafe { llama_get_timings(self.ctx.as_mut()) };
+            Timings {
+                prompt_eval_ms: totals.t_p_eval_ms,
+                n_prompt_eval: totals.n_p_eval as u32,
+                eval_ms: totals.t_eval_ms,
+                n_eval: totals.n_eval as u32,
+                sample_ms: totals.t_sample_ms,
+                token_ms,
+            }
+        });
+
+        let continuation_key = continuation_key();
+        self.kv.warm = Some(WarmContext {
+            key: continuation_key,
+            tokens: generation.tokens[..generation.n_past].to_vec(),
+        });
+
+        Ok(Completion {
+            text: completion,
+            prompt_truncated: generation.prompt_truncated,
+            finish_reason: generation.finish_reason.unwrap_or(FinishReason::Length),
+            sampling: generation.sampling,
+            timings,
+            continuation_key: Some(continuation_key),
+            cache_reused: generation.cache_reused,
+            alternatives,
+        })
+    }
+
+    pub async fn generate_stream(
+        &mut self,
+        prompt: &str,
+        params: &GenerateParams,
+        channel: Sender<StreamMessage>,
+    ) -> Result<()> {
+        if params.raw_bytes && !params.stop_sequences.is_empty() {
+            return Err(anyhow!("stop sequences can't be applied to raw bytes"));
+        }
+        let mut generation = self.start_generation(prompt, params)?;
+
+        let mut stops = StopFilter::new(&params.stop_sequences);
+        for _ in 0..MAX_NEW_TOKENS {
+            let next_token = match self.next_token(&mut generation)? {
+                Some(next_token) => next_token,
+                None => break,
+            };
+
+            // Stop generating if the receiver has hung up, there's nobody left to read the tokens.
+            if params.raw_bytes {
+                let msg = StreamMessage::NextTokenBytes(self.token_bytes(next_token)?);
+                if channel.send(msg).await.is_err() {
+                    return Ok(());
                 }
-                let mut candidates_array = llama_token_data_array {
-                    data: candidates.as_mut_ptr(),
-                    size: candidates.len(),
-                    sorted: false,
-                };
-
-                let next_token = llama_sample_token(self.ctx.as_mut(), &mut candidates_array);
-                if next_token == self.token_eos || next_token == self.token_bos {
-                    break;
+                continue;
+            }
+
+            let (text, stopped) = stops.push(&self.token_text(next_token, params.raw_tokens));
+            if !text.is_empty() && channel.send(StreamMessage::NextToken(text)).await.is_err() {
+                return Ok(());
+            }
+            if stopped {
+                break;
+            }
+        }
+        let held = stops.finish();
+        if !held.is_empty() && channel.send(StreamMessage::NextToken(held)).await.is_err() {
+            return Ok(());
+        }
+
+        // The receiver may already be gone, in which case there's nobody to notify.
+        let _ = channel.send(StreamMessage::Done).await;
+
+        Ok(())
+    }
+
+    /// Write the completion's text to `writer` as each token is generated, flushing every few tokens
+    /// and once generation finishes. If a write fails, generation stops and the error is returned.
+    pub async fn generate_to_writer<W>(
+        &mut self,
+        prompt: &str,
+        params: &GenerateParams,
+        writer: &mut W,
+    ) -> Result<Completion>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut generation = self.start_generation(prompt, params)?;
+
+        let mut stops = StopFilter::new(&params.stop_sequences);
+        for n_generated in 1..=MAX_NEW_TOKENS {
+            let next_token = match self.next_token(&mut generation)? {
+                Some(next_token) => next_token,
+                None => break,
+            };
+
+            let (text, stopped) = stops.push(&self.token_text(next_token, params.raw_tokens));
+            writer
+                .write_all(text.as_bytes())
+                .await
+                .context("failed to write token")?;
+            if stopped {
+                generation.finish_reason = Some(FinishReason::Stop);
+                break;
+            }
+
+            if n_generated % FLUSH_EVERY_TOKENS == 0 {
+                writer.flush().await.context("failed to flush writer")?;
+            }
+        }
+        writer
+            .write_all(stops.finish().as_bytes())
+            .await
+            .context("failed to write token")?;
+        writer.flush().await.context("failed to flush writer")?;
+
+        Ok(Completion {
+            text: stops.text,
+            prompt_truncated: generation.prompt_truncated,
+            finish_reason: generation.finish_reason.unwrap_or(FinishReason::Length),
+            sampling: generation.sampling,
+            timings: None,
+            continuation_key: None,
+            cache_reused: generation.cache_reused,
+            alternatives: Vec::new(),
+        })
+    }
+
+    /// Tokenize the prompt and fit it into the context window, ready for [Model::next_token]. Fails with
+    /// a [PromptError] if the prompt can't be made to fit.
+    fn start_generation(&mut self, prompt: &str, params: &GenerateParams) -> Result<Generation> {
+        let mut tokens = self.tokenize(prompt)?;
+        if tokens.is_empty() {
+            return Err(PromptError::Empty.into());
+        }
+
+        let n_ctx = self.n_ctx as usize;
+        let mut prompt_truncated = false;
+        if let Some(reserve_tokens) = params.reserve_tokens {
+            let reserve_tokens = reserve_tokens as usize;
+            if reserve_tokens >= n_ctx {
+                return Err(PromptError::ReserveTooLarge {
+                    reserve_tokens,
+                    n_ctx,
                 }
-                tokens[(prompt_tokens + i) as usize] = next_token;
-                completion.push_str(&self.token_text(next_token));
+                .into());
             }
+
+            // Keep the end of the prompt, which is closest to what we're about to generate.
+            let max_prompt_tokens = n_ctx - reserve_tokens;
+            if tokens.len() > max_prompt_tokens {
+                tokens.drain(..tokens.len() - max_prompt_tokens);
+                prompt_truncated = true;
+            }
+        }
+
+        if tokens.len() >= n_ctx {
+            return Err(PromptError::TooLong {
+                n_tokens: tokens.len(),
+                n_ctx,
+            }
+            .into());
+        }
+
+        params.sampling.validate()?;
+        let sampling = SamplingParams {
+            seed: Some(params.sampling.seed.unwrap_or_else(random_seed)),
+            ..params.sampling.clone()
+        };
+        unsafe { llama_set_rng_seed(self.ctx.as_mut(), sampling.seed.unwrap()) };
+
+        if let Some(token) = params
+            .logit_bias
+            .keys()
+            .find(|&&token| token < 0 || token >= self.n_vocab)
+        {
+            return Err(anyhow!("logit bias for unknown token {}", token));
+        }
+        if let Some(token) = params
+            .stop_tokens
+            .iter()
+            .find(|&&token| token < 0 || token >= self.n_vocab)
+        {
+            return Err(anyhow!("stop token {} is not in the vocabulary", token));
+        }
+        if params.top_alternatives > MAX_TOP_ALTERNATIVES {
+            return Err(anyhow!(
+                "top_alternatives must be at most {}, got {}",
+                MAX_TOP_ALTERNATIVES,
+                params.top_alternatives
+            ));
         }
 
-        completion
+        // Truncation cuts off the front of the prompt, so whatever the caller meant to reuse is gone.
+        let reuse = params.shared_prefix_tokens.is_some() || params.continuation_key.is_some();
+        if !reuse || prompt_truncated {
+            self.reset_context();
+        }
+        let prefix_len = params
+            .shared_prefix_tokens
+            .map_or(0, |n| (n as usize).min(tokens.len()));
+        let (n_past, cache_reused) = self.kv.reuse(&tokens, prefix_len, params.continuation_key);
+
+        Ok(Generation {
+            tokens,
+            n_past,
+            prompt_truncated,
+            cache_reused,
+            finish_reason: None,
+            logit_bias: params.logit_bias.clone(),
+            stop_tokens: params.stop_tokens.clone(),
+            control: params.control.clone(),
+            sampling,
+            candidates: Vec::with_capacity(self.n_vocab as usize),
+            top_alternatives: params.top_alternatives as usize,
+            alternatives: Vec::new(),
+        })
     }
 
-    pub async fn generate_stream(&mut self, prompt: &str, channel: Sender<StreamMessage>) {
-        let mut tokens = [0i32; 4096];
-        let prompt_c_str = CString::new(prompt).expect("unable to cast &str to CString");
-        let prompt_tokens = unsafe {
-            llama_tokenize(
+    /// Evaluate any pending tokens and sample the next one. Returns `None` once the model emits an
+    /// end-of-sequence token or one of the [GenerateParams::stop_tokens], or the context window is full.
+    fn next_token(&mut self, generation: &mut Generation) -> Result<Option<llama_token>> {
+        if generation.tokens.len() >= self.n_ctx as usize {
+            generation.finish_reason = Some(FinishReason::Length);
+            return Ok(None);
+        }
+        if generation
+            .control
+            .as_ref()
+            .is_some_and(|control| control.is_cancelled())
+        {
+            generation.finish_reason = Some(FinishReason::Cancelled);
+            return Ok(None);
+        }
+
+        // Only feed the tokens the context hasn't seen yet: the whole prompt on the first call, and
+        // just the last sampled token on every call after that.
+        let pending = &generation.tokens[generation.n_past..];
+        let mut alternatives = None;
+        let next_token = unsafe {
+            if llama_eval(
                 self.ctx.as_mut(),
-                prompt_c_str.as_ptr(),
-                tokens.as_mut_ptr(),
-                4096,
-                false,
-            )
-        };
-        assert!(prompt_tokens > 0, "No tokens generated");
-        for i in 0..20 {
-            unsafe {
-                assert_eq!(
-                    0,
-                    llama_eval(self.ctx.as_mut(), tokens.as_ptr(), prompt_tokens + i, i, 4),
-                    "llama_eval returned non-zero"
-                );
+                pending.as_ptr(),
+                pending.len() as i32,
+                generation.n_past as i32,
+                N_THREADS,
+            ) != 0
+            {
+                // The cache may be half written, so nothing in it can be trusted any more.
+                self.reset_context();
+                return Err(Error::msg("llama_eval returned non-zero"));
+            }
 
-                let logits = llama_get_logits(self.ctx.as_mut());
-                let mut candidates: Vec<llama_token_data> =
-                    Vec::with_capacity(self.n_vocab as usize);
-                for tok_id in 0..self.n_vocab {
-                    candidates.push(llama_token_data {
-                        id: tok_id,
-                        logit: *logits.offset(tok_id as isize),
-                        // NOTE(aduffy): We'd set this if we used top-p sampling
-                        p: 0.0f32,
-                    })
-                }
-                let mut candidates_array = llama_token_data_array {
-                    data: candidates.as_mut_ptr(),
-                    size: candidates.len(),
-                    sorted: false,
-                };
-
-                let next_token = llama_sample_token(self.ctx.as_mut(), &mut candidates_array);
-                if next_token == self.token_eos || next_token == self.token_bos {
-                    break;
-                }
-                tokens[(prompt_tokens + i) as usize] = next_token;
-                channel
-                    .send(StreamMessage::NextToken(self.token_text(next_token)))
-                    .await
-                    .context("failed to send generated token to receiver")
-                    .unwrap();
+            let logits =
+                slice::from_raw_parts(llama_get_logits(self.ctx.as_mut()), self.n_vocab as usize);
+            fill_candidates(&mut generation.candidates, logits);
+            // Candidates are filled in token order, so a token's ID is also its index.
+            for (&token, &bias) in &generation.logit_bias {
+                generation.candidates[token as usize].logit += bias;
             }
+            if generation.top_alternatives > 0 {
+                alternatives = Some(top_alternatives(
+                    &generation.candidates,
+                    generation.top_alternatives,
+                ));
+            }
+            let mut candidates_array = llama_token_data_array {
+                data: generation.candidates.as_mut_ptr(),
+                size: generation.candidates.len(),
+                sorted: false,
+            };
+
+            let sampling = &generation.sampling;
+            let last_tokens =
+                &generation.tokens[generation.tokens.len().saturating_sub(PENALTY_LAST_N)..];
+            llama_sample_repetition_penalty(
+                self.ctx.as_mut(),
+                &mut candidates_array,
+                last_tokens.as_ptr(),
+                last_tokens.len(),
+                sampling.repeat_penalty,
+            );
+            llama_sample_frequency_and_presence_penalties(
+                self.ctx.as_mut(),
+                &mut candidates_array,
+                last_tokens.as_ptr(),
+                last_tokens.len(),
+                sampling.frequency_penalty,
+                sampling.presence_penalty,
+            );
+
+            if sampling.temperature == 0.0 {
+                llama_sample_token_greedy(self.ctx.as_mut(), &mut candidates_array)
+            } else {
+                llama_sample_top_p(self.ctx.as_mut(), &mut candidates_array, sampling.top_p, 1);
+                llama_sample_temperature(
+                    self.ctx.as_mut(),
+                    &mut candidates_array,
+                    sampling.temperature,
+                );
+                llama_sample_token(self.ctx.as_mut(), &mut candidates_array)
+            }
+        };
+        generation.n_past = generation.tokens.len();
+        self.kv.evaluated(generation.n_past);
+
+        if next_token == self.token_eos
+            || next_token == self.token_bos
+            || generation.stop_tokens.contains(&next_token)
+        {
+            generation.finish_reason = Some(FinishReason::Stop);
+            return Ok(None);
+        }
+        generation.tokens.push(next_token);
+        generation.alternatives.extend(alternatives);
+        if let Some(control) = &generation.control {
+            control.n_generated.fetch_add(1, Ordering::Relaxed);
         }
 
-        channel
-            .send(StreamMessage::Done)
-            .await
-            .context("failed to send Done token to receiver")
-            .unwrap();
+        Ok(Some(next_token))
     }
 
     // Accept a channel as an argument, and then stream the tokens back over the channel
 
-    fn token_text(&self, token_id: llama_token) -> String {
+    /// ID of the special token whose vocabulary text is exactly `text`, e.g. `<|im_end|>`, which
+    /// ordinary tokenization would split into pieces. Looked up in a table built when the model was
+    /// loaded, so ordinary tokens aren't found.
+    pub fn find_token(&self, text: &str) -> Option<llama_token> {
+        self.special_tokens.get(text).copied()
+    }
+
+    /// The tokens with IDs in `ids` with their text, in ID order, leaving out IDs past the end of the
+    /// vocabulary. Text is rendered the same way as in completions: the `▁` word-boundary marker
+    /// becomes a space and the newline token is `\n`, while other byte tokens keep their `<0xNN>`
+    /// vocabulary text.
+    pub fn vocab(&self, ids: Range<u32>) -> Vec<(llama_token, String)> {
+        let end = ids.end.min(self.n_vocab as u32);
+        (ids.start.min(end)..end)
+            .map(|token_id| token_id as llama_token)
+            .map(|token_id| (token_id, self.token_text(token_id, false)))
+            .collect()
+    }
+
+    /// Tokenize `text`, pairing every token with its vocabulary text, rendered as in [Model::vocab],
+    /// and the bytes of `text` it covers, to show where the token boundaries fall.
+    pub fn token_pieces(&mut self, text: &str) -> Result<Vec<TokenPiece>> {
+        let tokens = self.tokenize(text)?;
+        let pieces = tokens
+            .iter()
+            .map(|&token_id| self.token_bytes(token_id))
+            .collect::<Result<Vec<_>>>()?;
+        let offsets = piece_offsets(text.as_bytes(), &pieces);
+
+        Ok(tokens
+            .into_iter()
+            .zip(offsets)
+            .map(|(id, offset)| TokenPiece {
+                id,
+                text: self.token_text(id, false),
+                offset,
+            })
+            .collect())
+    }
+
+    /// The bytes a token decodes to. A single token may hold only part of a multi-byte UTF-8 character,
+    /// in which case the bytes aren't valid UTF-8 on their own.
+    fn token_bytes(&self, token_id: llama_token) -> Result<Vec<u8>> {
+        let mut bytes: Vec<u8> = vec![0; 8];
+        loop {
+            let n_bytes = unsafe {
+                llama_token_to_piece(
+                    self.ctx.as_ptr(),
+                    token_id,
+                    bytes.as_mut_ptr() as *mut c_char,
+                    bytes.len() as i32,
+                )
+            };
+
+            if n_bytes >= 0 {
+                bytes.truncate(n_bytes as usize);
+                return Ok(bytes);
+            }
+            bytes.resize(n_bytes.unsigned_abs() as usize, 0);
+        }
+    }
+
+    /// Text of a token as it appears in the vocabulary. Unless `raw` is set, the `▁` word-boundary
+    /// marker is replaced with a space, and the newline token with `\n`.
+    fn token_text(&self, token_id: llama_token, raw: bool) -> String {
         let next_token = unsafe { llama_token_get_text(self.ctx.as_ptr(), token_id) };
         if next_token.is_null() {
             panic!("null next_token recovered");
         }
-        if token_id == self.token_nl {
+        if token_id == self.token_nl && !raw {
             return "\n".to_string();
         }
         let token_text = unsafe {
@@ -234,13 +988,926 @@ impl Model {
                 .expect("Failed to convert to &str")
                 .to_string()
         };
+        if raw {
+            return token_text;
+        }
 
         let string = String::from_utf8(vec![0xe2, 0x96, 0x81]).unwrap();
         token_text.replace(&string, " ")
     }
 }
 
+/// Options for a single call to [Model::generate] or [Model::generate_stream].
+#[derive(Debug, Clone, Default)]
+pub struct GenerateParams {
+    /// Number of tokens of the context window to keep free for the completion. When the prompt is too
+    /// long to leave this much room, tokens are dropped from the start of the prompt until it fits.
+    pub reserve_tokens: Option<u32>,
+
+    /// Added to the logit of a token before sampling. Large negative values effectively ban a token,
+    /// large positive values all but force it.
+    pub logit_bias: HashMap<llama_token, f32>,
+
+    /// End the completion as soon as its text contains any of these strings, leaving out the string
+    /// and everything after it. Streamed completions hold back text that could be the start of one
+    /// until it's clear whether it is. Can't be combined with [GenerateParams::raw_bytes].
+    pub stop_sequences: Vec<String>,
+
+    /// End the completion as soon as any of these tokens is sampled, leaving it out of the completion.
+    /// Matched on the token ID rather than its text, so special tokens are caught even though they
+    /// don't decode to the text they're named by.
+    pub stop_tokens: HashSet<llama_token>,
+
+    /// Have [Model::generate_stream] send each token's raw bytes as [StreamMessage::NextTokenBytes]
+    /// instead of its text, for clients that do their own UTF-8 decoding.
+    pub raw_bytes: bool,
+
+    /// Return token text exactly as it appears in the vocabulary, including the `▁` word-boundary
+    /// markers, rather than rendering it as plain text.
+    pub raw_tokens: bool,
+
+    /// Number of leading prompt tokens, e.g. a system prompt, which may be shared with other
+    /// generations through the KV cache. When the previous generation on this model declared the same
+    /// leading tokens as shareable, they're reused rather than evaluated again.
+    ///
+    /// Only tokens inside both generations' declared prefixes are ever compared or reused. Anything
+    /// past the prefix, such as a user's question, is never matched against later prompts and its KV
+    /// entries are overwritten by the next generation, so no request can observe whether another
+    /// request's suffix was cached. `None` opts out entirely: nothing is reused, and nothing from this
+    /// prompt is offered for reuse. Prefix tokens lost to `reserve_tokens` truncation are never shared,
+    /// and a prefix is only offered once it's been evaluated, so a generation cancelled before that
+    /// offers nothing.
+    pub shared_prefix_tokens: Option<u32>,
+
+    /// [Completion::continuation_key] of an earlier generation this one carries on from, its prompt
+    /// being the earlier prompt followed by the earlier completion. If nothing else has run on the
+    /// model since, the KV cache still holds those tokens and they aren't evaluated again. Without the
+    /// key nothing is reused, so only the client that received a completion can tell whether it's
+    /// still cached.
+    pub continuation_key: Option<u64>,
+
+    /// Lets another thread follow the generation and cancel it, see [GenerationControl].
+    pub control: Option<Arc<GenerationControl>>,
+
+    /// Have [Model::generate] report where the time went in [Completion::timings].
+    pub timings: bool,
+
+    /// Have [Model::generate] report this many of the most likely tokens at each step in
+    /// [Completion::alternatives], up to [MAX_TOP_ALTERNATIVES]. 0 turns it off.
+    pub top_alternatives: u32,
+
+    pub sampling: SamplingParams,
+}
+
+/// How the next token is picked from the model's logits. The defaults sample straight from the
+/// model's distribution, without any penalties.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingParams {
+    /// Seed for the sampler's RNG. Generations with the same prompt, seed and params produce the same
+    /// output. When `None` a random seed is picked, and reported back in [Completion::sampling].
+    pub seed: Option<u32>,
+
+    /// Flattens (above 1) or sharpens (below 1) the distribution. 0 always picks the most likely token.
+    pub temperature: f32,
+
+    /// Only sample from the most likely tokens whose probabilities add up to this value.
+    pub top_p: f32,
+
+    /// Divides the logits of recently generated tokens. 1 disables the penalty.
+    pub repeat_penalty: f32,
+
+    /// Subtracted from the logits of recent tokens once for each time they occurred.
+    pub frequency_penalty: f32,
+
+    /// Subtracted from the logits of recent tokens once if they occurred at all.
+    pub presence_penalty: f32,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            temperature: 1.0,
+            top_p: 1.0,
+            repeat_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Check the params are within the ranges llama.cpp expects.
+    pub fn validate(&self) -> Result<()> {
+        if self.temperature.is_nan() || self.temperature < 0.0 {
+            return Err(anyhow!(
+                "temperature must be >= 0, got {}",
+                self.temperature
+            ));
+        }
+        if self.top_p.is_nan() || self.top_p <= 0.0 || self.top_p > 1.0 {
+            return Err(anyhow!("top_p must be in (0, 1], got {}", self.top_p));
+        }
+        if self.repeat_penalty.is_nan() || self.repeat_penalty <= 0.0 {
+            return Err(anyhow!(
+                "repeat_penalty must be > 0, got {}",
+                self.repeat_penalty
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn random_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos())
+}
+
+/// Output of [Model::generate].
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+
+    /// Whether the prompt had to be truncated to satisfy [GenerateParams::reserve_tokens].
+    pub prompt_truncated: bool,
+
+    pub finish_reason: FinishReason,
+
+    /// The sampling params the completion was generated with, including the seed that was used.
+    pub sampling: SamplingParams,
+
+    /// Set when [GenerateParams::timings] was requested.
+    pub timings: Option<Timings>,
+
+    /// Pass as [GenerateParams::continuation_key] to continue this completion. Only set by
+    /// [Model::generate].
+    pub continuation_key: Option<u64>,
+
+    /// Whether the prompt was picked up from the KV cache left by the generation named in
+    /// [GenerateParams::continuation_key], rather than evaluated from scratch.
+    pub cache_reused: bool,
+
+    /// For each generated token, the [GenerateParams::top_alternatives] most likely tokens at that
+    /// step, most likely first. Empty unless requested. Only set by [Model::generate].
+    pub alternatives: Vec<Vec<TokenAlternative>>,
+}
+
+/// A token the model could have picked at some step of a generation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenAlternative {
+    pub token: llama_token,
+    pub text: String,
+
+    /// Probability of the token under the model's logits after [GenerateParams::logit_bias], before
+    /// any penalties, temperature or top-p.
+    pub probability: f32,
+}
+
+/// Time spent on a generation, split between evaluating the prompt and producing each token. Token
+/// evaluation and sampling totals come from llama.cpp's own counters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timings {
+    /// Time spent evaluating the prompt, and the number of prompt tokens evaluated. Tokens reused
+    /// from [GenerateParams::shared_prefix_tokens] aren't evaluated.
+    pub prompt_eval_ms: f64,
+    pub n_prompt_eval: u32,
+
+    /// Time spent evaluating generated tokens one at a time, and how many were evaluated.
+    pub eval_ms: f64,
+    pub n_eval: u32,
+
+    /// Time spent sampling, across all tokens.
+    pub sample_ms: f64,
+
+    /// Milliseconds after the generation started at which each token was produced. The first entry
+    /// includes prompt evaluation.
+    pub token_ms: Vec<f64>,
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model emitted an end-of-sequence token, or hit one of the stops in [GenerateParams].
+    Stop,
+
+    /// The token limit was reached, or the context window filled up.
+    Length,
+
+    /// Stopped early through [GenerationControl::cancel].
+    Cancelled,
+}
+
+/// Shared with whoever started a generation, to watch it from another thread and stop it early.
+#[derive(Debug, Default)]
+pub struct GenerationControl {
+    n_generated: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl GenerationControl {
+    /// Tokens generated so far, across every generation run with this control.
+    pub fn n_generated(&self) -> usize {
+        self.n_generated.load(Ordering::Relaxed)
+    }
+
+    /// Stop the generation before it evaluates another token, finishing with
+    /// [FinishReason::Cancelled]. An evaluation already running inside llama.cpp can't be interrupted,
+    /// so a generation stuck in one stays stuck.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// An in-progress generation, shared by the blocking and streaming generate variants.
+struct Generation {
+    /// Prompt tokens, followed by every token generated so far.
+    tokens: Vec<llama_token>,
+
+    /// Number of tokens at the front of `tokens` which have already been evaluated into the context.
+    n_past: usize,
+
+    prompt_truncated: bool,
+
+    /// See [Completion::cache_reused].
+    cache_reused: bool,
+
+    /// Set once the generation can't continue, `None` while it still can.
+    finish_reason: Option<FinishReason>,
+
+    logit_bias: HashMap<llama_token, f32>,
+
+    stop_tokens: HashSet<llama_token>,
+
+    control: Option<Arc<GenerationControl>>,
+
+    /// [GenerateParams::sampling], with the seed filled in.
+    sampling: SamplingParams,
+
+    /// Sampling candidates, one per token in the vocabulary. Allocated once per generation and
+    /// overwritten for every sampled token.
+    candidates: Vec<llama_token_data>,
+
+    /// See [GenerateParams::top_alternatives].
+    top_alternatives: usize,
+
+    /// Most likely tokens and their probabilities at each step, one entry per generated token.
+    alternatives: Vec<Vec<(llama_token, f32)>>,
+}
+
+/// Tokens of one segment of the text passed to [Model::tokenize_chunks].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenChunk {
+    pub tokens: Vec<llama_token>,
+    /// Bytes of the text tokenized so far, this segment included.
+    pub bytes_done: usize,
+}
+
+/// Iterator over the segments of a text being tokenized, see [Model::tokenize_chunks]. Stops after
+/// the first error.
+pub struct TokenChunks<'a> {
+    model: &'a mut Model,
+    text: &'a str,
+    pos: usize,
+    chunk_bytes: usize,
+    /// Whether the tokenizer puts a space in front of each segment, see [segment_end].
+    adds_space: bool,
+}
+
+impl Iterator for TokenChunks<'_> {
+    type Item = Result<TokenChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.text.len() {
+            return None;
+        }
+
+        let (end, next) = segment_end(self.text, self.pos, self.chunk_bytes, self.adds_space);
+        let tokens = self.model.tokenize_segment(&self.text[self.pos..end]);
+        self.pos = if tokens.is_ok() {
+            next
+        } else {
+            self.text.len()
+        };
+
+        Some(tokens.map(|tokens| TokenChunk {
+            tokens,
+            bytes_done: next,
+        }))
+    }
+}
+
+/// Where the segment of `text` starting at `start` ends when tokenizing about `chunk_bytes` at a
+/// time, and where the segment after it starts. Segments end at a single space between two words, so
+/// the words on either side of the seam come out as the same tokens as when the text is tokenized in
+/// one go, and no multi-byte character is cut in two. When the tokenizer `adds_space` in front of
+/// every segment it's given, as SentencePiece does, the space is dropped. Otherwise, as with BPE, it
+/// starts the next segment, where it's part of the next word's first token. Without such a space at
+/// or before `chunk_bytes`, the segment runs on to the next one, or to the end of the text.
+fn segment_end(text: &str, start: usize, chunk_bytes: usize, adds_space: bool) -> (usize, usize) {
+    let bytes = text.as_bytes();
+    let limit = start + chunk_bytes;
+    if limit >= bytes.len() {
+        return (bytes.len(), bytes.len());
+    }
+
+    let is_seam = |i: usize| {
+        bytes[i] == b' '
+            && !bytes[i - 1].is_ascii_whitespace()
+            && bytes
+                .get(i + 1)
+                .is_some_and(|next| !next.is_ascii_whitespace())
+    };
+    let seam = (start + 1..=limit)
+        .rev()
+        .find(|&i| is_seam(i))
+        .or_else(|| (limit + 1..bytes.len()).find(|&i| is_seam(i)));
+
+    match seam {
+        Some(i) if adds_space => (i, i + 1),
+        Some(i) => (i, i),
+        None => (bytes.len(), bytes.len()),
+    }
+}
+
+/// One token of the text passed to [Model::token_pieces].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenPiece {
+    pub id: llama_token,
+    pub text: String,
+    /// Byte range of the text the token decodes to. Ranges can split a multi-byte UTF-8 character
+    /// when the tokenizer falls back to byte tokens. `None` when the tokens stop spelling out the text.
+    pub offset: Option<Range<usize>>,
+}
+
+/// Byte range of `text` covered by each of the decoded `pieces`, walking them in order. The tokenizer
+/// prepends a space to the text, which is left out of the first range. Once a piece doesn't line up
+/// with the text, it and every piece after it get `None`.
+fn piece_offsets(text: &[u8], pieces: &[Vec<u8>]) -> Vec<Option<Range<usize>>> {
+    let mut offsets = Vec::with_capacity(pieces.len());
+    let mut pos = 0;
+    let mut aligned = true;
+    for (i, piece) in pieces.iter().enumerate() {
+        let mut piece = piece.as_slice();
+        if i == 0 && !text.starts_with(piece) {
+            piece = piece.strip_prefix(b" ").unwrap_or(piece);
+        }
+
+        aligned = aligned && text[pos..].starts_with(piece);
+        if aligned {
+            offsets.push(Some(pos..pos + piece.len()));
+            pos += piece.len();
+        } else {
+            offsets.push(None);
+        }
+    }
+
+    offsets
+}
+
+/// What a [Model]'s KV cache holds that the next generation may skip evaluating again.
+#[derive(Default)]
+struct KvState {
+    /// Tokens at the start of the KV cache that later generations may skip re-evaluating, see
+    /// [GenerateParams::shared_prefix_tokens]. Never holds more than the last generation's prefix,
+    /// and only once an evaluation has put all of it in the cache.
+    shared_prefix: Vec<llama_token>,
+
+    /// Prefix the generation in progress offered for reuse, which becomes the `shared_prefix` once
+    /// it's been evaluated. A generation that finishes before then leaves it unshared.
+    pending_prefix: Vec<llama_token>,
+
+    /// What the KV cache holds after the last [Model::generate], for the caller holding its key to
+    /// continue from, see [GenerateParams::continuation_key].
+    warm: Option<WarmContext>,
+}
+
+impl KvState {
+    fn reset(&mut self) {
+        self.shared_prefix.clear();
+        self.pending_prefix.clear();
+        self.warm = None;
+    }
+
+    /// Work out how much of the cache a generation of `tokens` can start from, given its first
+    /// `prefix_len` tokens may be shared and it may continue the generation with `continuation_key`.
+    /// Returns the number of tokens already evaluated, and whether they came from the continuation.
+    /// Until [KvState::evaluated] says otherwise, only the reused tokens are left shared.
+    fn reuse(
+        &mut self,
+        tokens: &[llama_token],
+        prefix_len: usize,
+        continuation_key: Option<u64>,
+    ) -> (usize, bool) {
+        let prefix = &tokens[..prefix_len];
+        // Leave at least one token to evaluate, to get logits for the first sampled token.
+        let n_shared = shared_prefix_len(&self.shared_prefix, prefix).min(tokens.len() - 1);
+        // Everything past what's reused is about to be overwritten, and must never be reused again.
+        self.shared_prefix.truncate(n_shared);
+        self.pending_prefix = prefix.to_vec();
+
+        // Whatever happens next, the previous generation's context can't be continued again.
+        let warm = self.warm.take();
+        let n_continued =
+            continuation_key.map_or(0, |key| continued_len(warm.as_ref(), key, tokens));
+
+        (n_shared.max(n_continued), n_continued > 0)
+    }
+
+    /// Record that the first `n_past` tokens of the generation in progress are in the cache.
+    fn evaluated(&mut self, n_past: usize) {
+        if !self.pending_prefix.is_empty() && n_past >= self.pending_prefix.len() {
+            self.shared_prefix = std::mem::take(&mut self.pending_prefix);
+        }
+    }
+}
+
+/// Tokens left in the KV cache by a finished [Model::generate].
+struct WarmContext {
+    /// Handed out as [Completion::continuation_key].
+    key: u64,
+    /// Every token that was evaluated into the cache, in order.
+    tokens: Vec<llama_token>,
+}
+
+/// A fresh key for [WarmContext]. Keys come from the standard library's randomly keyed hasher, so
+/// they can't be guessed from earlier ones.
+fn continuation_key() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Number of leading `tokens` that can be picked up from `warm` when continuing with `key`, always
+/// leaving at least one token to evaluate.
+fn continued_len(warm: Option<&WarmContext>, key: u64, tokens: &[llama_token]) -> usize {
+    match warm {
+        Some(warm) if warm.key == key => {
+            shared_prefix_len(&warm.tokens, tokens).min(tokens.len().saturating_sub(1))
+        }
+        _ => 0,
+    }
+}
+
+/// Byte offset of the earliest of the `stops` in `text`, if any of them occur. Empty stops never match.
+///
+/// Only stops ending in the last `new_bytes` of `text` are looked for, as it's checked after every
+/// token, and any stop ending before them would have been found already.
+fn find_stop(text: &str, stops: &[String], new_bytes: usize) -> Option<usize> {
+    let max_stop_len = stops.iter().map(String::len).max().unwrap_or(0);
+    let mut start = text.len().saturating_sub(new_bytes + max_stop_len);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+
+    stops
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text[start..].find(stop.as_str()))
+        .min()
+        .map(|offset| start + offset)
+}
+
+/// Applies [GenerateParams::stop_sequences] to a completion handed on as it's generated. Text that
+/// could be the start of a stop sequence is held back until the tokens after it show whether it is,
+/// so none of a stop sequence is ever handed on.
+struct StopFilter<'a> {
+    stops: &'a [String],
+
+    /// The completion so far, cut off before the stop sequence once one is found.
+    text: String,
+
+    /// Bytes at the front of `text` already handed on.
+    released: usize,
+}
+
+impl<'a> StopFilter<'a> {
+    fn new(stops: &'a [String]) -> Self {
+        Self {
+            stops,
+            text: String::new(),
+            released: 0,
+        }
+    }
+
+    /// Add the text of the next token. Returns the text that's now safe to hand on, and whether a
+    /// stop sequence was found, which ends the completion.
+    fn push(&mut self, token_text: &str) -> (String, bool) {
+        self.text.push_str(token_text);
+        if let Some(stop_at) = find_stop(&self.text, self.stops, token_text.len()) {
+            self.text.truncate(stop_at);
+            return (self.release(stop_at), true);
+        }
+
+        let held = partial_stop_len(&self.text, self.stops);
+        (self.release(self.text.len() - held), false)
+    }
+
+    /// Hand on whatever is still held back, once the completion ends without a stop sequence.
+    fn finish(&mut self) -> String {
+        self.release(self.text.len())
+    }
+
+    fn release(&mut self, end: usize) -> String {
+        let released = self.text[self.released..end].to_owned();
+        self.released = end;
+        released
+    }
+}
+
+/// Length in bytes of the longest end of `text` that the start of one of the `stops` matches, and
+/// which could turn into that stop as more text is added.
+fn partial_stop_len(text: &str, stops: &[String]) -> usize {
+    let max_stop_len = stops.iter().map(String::len).max().unwrap_or(0);
+    (text.len().saturating_sub(max_stop_len)..text.len())
+        .filter(|&start| text.is_char_boundary(start))
+        .find(|&start| stops.iter().any(|stop| stop.starts_with(&text[start..])))
+        .map_or(0, |start| text.len() - start)
+}
+
+/// Length of the longest common prefix of two token sequences.
+fn shared_prefix_len(a: &[llama_token], b: &[llama_token]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// The `k` most likely of `candidates` and their probabilities under a softmax of the logits, most
+/// likely first.
+fn top_alternatives(candidates: &[llama_token_data], k: usize) -> Vec<(llama_token, f32)> {
+    let max_logit = candidates
+        .iter()
+        .map(|candidate| candidate.logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = candidates
+        .iter()
+        .map(|candidate| (candidate.logit - max_logit).exp())
+        .sum();
+
+    // k is small, so keeping a sorted shortlist beats sorting the whole vocabulary.
+    let mut top: Vec<&llama_token_data> = Vec::with_capacity(k + 1);
+    for candidate in candidates {
+        if top.len() == k && top.last().is_some_and(|last| candidate.logit <= last.logit) {
+            continue;
+        }
+        let at = top.partition_point(|other| other.logit >= candidate.logit);
+        top.insert(at, candidate);
+        top.truncate(k);
+    }
+
+    top.into_iter()
+        .map(|candidate| (candidate.id, (candidate.logit - max_logit).exp() / total))
+        .collect()
+}
+
+/// Overwrite `candidates` with one entry per token, using the logits the model produced for each,
+/// reusing the existing allocation.
+fn fill_candidates(candidates: &mut Vec<llama_token_data>, logits: &[f32]) {
+    candidates.clear();
+    candidates.extend(
+        logits
+            .iter()
+            .enumerate()
+            .map(|(tok_id, &logit)| llama_token_data {
+                id: tok_id as llama_token,
+                logit,
+                // NOTE(aduffy): We'd set this if we used top-p sampling
+                p: 0.0f32,
+            }),
+    );
+}
+
+/// Internals exposed for the benchmarks only, not part of the crate's API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    use llamacpp_sys::llama_token_data;
+
+    pub fn fill_candidates(candidates: &mut Vec<llama_token_data>, logits: &[f32]) {
+        super::fill_candidates(candidates, logits)
+    }
+}
+
 pub enum StreamMessage {
     Done,
     NextToken(String),
+
+    /// Raw bytes of the next token, sent instead of [StreamMessage::NextToken] when
+    /// [GenerateParams::raw_bytes] is set.
+    NextTokenBytes(Vec<u8>),
+}
+
+#[cfg(test)]
+mod test {
+    use llamacpp_sys::llama_token_data;
+
+    use super::{
+        check_memory_limit, continuation_key, continued_len, find_stop, fit_context_size,
+        partial_stop_len, piece_offsets, segment_end, shared_prefix_len, top_alternatives, KvState,
+        MemoryLimitExceeded, MemoryProfile, StopFilter, WarmContext, CONTEXT_OVERHEAD_BYTES,
+    };
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_fit_context_size() {
+        // 7B llama: 32 layers of 4096-wide f16 keys and values.
+        let kv_bytes_per_token = 2 * 32 * 4096 * 2;
+        let weights = 4000 * MIB;
+
+        // Plenty of room: capped at the trained context.
+        assert_eq!(
+            fit_context_size(64 * 1024 * MIB, weights, kv_bytes_per_token, 4096).unwrap(),
+            4096
+        );
+
+        // 1 GiB for the KV cache is 2048 tokens.
+        let budget = weights + CONTEXT_OVERHEAD_BYTES + 1024 * MIB;
+        assert_eq!(
+            fit_context_size(budget, weights, kv_bytes_per_token, 4096).unwrap(),
+            2048
+        );
+
+        // Rounded down rather than up.
+        assert_eq!(
+            fit_context_size(budget - 1, weights, kv_bytes_per_token, 4096).unwrap(),
+            1792
+        );
+
+        // Not even enough for the weights.
+        assert!(fit_context_size(weights, weights, kv_bytes_per_token, 4096).is_err());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let profile = MemoryProfile {
+            weights_bytes: 4000 * MIB,
+            kv_bytes_per_token: 2 * 32 * 4096 * 2,
+            trained_n_ctx: 4096,
+        };
+        // 512 KiB of KV cache per token.
+        let footprint = profile.footprint(2048);
+        assert_eq!(footprint, 4000 * MIB + CONTEXT_OVERHEAD_BYTES + 1024 * MIB);
+
+        assert!(check_memory_limit(footprint, footprint).is_ok());
+        assert_eq!(
+            check_memory_limit(footprint, 5 * 1024 * MIB),
+            Err(MemoryLimitExceeded {
+                estimated_bytes: footprint,
+                limit_bytes: 5 * 1024 * MIB,
+            })
+        );
+    }
+
+    #[test]
+    fn test_top_alternatives() {
+        let candidates: Vec<llama_token_data> = [1.0f32, 3.0, 2.0, 3.0, -1.0]
+            .into_iter()
+            .enumerate()
+            .map(|(id, logit)| llama_token_data {
+                id: id as i32,
+                logit,
+                p: 0.0,
+            })
+            .collect();
+
+        let top = top_alternatives(&candidates, 3);
+        let ids: Vec<i32> = top.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+
+        let total: f32 = [1.0f32, 3.0, 2.0, 3.0, -1.0]
+            .iter()
+            .map(|logit| logit.exp())
+            .sum();
+        assert!((top[0].1 - 3.0f32.exp() / total).abs() < 1e-6);
+        assert!((top[2].1 - 2.0f32.exp() / total).abs() < 1e-6);
+
+        // Asking for more than there are returns them all, with probabilities adding up to 1.
+        let all = top_alternatives(&candidates, 10);
+        assert_eq!(all.len(), 5);
+        assert!((all.iter().map(|&(_, p)| p).sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(top_alternatives(&candidates, 0).is_empty());
+    }
+
+    #[test]
+    fn test_segment_end() {
+        fn split(text: &str, chunk_bytes: usize, adds_space: bool) -> Vec<&str> {
+            let mut segments = Vec::new();
+            let mut pos = 0;
+            while pos < text.len() {
+                let (end, next) = segment_end(text, pos, chunk_bytes, adds_space);
+                segments.push(&text[pos..end]);
+                pos = next;
+            }
+            segments
+        }
+        let segments = |text, chunk_bytes| split(text, chunk_bytes, true);
+
+        // Split at the space before the limit, which the tokenizer adds back.
+        assert_eq!(
+            segments("the quick brown fox", 12),
+            vec!["the quick", "brown fox"]
+        );
+        // Runs of whitespace are kept whole, as the tokenizer may merge them.
+        assert_eq!(segments("one  two three", 6), vec!["one  two", "three"]);
+        // Without a space in reach, the segment runs on past the limit.
+        assert_eq!(
+            segments("héllowörld and more", 4),
+            vec!["héllowörld", "and", "more"]
+        );
+        assert_eq!(segments("ünïcödé", 2), vec!["ünïcödé"]);
+        assert_eq!(segments("short", 64), vec!["short"]);
+        assert_eq!(segments(" leading", 1), vec![" leading"]);
+
+        // BPE tokenizers don't add a space, so it's kept at the start of the next segment.
+        assert_eq!(
+            split("the quick brown fox", 12, false),
+            vec!["the quick", " brown fox"]
+        );
+        assert_eq!(
+            split("one two three", 1, false),
+            vec!["one", " two", " three"]
+        );
+
+        // Tokenizing segment by segment comes out the same as in one go. Both tokenizers split words
+        // off before the space in front of them, SentencePiece after putting a space in front of the
+        // whole text.
+        fn pre_tokenize(text: &str) -> Vec<String> {
+            let mut words: Vec<String> = Vec::new();
+            for c in text.chars() {
+                match words.last_mut() {
+                    Some(word) if c != ' ' || word.ends_with(' ') => word.push(c),
+                    _ => words.push(c.to_string()),
+                }
+            }
+            words
+        }
+        let spm = |text: &str| pre_tokenize(&format!(" {}", text));
+        let bpe = |text: &str| pre_tokenize(text);
+        let text = "Tokenize  this text, spanning  several segments of ünïcödé words.\nAnd lines.";
+        for chunk_bytes in 1..text.len() {
+            let chunked = |tokenize: &dyn Fn(&str) -> Vec<String>, adds_space| {
+                split(text, chunk_bytes, adds_space)
+                    .into_iter()
+                    .flat_map(tokenize)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                chunked(&spm, true),
+                spm(text),
+                "{} byte chunks",
+                chunk_bytes
+            );
+            assert_eq!(
+                chunked(&bpe, false),
+                bpe(text),
+                "{} byte chunks",
+                chunk_bytes
+            );
+        }
+        // Dropping the space would glue BPE words together.
+        assert_ne!(
+            split(text, 8, true)
+                .into_iter()
+                .flat_map(bpe)
+                .collect::<Vec<_>>(),
+            bpe(text)
+        );
+    }
+
+    #[test]
+    fn test_shared_prefix_len() {
+        assert_eq!(shared_prefix_len(&[1, 2, 3], &[1, 2, 4]), 2);
+        assert_eq!(shared_prefix_len(&[1, 2], &[1, 2, 3]), 2);
+        assert_eq!(shared_prefix_len(&[], &[1]), 0);
+        assert_eq!(shared_prefix_len(&[5], &[1]), 0);
+    }
+
+    #[test]
+    fn test_kv_state_reset() {
+        let mut kv = KvState::default();
+
+        // The first generation offers its system prompt for reuse, which the next one picks up.
+        assert_eq!(kv.reuse(&[1, 2, 3, 4], 3, None), (0, false));
+        kv.evaluated(4);
+        assert_eq!(kv.reuse(&[1, 2, 3, 5], 3, None), (3, false));
+
+        // A generation cancelled before evaluating anything leaves its own prefix unshared, as the
+        // cache past what it reused still holds the previous generation's tokens.
+        assert_eq!(kv.reuse(&[1, 2, 7, 8], 3, None), (2, false));
+        assert_eq!(kv.reuse(&[1, 2, 7, 9], 3, None), (2, false));
+        kv.evaluated(4);
+        assert_eq!(kv.reuse(&[1, 2, 7, 6], 3, None), (3, false));
+
+        // An unrelated request in between resets the context, as generations that don't opt into
+        // reuse do, and nothing of the earlier ones carries over to the request after it.
+        kv.reset();
+        assert_eq!(kv.reuse(&[9, 9], 0, None), (0, false));
+        assert_eq!(kv.reuse(&[1, 2, 3, 6], 3, None), (0, false));
+
+        // Nor can a reset context be continued.
+        kv.warm = Some(WarmContext {
+            key: 7,
+            tokens: vec![1, 2, 3, 6, 8],
+        });
+        kv.reset();
+        assert_eq!(kv.reuse(&[1, 2, 3, 6, 8, 9], 0, Some(7)), (0, false));
+    }
+
+    #[test]
+    fn test_find_stop() {
+        let stops = vec!["\n\n".to_owned(), "User:".to_owned(), String::new()];
+        assert_eq!(find_stop("Paris.\n\nUser: and", &stops, 17), Some(6));
+        assert_eq!(find_stop("Paris. User:\n\n", &stops, 14), Some(7));
+        assert_eq!(find_stop("Paris.\n", &stops, 7), None);
+        assert_eq!(find_stop("Paris.", &[], 6), None);
+
+        // A stop split across tokens is found once its last token arrives.
+        assert_eq!(find_stop("Paris. Us", &stops, 2), None);
+        assert_eq!(find_stop("Paris. User:", &stops, 3), Some(7));
+        // Stops well before the latest token aren't looked for again.
+        assert_eq!(find_stop("User: Paris, the capital", &stops, 8), None);
+        // The searched tail starts on a character boundary.
+        assert_eq!(find_stop("Pâris\n\n", &stops, 1), Some(6));
+    }
+
+    #[test]
+    fn test_stop_filter() {
+        let stops = vec!["\n\n".to_owned(), "User:".to_owned()];
+        assert_eq!(partial_stop_len("Paris. Us", &stops), 2);
+        assert_eq!(partial_stop_len("Paris.\n", &stops), 1);
+        assert_eq!(partial_stop_len("Paris.", &stops), 0);
+        assert_eq!(partial_stop_len("Pâ", &stops), 0);
+
+        // Text that might start a stop is held back, and let go once it turns out not to.
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("Paris."), ("Paris.".to_owned(), false));
+        assert_eq!(filter.push(" Us"), (" ".to_owned(), false));
+        assert_eq!(filter.push("ually"), ("Usually".to_owned(), false));
+        assert_eq!(filter.push("\n"), (String::new(), false));
+        assert_eq!(filter.finish(), "\n");
+
+        // None of a stop split across tokens is let go.
+        let mut filter = StopFilter::new(&stops);
+        assert_eq!(filter.push("Paris. Us"), ("Paris. ".to_owned(), false));
+        assert_eq!(filter.push("er: and"), (String::new(), true));
+        assert_eq!(filter.finish(), "");
+        assert_eq!(filter.text, "Paris. ");
+
+        // Without stops, everything goes straight through.
+        let mut filter = StopFilter::new(&[]);
+        assert_eq!(filter.push("User:"), ("User:".to_owned(), false));
+    }
+
+    #[test]
+    fn test_continued_len() {
+        let key = continuation_key();
+        assert_ne!(key, continuation_key());
+        let warm = WarmContext {
+            key,
+            tokens: vec![1, 2, 3, 4],
+        };
+
+        // Prompt plus completion, with more text after it.
+        assert_eq!(continued_len(Some(&warm), key, &[1, 2, 3, 4, 5]), 4);
+        // The completion re-tokenized differently.
+        assert_eq!(continued_len(Some(&warm), key, &[1, 2, 7, 8]), 2);
+        // Exactly what's cached: the last token is evaluated again for its logits.
+        assert_eq!(continued_len(Some(&warm), key, &[1, 2, 3, 4]), 3);
+        // Someone else's key, or a cache that's since been overwritten.
+        assert_eq!(continued_len(Some(&warm), key ^ 1, &[1, 2, 3, 4, 5]), 0);
+        assert_eq!(continued_len(None, key, &[1, 2, 3, 4, 5]), 0);
+    }
+
+    #[test]
+    fn test_piece_offsets() {
+        let pieces = |pieces: &[&str]| -> Vec<Vec<u8>> {
+            pieces
+                .iter()
+                .map(|piece| piece.as_bytes().to_vec())
+                .collect()
+        };
+
+        // The space the tokenizer adds to the front isn't part of the text.
+        assert_eq!(
+            piece_offsets(b"Hello world", &pieces(&[" Hello", " world"])),
+            vec![Some(0..5), Some(5..11)]
+        );
+
+        // Byte tokens split "é" in two.
+        assert_eq!(
+            piece_offsets(
+                "café".as_bytes(),
+                &[b" caf".to_vec(), vec![0xc3], vec![0xa9]]
+            ),
+            vec![Some(0..3), Some(3..4), Some(4..5)]
+        );
+
+        // Everything from the first mismatch on has no offset.
+        assert_eq!(
+            piece_offsets(b"a b c", &pieces(&[" a", " x", " c"])),
+            vec![Some(0..1), None, None]
+        );
+    }
 }
@@ -0,0 +1,109 @@
+//! What this build of llama.cpp can do on the current machine.
+
+use std::ffi::CStr;
+
+use llamacpp_sys::llama_print_system_info;
+
+/// Hardware acceleration available to llama.cpp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acceleration {
+    /// Apple's Accelerate framework, linked on macOS.
+    Accelerate,
+    /// A BLAS library for batched prompt evaluation.
+    Blas,
+    /// NVIDIA GPU offload via cuBLAS.
+    Cuda,
+    /// Apple GPU offload via Metal.
+    Metal,
+}
+
+/// Acceleration compiled into this build.
+pub fn acceleration() -> Vec<Acceleration> {
+    let flags = parse_system_info(&system_info());
+    let enabled = |name: &str| flags.iter().any(|(flag, on)| flag == name && *on);
+
+    let mut acceleration = Vec::new();
+    // build.rs links Accelerate on every macOS build.
+    if cfg!(target_os = "macos") {
+        acceleration.push(Acceleration::Accelerate);
+    }
+    if enabled("BLAS") {
+        acceleration.push(Acceleration::Blas);
+    }
+    // ggml's CUDA and Metal backends aren't compiled by build.rs, so there's nothing to detect.
+
+    acceleration
+}
+
+/// SIMD extensions the host CPU supports, detected at runtime.
+pub fn cpu_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            features.push("avx");
+        }
+        if is_x86_feature_detected!("avx2") {
+            features.push("avx2");
+        }
+        if is_x86_feature_detected!("avx512f") {
+            features.push("avx512f");
+        }
+        if is_x86_feature_detected!("fma") {
+            features.push("fma");
+        }
+        if is_x86_feature_detected!("f16c") {
+            features.push("f16c");
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            features.push("neon");
+        }
+        if std::arch::is_aarch64_feature_detected!("fp16") {
+            features.push("fp16");
+        }
+    }
+
+    features
+}
+
+/// llama.cpp's own summary of the features it was compiled with, e.g. `AVX = 1 | AVX2 = 0 | `.
+pub fn system_info() -> String {
+    unsafe { CStr::from_ptr(llama_print_system_info()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Split a [system_info] string into `(name, enabled)` pairs.
+pub fn parse_system_info(info: &str) -> Vec<(String, bool)> {
+    info.split('|')
+        .filter_map(|flag| {
+            let (name, value) = flag.split_once('=')?;
+            Some((name.trim().to_owned(), value.trim() == "1"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_system_info;
+
+    #[test]
+    fn test_parse_system_info() {
+        let flags = parse_system_info("AVX = 1 | AVX2 = 0 | BLAS = 1 | SSE3 = 1 | ");
+        assert_eq!(
+            flags,
+            vec![
+                ("AVX".to_owned(), true),
+                ("AVX2".to_owned(), false),
+                ("BLAS".to_owned(), true),
+                ("SSE3".to_owned(), true),
+            ]
+        );
+        assert!(parse_system_info("").is_empty());
+    }
+}
@@ -3,71 +3,770 @@
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::PathBuf};
 use time::OffsetDateTime;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, ToSchema)]
 pub struct GenerateRequest {
+    /// Name of the registered model to run, loaded on first use.
     pub model_id: String,
+
+    /// Text to complete. With `continue_from`, it's appended after the earlier completion, and may be
+    /// left out to just keep generating.
+    #[serde(default)]
     pub prompt: String,
+
+    /// Carry on from an earlier completion, e.g. one that was cut off by the token limit.
+    #[serde(default)]
+    pub continue_from: Option<ContinueFrom>,
+
+    /// Version of the model to run. When left out, the latest version with the requested
+    /// `quantization` is used, or else the model's default quantization, or else its smallest.
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub version: Option<semver::Version>,
+
+    /// Quantization to run, e.g. `Q4_K_M`, for models registered in several quantizations.
+    #[serde(default)]
+    pub quantization: Option<String>,
+
+    /// Guarantee at least this many tokens of the context window are left for the completion,
+    /// truncating the start of the prompt if needed.
+    pub reserve_tokens: Option<u32>,
+
+    /// Adjustments to the likelihood of specific tokens being generated.
+    #[serde(default)]
+    pub logit_bias: Vec<LogitBias>,
+
+    /// Steer the completion toward or away from the vocabulary of a reference text, see [Guidance].
+    #[serde(default)]
+    pub guidance: Option<Guidance>,
+
+    /// End the completion at any of these, which can mix text and tokens, see [StopSequence].
+    #[serde(default)]
+    pub stop: Vec<StopSequence>,
+
+    /// Shape of the JSON response. Defaults to [GenerateResponse].
+    #[serde(default)]
+    pub response_format: GenerateResponseFormat,
+
+    /// Left out to sample with the model's `default_sampling`, or the [SamplingParams] defaults if it
+    /// has none.
+    #[serde(default)]
+    pub sampling: Option<SamplingParams>,
+
+    /// Return the completion as raw vocabulary text, keeping the `▁` markers where words start and the
+    /// newline token's `<0x0A>` text, for clients that render tokens themselves.
+    #[serde(default)]
+    pub raw_tokens: bool,
+
+    /// Number of leading prompt tokens, such as a system prompt, that may be reused from and offered
+    /// to other requests through the model's KV cache. The rest of the prompt is never shared.
+    #[serde(default)]
+    pub shared_prefix_tokens: Option<u32>,
+
+    /// Report where the time went in [GenerateResponse::timings]. Off by default, as it adds a little
+    /// overhead to every token.
+    #[serde(default)]
+    pub timings: bool,
+
+    /// Report this many of the most likely tokens at each step in [GenerateResponse::top_alternatives],
+    /// e.g. for an autocomplete dropdown. At most 20, and 0, the default, turns it off.
+    #[serde(default)]
+    pub top_alternatives: u32,
+
+    /// Generate several completions and respond with one giving the most common answer.
+    #[serde(default)]
+    pub self_consistency: Option<SelfConsistency>,
+}
+
+/// Majority vote over several completions of the same prompt, for reasoning tasks where sampled
+/// chains of thought disagree but the right answer tends to come up most.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SelfConsistency {
+    /// Number of completions to vote over. With a fixed seed, completion `i` uses `seed + i`.
+    pub n: u32,
+
+    /// Pulls the answer out of a completion. The last match counts, using its first capture group if
+    /// there is one, e.g. `answer is (\d+)`.
+    pub extract_regex: String,
+}
+
+/// Outcome of a [SelfConsistency] vote.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SelfConsistencyResult {
+    /// The most common answer, ties going to the one that came up first. `None` when no completion
+    /// had an answer.
+    pub answer: Option<String>,
+
+    /// Every answer given, most votes first.
+    pub votes: Vec<AnswerVotes>,
+
+    /// Completions the regex found no answer in.
+    pub unanswered: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct AnswerVotes {
+    pub answer: String,
+    pub votes: u32,
 }
 
-#[derive(Serialize)]
+/// How tokens are sampled during a completion. Any field left out takes its default, which samples
+/// from the model's unmodified distribution with a random seed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct SamplingParams {
+    /// Completions with the same prompt, seed and params are identical.
+    pub seed: Option<u32>,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            temperature: 1.0,
+            top_p: 1.0,
+            repeat_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+pub enum GenerateResponseFormat {
+    /// Respond with a [GenerateResponse].
+    #[default]
+    #[serde(rename = "default")]
+    Default,
+
+    /// Respond with a [ChoicesResponse], shaped like an OpenAI completion, for clients migrating over.
+    #[serde(rename = "choices")]
+    Choices,
+}
+
+/// Bias applied to the logit of a single token during sampling, e.g. `{"token_id": 13, "bias": -100}`
+/// or `{"text": " Paris", "bias": 5}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LogitBias {
+    #[serde(flatten)]
+    pub token: LogitBiasToken,
+    pub bias: f32,
+}
+
+/// Nudge generation toward the words of a reference text, e.g. `{"text": "mitochondria, ATP, enzyme",
+/// "scale": 2}`, or away from them with a negative scale. Every distinct token the text tokenizes to gets
+/// `scale` added to its logit, on top of any [LogitBias] for it.
+///
+/// This is a heuristic that only looks at which tokens the reference uses, not classifier-free
+/// guidance: the model never sees the reference, so it can't steer toward its meaning, and common
+/// tokens like spaces and punctuation in the reference are boosted along with everything else.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Guidance {
+    pub text: String,
+    /// Added to the logits of the reference's tokens. Small values, around 1 to 3, steer without
+    /// making the completion parrot the reference.
+    pub scale: f32,
+}
+
+/// The token a [LogitBias] applies to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(untagged)]
+pub enum LogitBiasToken {
+    /// A token ID from the model's vocabulary. IDs differ between models.
+    Id { token_id: i32 },
+
+    /// Text which the model tokenizes to exactly one token, resolved to its ID at request time.
+    Text { text: String },
+}
+
+/// Where to end a completion, e.g. `"\n\n"`, `{"token": "<|im_end|>"}` or `{"token_id": 2}`. The stop
+/// itself is left out of the completion, which finishes with reason `stop`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(untagged)]
+pub enum StopSequence {
+    /// Text matched against the decoded completion.
+    Text(String),
+
+    /// A token ID from the model's vocabulary, matched against the sampled tokens.
+    TokenId { token_id: i32 },
+
+    /// A token referred to by its text in the vocabulary, typically a special token such as
+    /// `<|im_end|>` whose decoded text differs from its name. Resolved to its ID at request time, or
+    /// failing that to the single token the text tokenizes to, and matched like [StopSequence::TokenId].
+    Token { token: String },
+}
+
+/// An earlier completion for [GenerateRequest::continue_from].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ContinueFrom {
+    #[serde(flatten)]
+    pub source: ContinuationSource,
+
+    /// [GenerateResponse::continuation_key] of the earlier completion. If nothing else has run on the
+    /// model since, its KV cache is reused instead of evaluating the whole text again.
+    #[serde(default)]
+    pub continuation_key: Option<String>,
+}
+
+/// Where the text of a [ContinueFrom] comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(untagged)]
+pub enum ContinuationSource {
+    /// The earlier prompt and the completion it got, resubmitted.
+    Completion { prompt: String, completion: String },
+
+    /// A saved experiment's prompt and output.
+    Experiment { experiment_id: uuid::Uuid },
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct GenerateResponse {
     pub model_id: String,
+
+    /// The version of the model that produced the completion, as picked by [GenerateRequest::version]
+    /// and [GenerateRequest::quantization], and its quantization if known. Useful for comparing
+    /// versions, e.g. in an A/B test.
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<String>,
+
+    pub completion: String,
+
+    pub finish_reason: FinishReason,
+
+    /// Whether the prompt was truncated to honor [GenerateRequest::reserve_tokens].
+    pub prompt_truncated: bool,
+
+    /// The sampling params that produced the completion, with the seed filled in. Saving these with an
+    /// experiment allows it to be replayed exactly.
+    pub sampling: SamplingParams,
+
+    /// Set when [GenerateRequest::timings] was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+
+    /// Pass back in [ContinueFrom::continuation_key] to continue this completion.
+    pub continuation_key: Option<String>,
+
+    /// Whether [GenerateRequest::continue_from] picked up the earlier completion's KV cache.
+    pub cache_reused: bool,
+
+    /// How the vote went when [GenerateRequest::self_consistency] was requested. The completion is
+    /// the first one that gave the winning answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_consistency: Option<SelfConsistencyResult>,
+
+    /// Set when [GenerateRequest::top_alternatives] was requested: for each token of the completion,
+    /// in order, the most likely tokens at that step, most likely first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_alternatives: Option<Vec<Vec<TokenAlternative>>>,
+}
+
+/// A token the model could have generated at some step of a completion.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TokenAlternative {
+    pub token_id: i32,
+    pub text: String,
+
+    /// Probability of the token under the model's distribution, after [GenerateRequest::logit_bias]
+    /// but before sampling params like temperature reshape it.
+    pub probability: f32,
+}
+
+/// Where the time in a completion went, for profiling latency.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Timings {
+    /// Time spent evaluating the prompt, and how many prompt tokens that covered.
+    pub prompt_eval_ms: f64,
+    pub prompt_tokens: u32,
+
+    /// Time spent evaluating generated tokens, and how many were evaluated.
+    pub eval_ms: f64,
+    pub eval_tokens: u32,
+
+    /// Time spent sampling, across all tokens.
+    pub sample_ms: f64,
+
+    /// Milliseconds after the completion started at which each token was generated.
+    pub token_ms: Vec<f64>,
+}
+
+/// Completion response matching the `choices` shape of OpenAI's completion API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ChoicesResponse {
+    pub model_id: String,
+
+    /// The version of the model that produced the choices, and its quantization if known, see
+    /// [GenerateResponse::version].
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<String>,
+
+    pub choices: Vec<CompletionChoice>,
+
+    /// Whether the prompt was truncated to honor [GenerateRequest::reserve_tokens].
+    pub prompt_truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CompletionChoice {
+    pub text: String,
+    pub index: usize,
+
+    /// Always `null`, log probabilities aren't reported yet.
+    #[schema(value_type = Option<Object>)]
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: FinishReason,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum FinishReason {
+    /// The model ended the completion itself.
+    #[serde(rename = "stop")]
+    Stop,
+
+    /// The completion was cut off by the token limit or the context window.
+    #[serde(rename = "length")]
+    Length,
+
+    /// The server cancelled the completion, e.g. an operator sent it SIGUSR2.
+    #[serde(rename = "cancelled")]
+    Cancelled,
+}
+
+/// Request to complete one prompt once per seed, to explore the range of outputs.
+#[derive(Deserialize, Clone, ToSchema)]
+pub struct SweepRequest {
+    pub model_id: String,
+    pub prompt: String,
+
+    /// Version and quantization of the model to run, picked as for [GenerateRequest::version].
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub version: Option<semver::Version>,
+    #[serde(default)]
+    pub quantization: Option<String>,
+
+    /// Seeds to complete the prompt with. Either this or `count` must be given.
+    #[serde(default)]
+    pub seeds: Option<Vec<u32>>,
+
+    /// Complete the prompt with this many consecutive seeds, starting from the seed in `sampling`, or
+    /// 0 if that's unset.
+    #[serde(default)]
+    pub count: Option<u32>,
+
+    /// Sampling params shared by every completion. The seed is overridden per completion. Left out to
+    /// sample with the model's `default_sampling`, or the [SamplingParams] defaults if it has none.
+    #[serde(default)]
+    pub sampling: Option<SamplingParams>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SweepResponse {
+    pub model_id: String,
+
+    /// The version of the model that produced the completions, and its quantization if known, see
+    /// [GenerateResponse::version].
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<String>,
+
+    /// One completion per seed, in the order the seeds were given.
+    pub completions: Vec<SweepCompletion>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SweepCompletion {
+    pub seed: u32,
     pub completion: String,
+    pub finish_reason: FinishReason,
+}
+
+/// Request to complete several prompts against the same model in a single call.
+#[derive(Deserialize, Clone, ToSchema)]
+pub struct BatchGenerateRequest {
+    pub model_id: String,
+    pub prompts: Vec<String>,
+
+    /// Version and quantization of the model to run, picked as for [GenerateRequest::version].
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub version: Option<semver::Version>,
+    #[serde(default)]
+    pub quantization: Option<String>,
+
+    /// Stream `token_bytes` events holding each token's raw bytes rather than `token` events holding
+    /// its text. For clients that want to do their own UTF-8 decoding.
+    #[serde(default)]
+    pub raw_bytes: bool,
+
+    /// Stream raw vocabulary text for each token, see [GenerateRequest::raw_tokens].
+    #[serde(default)]
+    pub raw_tokens: bool,
+}
+
+/// Event sent over the batched streaming completion endpoint. Every SSE `data` payload is one of
+/// these, tagged with the index of the prompt in [BatchGenerateRequest::prompts] that it belongs to:
+///
+/// - `{"type":"token","index":0,"token":" Hello"}` - the next token generated for prompt `index`
+/// - `{"type":"token_bytes","index":0,"bytes":[226,150]}` - the raw bytes of the next token, sent in
+///   place of `token` when [BatchGenerateRequest::raw_bytes] is set. Multi-byte characters may be
+///   split across several of these.
+/// - `{"type":"done","index":0}` - prompt `index` has finished generating, no more tokens will follow
+///
+/// Events for different prompts may interleave, so clients should route each event by its `index`
+/// rather than assume the items are streamed one after another. The stream closes once every
+/// prompt has sent its `done` event.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(tag = "type")]
+pub enum BatchStreamEvent {
+    #[serde(rename = "token")]
+    Token { index: usize, token: String },
+
+    #[serde(rename = "token_bytes")]
+    TokenBytes { index: usize, bytes: Vec<u8> },
+
+    #[serde(rename = "done")]
+    Done { index: usize },
+}
+
+/// Speaker of a [ChatMessage].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum ChatRole {
+    #[serde(rename = "system")]
+    System,
+    #[serde(rename = "user")]
+    User,
+    #[serde(rename = "assistant")]
+    Assistant,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+/// A conversation to preview the prompt for, see [ChatRenderResponse].
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ChatRenderRequest {
+    pub messages: Vec<ChatMessage>,
+}
+
+/// The prompt a conversation renders to with the chat template. Completions don't apply the template
+/// themselves, so this is what to send as the `prompt` of a completion to run the conversation.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ChatRenderResponse {
+    pub prompt: String,
 }
 /// ModelType corresponds to the category of model. Currently accepted values include
 /// Completion: a completion language model.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
 pub enum ModelType {
     #[serde(rename = "completion")]
     Completion,
 }
 
 /// Runtime indicates the runtime the model is built for. Some common examples are "ggml" or "onnx".
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
 pub enum Runtime {
     #[serde(rename = "ggml")]
     Ggml,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Hardware acceleration a runtime can use.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Acceleration {
+    Accelerate,
+    Blas,
+    Cuda,
+    Metal,
+}
+
+/// What the server can run with a given runtime.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct RuntimeInfo {
+    pub runtime: Runtime,
+
+    /// Whether the runtime is compiled into this build of the server.
+    pub available: bool,
+    pub acceleration: Vec<Acceleration>,
+
+    /// SIMD extensions of the host CPU, e.g. `avx2` or `neon`.
+    pub cpu_features: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct RuntimesResponse {
+    pub runtimes: Vec<RuntimeInfo>,
+}
+
+/// What one version of a model supports, for clients to enable only the features that work with it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ModelCapabilities {
+    pub model: String,
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    pub model_type: ModelType,
+    pub runtime: Runtime,
+
+    /// Model architecture from the GGUF metadata, e.g. `llama`.
+    pub architecture: Option<String>,
+    pub quantization: Option<String>,
+
+    /// Size of the context window the model is loaded with. Prompt and completion together must fit
+    /// in it.
+    pub max_context_tokens: Option<u64>,
+    pub vocab_size: Option<u64>,
+
+    /// Whether the model file carries a chat template for rendering conversations.
+    pub chat_template: bool,
+
+    /// Whether embeddings can be computed with the model.
+    pub embeddings: bool,
+
+    /// Sampling features completions with the model can use.
+    pub sampling: Vec<SamplingFeature>,
+
+    /// Sampling params recommended for the model, if any.
+    pub default_sampling: Option<SamplingParams>,
+
+    /// Hardware acceleration this build of the runtime runs the model with.
+    pub acceleration: Vec<Acceleration>,
+}
+
+/// A way of steering sampling, each one a field of [SamplingParams] or [GenerateRequest].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingFeature {
+    Seed,
+    Temperature,
+    TopP,
+    RepeatPenalty,
+    FrequencyPenalty,
+    PresencePenalty,
+    LogitBias,
+    StopSequences,
+    Guidance,
+    TopAlternatives,
+}
+
+/// Query parameters picking which version of a model to describe. When left out, the version a
+/// completion would run is described.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+pub struct CapabilitiesQuery {
+    #[serde(default)]
+    #[param(value_type = Option<String>)]
+    pub version: Option<semver::Version>,
+
+    #[serde(default)]
+    pub quantization: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, ToSchema)]
 pub struct RegisteredModel {
     pub id: uuid::Uuid,
     pub name: String,
     pub model_type: ModelType,
     pub runtime: Runtime,
     pub versions: Vec<ModelVersion>,
+
+    /// Quantization used when a request doesn't pick a version, see [SetDefaultQuantizationRequest].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_quantization: Option<String>,
+}
+
+/// Body of `POST /v1/models/:model_name/versions/:version/rename`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct RenameVersionRequest {
+    #[schema(value_type = String)]
+    pub new_version: semver::Version,
+}
+
+/// Body of `PUT /v1/models/:model_name/default-quantization`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SetDefaultQuantizationRequest {
+    /// Quantization to use when a request doesn't pick a version or quantization, e.g. `Q5_K_M`.
+    /// `null` goes back to picking the smallest quantization.
+    pub quantization: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// A registered model version and the file it runs from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct RegisteredModelFile {
+    pub model: String,
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    #[schema(value_type = String)]
+    pub model_path: PathBuf,
+}
+
+/// Response of `GET /v1/models/orphans`, where the registered models and the files on disk disagree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct OrphansResponse {
+    /// Registered versions whose model file no longer exists.
+    pub missing_files: Vec<RegisteredModelFile>,
+
+    /// Files in the server's `MODELS_DIR` that no registered version runs from. Absent when
+    /// `MODELS_DIR` isn't set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<String>>)]
+    pub unregistered_files: Option<Vec<PathBuf>>,
+}
+
+/// Body of `PUT /v1/models/:model_name/max-versions`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SetMaxVersionsRequest {
+    /// Most versions of the model to keep. Registering another version beyond this prunes the oldest,
+    /// other than the one requests run by default. `null` goes back to the server-wide limit.
+    pub max_versions: Option<u32>,
+}
+
+/// Body of `PUT /v1/models/:model_name/max-memory`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SetMaxMemoryRequest {
+    /// Most memory in bytes the model may take once loaded, counting its weights and the KV cache for
+    /// its context. Loads estimated to need more are refused. `null` goes back to the server-wide limit.
+    pub max_memory_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
 pub struct ModelVersion {
+    #[schema(value_type = String)]
     pub version: semver::Version,
     pub import_metadata: ImportMetadata,
+
+    /// Size and checksum of the model file, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<ModelFile>,
+
+    /// Size and checksum of the file found on disk when the model was last loaded, if they didn't
+    /// match `file`. The recorded `file` is kept, so the change stays visible until the file is put
+    /// back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_on_disk: Option<ModelFile>,
+
+    /// Quantization of the weights, e.g. `Q4_K_M`, read from the GGUF metadata when the version was
+    /// registered. Several quantizations of one model can be registered as separate versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<String>,
+}
+
+/// Integrity metadata for a model file, recorded at import and checked again whenever it's loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ModelFile {
+    pub size_bytes: u64,
+
+    /// Hex-encoded SHA256 of the file contents.
+    pub sha256: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct RegisterModelRequest {
     pub model: String,
+    #[schema(value_type = String)]
     pub version: semver::Version,
     pub model_type: ModelType,
     pub runtime: Runtime,
     pub import_metadata: ImportMetadata,
     pub internal_params: ModelParams,
+
+    #[serde(default)]
+    pub file: Option<ModelFile>,
+
+    #[serde(default)]
+    pub quantization: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Current load on a model. Counters start from zero each time the model is loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LoadStatsResponse {
+    /// Whether the model is currently loaded. Unloaded models have no requests queued or in flight.
+    pub loaded: bool,
+
+    /// Requests waiting for the model to become free.
+    pub queued: usize,
+
+    /// Requests currently generating.
+    pub in_flight: usize,
+
+    /// Average time from being queued to finishing over recent requests, in milliseconds. `null`
+    /// until a request has finished.
+    pub avg_latency_ms: Option<f64>,
+}
+
+/// One model in a bulk registration manifest. The model file must already be on disk.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BulkRegisterEntry {
+    pub model: String,
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    #[schema(value_type = String)]
+    pub model_path: PathBuf,
+
+    #[serde(default)]
+    pub default_sampling: Option<SamplingParams>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BulkRegisterRequest {
+    pub models: Vec<BulkRegisterEntry>,
+}
+
+/// Outcome of a bulk registration. Entries are registered all together or not at all, so `committed`
+/// is only true if every entry registered.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BulkRegisterResponse {
+    pub committed: bool,
+
+    /// One result per entry, in the order of [BulkRegisterRequest::models].
+    pub results: Vec<BulkRegisterResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(tag = "status")]
+pub enum BulkRegisterResult {
+    #[serde(rename = "registered")]
+    Registered { model_id: uuid::Uuid },
+
+    /// The entry was valid, but wasn't registered because another entry failed.
+    #[serde(rename = "rolled_back")]
+    RolledBack,
+
+    #[serde(rename = "failed")]
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 #[serde(tag = "type")]
 pub enum ModelParams {
     #[serde(rename = "paramsv1/completion")]
     COMPLETION(CompletionModelParams),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct CompletionModelParams {
+    #[schema(value_type = String)]
     pub model_path: PathBuf,
+
+    /// Sampling params recommended for the model, if any. Completions that don't give their own
+    /// sampling params use these.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_sampling: Option<SamplingParams>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct CompletionInferenceRequest {
     /// Prompt for the inference engine to complete against.
     pub prompt: String,
@@ -80,29 +779,79 @@ pub struct CompletionInferenceRequest {
     pub temperature: f32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Request to save the prompt and output of a completion run against a model version, so it can be
+/// revisited later.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SaveExperimentRequest {
+    pub model: String,
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    pub temperature: f32,
+    pub tokens: u32,
+    pub prompt: String,
+    pub output: String,
+
+    /// Resolved sampling params of the completion, see [GenerateResponse::sampling]. Required to replay
+    /// the experiment.
+    #[serde(default)]
+    pub sampling: Option<SamplingParams>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SaveExperimentResponse {
+    pub id: uuid::Uuid,
+}
+
+/// A saved experiment, as stored by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SavedExperiment {
+    pub id: uuid::Uuid,
+    pub model: String,
+    #[schema(value_type = String)]
+    pub version: semver::Version,
+    pub prompt: String,
+    pub output: String,
+    pub sampling: Option<SamplingParams>,
+}
+
+/// Result of re-running a saved experiment with its stored sampling params.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ReplayExperimentResponse {
+    pub id: uuid::Uuid,
+    pub completion: String,
+
+    /// Output stored with the experiment, to compare against.
+    pub original_output: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct GetRegisteredModelsResponse {
     pub models: Vec<RegisteredModel>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
 pub struct ImportMetadata {
+    /// Time the import completed, serialized as an RFC3339 timestamp with its original offset and
+    /// full sub-second precision, e.g. `2023-09-01T12:34:56.123456789Z`.
+    #[serde(with = "time::serde::rfc3339")]
     pub imported_at: OffsetDateTime,
     pub source: ImportSource,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, ToSchema)]
 pub struct HFLocator {
     pub repo: String,
+    #[schema(value_type = String)]
     pub file: PathBuf,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, ToSchema)]
 pub struct DiskLocator {
+    #[schema(value_type = String)]
     pub path: PathBuf,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, ToSchema)]
 #[serde(tag = "type")]
 pub enum Locator {
     #[serde(rename = "locatorv1/hf")]
@@ -112,7 +861,7 @@ pub enum Locator {
     DISK(DiskLocator),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
 #[serde(tag = "type")]
 pub enum ImportSource {
     #[serde(rename = "importv1/hf")]
@@ -122,28 +871,128 @@ pub enum ImportSource {
     DISK { source: DiskLocator },
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct GetAllJobStatusResponse {
+    #[schema(value_type = HashMap<uuid::Uuid, ImportJobStatus>)]
     pub import_jobs: HashMap<ImportJobId, ImportJobStatus>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+/// Query parameters for paging through a model's vocabulary.
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct VocabQuery {
+    /// ID of the first token to return.
+    #[serde(default)]
+    pub offset: u32,
+
+    /// Maximum number of tokens to return.
+    #[serde(default = "default_vocab_limit")]
+    pub limit: u32,
+}
+
+fn default_vocab_limit() -> u32 {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct VocabToken {
+    pub id: i32,
+    pub text: String,
+}
+
+/// One page of a model's vocabulary.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct VocabResponse {
+    /// Total number of tokens in the vocabulary.
+    pub n_vocab: u32,
+    pub offset: u32,
+    pub tokens: Vec<VocabToken>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct TokenCountRequest {
+    pub model_id: String,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct TokenCountResponse {
+    pub model_id: String,
+    pub n_tokens: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct TokenizePreviewRequest {
+    pub model_id: String,
+    pub text: String,
+}
+
+/// A token of [TokenizePreviewRequest::text], with the part of the text it covers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct PreviewToken {
+    pub id: i32,
+    pub text: String,
+    /// Byte offsets into the UTF-8 encoded text, end exclusive. Unset when the token can't be lined
+    /// up with the text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct TokenizePreviewResponse {
+    pub model_id: String,
+    pub n_tokens: usize,
+    pub tokens: Vec<PreviewToken>,
+}
+
+/// Query parameters for listing import jobs.
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ImportJobsQuery {
+    /// Only return jobs importing from this source: a JSON-encoded [Locator], e.g.
+    /// `?source={"type":"locatorv1/hf","repo":"meta-llm/llama","file":"model.gguf"}` (URL-encoded).
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, ToSchema)]
 pub enum ImportJob {
     // Depending on the task, we want to include the subtypes of the locator here as well instead...fuck
     HF { locator: HFLocator },
     DISK { locator: DiskLocator },
 }
 
+impl From<Locator> for ImportJob {
+    fn from(locator: Locator) -> Self {
+        match locator {
+            Locator::DISK(locator) => ImportJob::DISK { locator },
+            Locator::HF(locator) => ImportJob::HF { locator },
+        }
+    }
+}
+
 // Have it enqueue a task, and return an ID
 pub type ImportJobId = uuid::Uuid;
 
+/// An import job that was cut short by the server shutting down, recorded so it can be retried after
+/// the server comes back up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterruptedImport {
+    pub job_id: ImportJobId,
+    pub task: ImportJob,
+    /// Bytes of the model file downloaded before the job was interrupted, which a retry resumes from.
+    pub downloaded_bytes: u64,
+    pub interrupted_at: OffsetDateTime,
+}
+
 /// Status of an import job.
 /// Import jobs can be in one of three different states at a given point in time
 /// - **[Queued]** - for imports that are taking too long
 /// - **[InProgress]** - for imports that are actively being worked on
 /// - **[Completed]** - for imports that are complete and cached locally on disk
 /// - **[Failed]** - for import jobs that failed with an error
-#[derive(Debug, Clone, Serialize)]
+/// - **[Cancelled]** - for import jobs that were cancelled before they finished
+/// - **[Interrupted]** - for import jobs cut short by the server shutting down, which can be retried
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
 #[serde(tag = "type")]
 pub enum ImportJobStatus {
     #[serde(rename = "queued")]
@@ -163,24 +1012,134 @@ pub enum ImportJobStatus {
         // We need to keep track of an error, so that it's sendable, and so that we can log it for later.
         error: Option<String>,
     },
+
+    #[serde(rename = "cancelled")]
+    Cancelled,
+
+    #[serde(rename = "interrupted")]
+    Interrupted,
+}
+
+impl ImportJobStatus {
+    /// Returns true if the job has reached a terminal state and will receive no further updates.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            ImportJobStatus::Completed { .. }
+                | ImportJobStatus::Failed { .. }
+                | ImportJobStatus::Cancelled
+                | ImportJobStatus::Interrupted
+        )
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CancelAllImportsResponse {
+    /// IDs of the jobs that were queued or in progress and have now been cancelled.
+    #[schema(value_type = Vec<uuid::Uuid>)]
+    pub cancelled: Vec<ImportJobId>,
+}
+
+/// Utilization of the server-wide generation slots.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct GenerationMetrics {
+    /// Most generations that may run at once, across all models.
+    pub limit: usize,
+    /// Generations holding a slot, including those waiting for their model to become free.
+    pub in_flight: usize,
+    /// Requests rejected with 503 since startup because every slot was taken.
+    pub rejected: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct MetricsResponse {
+    pub generations: GenerationMetrics,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct VacuumResponse {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub reclaimed_bytes: u64,
+    /// False when there was nothing to reclaim and only the planner statistics were refreshed.
+    pub vacuumed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
 pub struct ListHFFiles {
     pub repo: String,
     pub subfolder: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
 pub struct HFFile {
     pub filename: String,
     pub subfolder: Option<String>,
     pub size_bytes: usize,
+    /// Serialized as an RFC3339 timestamp, see [ImportMetadata::imported_at].
+    #[serde(with = "time::serde::rfc3339")]
     pub committed_at: OffsetDateTime,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
 pub struct ListHFFilesResponse {
     pub repo: String,
     pub files: Vec<HFFile>,
 }
+
+/// Body of every structured error response, e.g.
+/// `{"error":{"code":"not_found","message":"no route for GET /v2/models","path":"/v2/models"}}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ErrorDetail {
+    /// Stable, machine-readable identifier for the kind of error.
+    pub code: String,
+
+    /// Human-readable description of what went wrong.
+    pub message: String,
+
+    /// Request path that caused the error, where relevant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    /// Problems with individual fields of the request body, for validation errors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+
+    /// How much memory a model would need, for `memory_limit_exceeded` errors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryLimit>,
+}
+
+impl ErrorResponse {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            error: ErrorDetail {
+                code: code.to_owned(),
+                message: message.into(),
+                path: None,
+                fields: Vec::new(),
+                memory: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct FieldError {
+    /// Name of the offending field.
+    pub field: String,
+    pub message: String,
+}
+
+/// Estimated footprint of a model that wasn't loaded because it's over its memory limit.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct MemoryLimit {
+    /// Weights plus the KV cache for the context it would be loaded with.
+    pub estimated_bytes: u64,
+    pub limit_bytes: u64,
+}
@@ -0,0 +1,150 @@
+//! What each registered model supports, so clients can tailor themselves to it, e.g. hiding chat
+//! for a model without a chat template.
+//!
+//! Capabilities are pieced together from the model file's GGUF metadata, the version's stored params,
+//! the params the server loads models with, and what this build of the runtime can do.
+
+use llamacpp::gguf::{GgufMetadata, GgufValue};
+
+use crate::api_types::{
+    Acceleration, CompletionModelParams, ModelCapabilities, ModelType, Runtime, SamplingFeature,
+};
+
+/// GGUF key holding the model's chat template, a Jinja template for rendering conversations.
+const CHAT_TEMPLATE_KEY: &str = "tokenizer.chat_template";
+
+/// GGUF key holding the text of every token in the vocabulary.
+const TOKENS_KEY: &str = "tokenizer.ggml.tokens";
+
+/// Sampling features the runtime supports for completion models, every way a [GenerateRequest] can
+/// steer sampling.
+///
+/// [GenerateRequest]: crate::api_types::GenerateRequest
+const COMPLETION_SAMPLING: &[SamplingFeature] = &[
+    SamplingFeature::Seed,
+    SamplingFeature::Temperature,
+    SamplingFeature::TopP,
+    SamplingFeature::RepeatPenalty,
+    SamplingFeature::FrequencyPenalty,
+    SamplingFeature::PresencePenalty,
+    SamplingFeature::LogitBias,
+    SamplingFeature::StopSequences,
+    SamplingFeature::Guidance,
+    SamplingFeature::TopAlternatives,
+];
+
+/// Describe one version of a model. `metadata` is `None` when the model file couldn't be read as
+/// GGUF, leaving out everything that comes from it. `n_ctx` is the context size the model is, or
+/// would be, loaded with, and `acceleration` is what the runtime was built with.
+#[allow(clippy::too_many_arguments)]
+pub fn describe(
+    model: &str,
+    version: &semver::Version,
+    runtime: Runtime,
+    quantization: Option<String>,
+    params: &CompletionModelParams,
+    metadata: Option<&GgufMetadata>,
+    n_ctx: Option<u32>,
+    acceleration: Vec<Acceleration>,
+) -> ModelCapabilities {
+    ModelCapabilities {
+        model: model.to_owned(),
+        version: version.clone(),
+        model_type: ModelType::Completion,
+        runtime,
+        architecture: metadata
+            .and_then(GgufMetadata::architecture)
+            .map(str::to_owned),
+        quantization,
+        max_context_tokens: n_ctx.map(u64::from),
+        vocab_size: metadata.and_then(|metadata| match metadata.get(TOKENS_KEY)? {
+            GgufValue::Array(tokens) => Some(tokens.len() as u64),
+            _ => None,
+        }),
+        chat_template: metadata
+            .and_then(|metadata| metadata.get(CHAT_TEMPLATE_KEY))
+            .is_some_and(|template| template.as_str().is_some()),
+        // Completion models are all that can be registered, and they only generate text.
+        embeddings: false,
+        sampling: COMPLETION_SAMPLING.to_vec(),
+        default_sampling: params.default_sampling.clone(),
+        acceleration,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use llamacpp::gguf::{GgufMetadata, GgufValue};
+
+    use crate::api_types::{Acceleration, CompletionModelParams, Runtime, SamplingFeature};
+
+    use super::describe;
+
+    #[test]
+    fn test_describe() {
+        let params = CompletionModelParams {
+            model_path: "/models/llama.gguf".into(),
+            default_sampling: None,
+        };
+        let version = semver::Version::new(1, 0, 0);
+        let metadata = GgufMetadata {
+            version: 3,
+            tensor_count: 0,
+            kv: HashMap::from([
+                (
+                    "general.architecture".to_owned(),
+                    GgufValue::String("llama".to_owned()),
+                ),
+                ("llama.context_length".to_owned(), GgufValue::U32(4096)),
+                (
+                    "tokenizer.ggml.tokens".to_owned(),
+                    GgufValue::Array(vec![GgufValue::String("<s>".to_owned()); 3]),
+                ),
+                (
+                    "tokenizer.chat_template".to_owned(),
+                    GgufValue::String("{{ messages }}".to_owned()),
+                ),
+            ]),
+        };
+
+        let capabilities = describe(
+            "llama",
+            &version,
+            Runtime::Ggml,
+            Some("Q4_K_M".to_owned()),
+            &params,
+            Some(&metadata),
+            Some(2048),
+            vec![Acceleration::Metal],
+        );
+        assert_eq!(capabilities.architecture.as_deref(), Some("llama"));
+        // The context it's loaded with, not the one it was trained with.
+        assert_eq!(capabilities.max_context_tokens, Some(2048));
+        assert_eq!(capabilities.vocab_size, Some(3));
+        assert!(capabilities.chat_template);
+        assert!(!capabilities.embeddings);
+        assert!(capabilities.sampling.contains(&SamplingFeature::LogitBias));
+        assert!(capabilities
+            .sampling
+            .contains(&SamplingFeature::StopSequences));
+        assert_eq!(capabilities.acceleration, vec![Acceleration::Metal]);
+
+        // Without readable metadata, only what's stored for the version is known.
+        let capabilities = describe(
+            "llama",
+            &version,
+            Runtime::Ggml,
+            None,
+            &params,
+            None,
+            None,
+            Vec::new(),
+        );
+        assert_eq!(capabilities.architecture, None);
+        assert_eq!(capabilities.max_context_tokens, None);
+        assert_eq!(capabilities.vocab_size, None);
+        assert!(!capabilities.chat_template);
+    }
+}
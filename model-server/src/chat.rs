@@ -0,0 +1,111 @@
+//! Templating for chat conversations.
+//!
+//! Chat models are still plain completion models underneath, and expect a conversation to be laid
+//! out in the same format they were fine-tuned on. We only serve Llama 2 models for now, so that's
+//! the only template.
+
+use anyhow::{anyhow, Result};
+
+use crate::api_types::{ChatMessage, ChatRole};
+
+/// Render a conversation into a prompt using the Llama 2 chat template:
+///
+/// ```text
+/// [INST] <<SYS>>
+/// {system}
+/// <</SYS>>
+///
+/// {user} [/INST] {assistant} [INST] {user} [/INST]
+/// ```
+///
+/// The system message is optional and must come first. After it, messages must alternate between the
+/// user and the assistant, starting with the user. The BOS/EOS tokens that the original template wraps
+/// each turn in are left out, they're added by the tokenizer rather than written into the text.
+pub fn render_llama2(messages: &[ChatMessage]) -> Result<String> {
+    let (system, turns) = match messages.split_first() {
+        Some((first, rest)) if first.role == ChatRole::System => (Some(&first.content), rest),
+        _ => (None, messages),
+    };
+
+    if turns.is_empty() {
+        return Err(anyhow!("conversation has no user messages"));
+    }
+
+    let mut prompt = String::new();
+    for (i, message) in turns.iter().enumerate() {
+        let expected_role = if i % 2 == 0 {
+            ChatRole::User
+        } else {
+            ChatRole::Assistant
+        };
+        if message.role != expected_role {
+            return Err(anyhow!(
+                "message {} should be from {:?} but is from {:?}",
+                i,
+                expected_role,
+                message.role
+            ));
+        }
+
+        match message.role {
+            ChatRole::User => {
+                if i > 0 {
+                    prompt.push(' ');
+                }
+                prompt.push_str("[INST] ");
+                if let (0, Some(system)) = (i, system) {
+                    prompt.push_str(&format!("<<SYS>>\n{}\n<</SYS>>\n\n", system.trim()));
+                }
+                prompt.push_str(&format!("{} [/INST]", message.content.trim()));
+            }
+            _ => prompt.push_str(&format!(" {}", message.content.trim())),
+        }
+    }
+
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api_types::{ChatMessage, ChatRole};
+
+    use super::render_llama2;
+
+    fn message(role: ChatRole, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_llama2() {
+        let messages = vec![
+            message(ChatRole::System, "Be brief."),
+            message(ChatRole::User, "Hi!"),
+            message(ChatRole::Assistant, "Hello."),
+            message(ChatRole::User, "What's 2 + 2?"),
+        ];
+        assert_eq!(
+            render_llama2(&messages).unwrap(),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi! [/INST] Hello. [INST] What's 2 + 2? [/INST]"
+        );
+
+        assert_eq!(
+            render_llama2(&messages[1..2]).unwrap(),
+            "[INST] Hi! [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_render_llama2_rejects_bad_ordering() {
+        assert!(render_llama2(&[]).is_err());
+        assert!(render_llama2(&[message(ChatRole::System, "Be brief.")]).is_err());
+        assert!(render_llama2(&[message(ChatRole::Assistant, "Hello.")]).is_err());
+        assert!(render_llama2(&[
+            message(ChatRole::User, "Hi!"),
+            message(ChatRole::User, "Hi again!"),
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Size and SHA256 of model files, for catching files that changed on disk behind our back.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::api_types::ModelFile;
+
+/// Read buffer size used while hashing. Model files run to gigabytes, so they're streamed.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Hash a file, returning its size and hex-encoded SHA256. Reads the whole file, so call it from a
+/// blocking context.
+pub fn checksum_file(path: &Path) -> anyhow::Result<ModelFile> {
+    let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, file);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size_bytes = 0u64;
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .with_context(|| format!("failed to read {:?}", path))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size_bytes += n as u64;
+    }
+
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    Ok(ModelFile { size_bytes, sha256 })
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::checksum_file;
+
+    #[test]
+    fn test_checksum_file() {
+        let dir = TempDir::new("checksum_test").unwrap();
+        let path = dir.path().join("model.gguf");
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let file = checksum_file(&path).unwrap();
+        assert_eq!(file.size_bytes, 11);
+        assert_eq!(
+            file.sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        assert!(checksum_file(&dir.path().join("missing.gguf")).is_err());
+    }
+}
@@ -75,9 +75,118 @@ impl Migration for V0 {
     }
 }
 
+/// Record the sampling params used for each saved experiment, so it can be replayed.
+#[derive(Clone, Copy, Debug)]
+pub struct V1;
+
+impl Migration for V1 {
+    fn forward(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            r"
+        alter table saved_experiments add column sampling_params text;
+    ",
+        )
+        .context("failed to execute migration v1 -- add saved_experiments.sampling_params")?;
+
+        Ok(())
+    }
+}
+
+/// Record the size and checksum of each model version's file, and what was found on disk instead when
+/// the file no longer matches them.
+#[derive(Clone, Copy, Debug)]
+pub struct V2;
+
+impl Migration for V2 {
+    fn forward(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            r"
+        alter table model_version add column size_bytes integer;
+        alter table model_version add column sha256 text;
+        alter table model_version add column found_size_bytes integer;
+        alter table model_version add column found_sha256 text;
+    ",
+        )
+        .context("failed to execute migration v2 -- add model_version file metadata")?;
+
+        Ok(())
+    }
+}
+
+/// Records the quantization of each model version, and which quantization a model should use when a
+/// request doesn't pick one.
+pub struct V3;
+
+impl Migration for V3 {
+    fn forward(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            r"
+        alter table model_version add column quantization text;
+        alter table model add column default_quantization text;
+    ",
+        )
+        .context("failed to execute migration v3 -- add quantization")?;
+
+        Ok(())
+    }
+}
+
+/// Records how many versions of a model to keep, overriding the server-wide default.
+pub struct V4;
+
+impl Migration for V4 {
+    fn forward(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            r"
+        alter table model add column max_versions integer;
+    ",
+        )
+        .context("failed to execute migration v4 -- add version retention")?;
+
+        Ok(())
+    }
+}
+
+/// Records the most memory a model may take once loaded, overriding the server-wide limit.
+pub struct V5;
+
+impl Migration for V5 {
+    fn forward(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            r"
+        alter table model add column max_memory_bytes integer;
+    ",
+        )
+        .context("failed to execute migration v5 -- add memory limit")?;
+
+        Ok(())
+    }
+}
+
+/// Records import jobs cut short by a shutdown, so they can be offered for retry on the next boot.
+pub struct V6;
+
+impl Migration for V6 {
+    fn forward(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute_batch(
+            r"
+        create table if not exists interrupted_import (
+            job_id              text primary key,
+            task                text not null,
+            downloaded_bytes    integer not null,
+            interrupted_at      datetime not null
+        );
+    ",
+        )
+        .context("failed to execute migration v6 -- add interrupted_import")?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Migration, V0};
+    use super::{Migration, V0, V1, V2, V3, V4, V5, V6};
 
     #[test]
     fn test_migration() {
@@ -85,5 +194,11 @@ mod test {
 
         // Test migrations
         V0.forward(&db).unwrap();
+        V1.forward(&db).unwrap();
+        V2.forward(&db).unwrap();
+        V3.forward(&db).unwrap();
+        V4.forward(&db).unwrap();
+        V5.forward(&db).unwrap();
+        V6.forward(&db).unwrap();
     }
 }
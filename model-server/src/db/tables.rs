@@ -1,17 +1,150 @@
 use anyhow::Context;
-use std::path::Path;
-use tokio::sync::Mutex;
+use log::{error, info, warn};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, task::JoinHandle};
 
-use rusqlite::{named_params, Connection};
-use time::OffsetDateTime;
+use rusqlite::{named_params, Connection, OptionalExtension};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-use crate::api_types::{self, ModelType, RegisterModelRequest, RegisteredModel, Runtime};
+use crate::api_types::{
+    self, InterruptedImport, ModelFile, ModelType, RegisterModelRequest, RegisteredModel,
+    RegisteredModelFile, Runtime, SaveExperimentRequest, SavedExperiment,
+};
 use crate::db_types::Model;
+use crate::download;
+use crate::quantization::{select_version, VersionSelector};
+
+/// Marker appended to experiment prompts and outputs that were cut short by [ExperimentLimits].
+pub const TRUNCATION_MARKER: &str = "...[truncated]";
+
+/// Caps on the size of the text stored with each saved experiment, to keep the experiment store bounded.
+/// Text over the cap is cut at a character boundary and ends with [TRUNCATION_MARKER], such that the
+/// stored text including the marker is at most the cap.
+#[derive(Debug, Clone, Copy)]
+pub struct ExperimentLimits {
+    /// Max size of a stored prompt in bytes. Defaults to 64 KiB.
+    pub max_prompt_bytes: usize,
+
+    /// Max size of a stored output in bytes. Defaults to 64 KiB.
+    pub max_output_bytes: usize,
+}
+
+impl Default for ExperimentLimits {
+    fn default() -> Self {
+        Self {
+            max_prompt_bytes: 64 * 1024,
+            max_output_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Outcome of [DB::vacuum]. Sizes cover the main DB file only, not the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+
+    /// Whether the DB was rebuilt. It's skipped when there are no free pages to reclaim.
+    pub vacuumed: bool,
+}
+
+impl VacuumReport {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+#[derive(Debug)]
+pub enum DBError {
+    /// The model has no version with the requested version number.
+    VersionNotFound,
+
+    /// The model already has a version with the requested version number.
+    VersionExists,
+}
+
+impl fmt::Display for DBError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for DBError {}
+
+/// Version of a model from a row of `version, source, imported_at, size_bytes, sha256, quantization,
+/// found_size_bytes, found_sha256` columns, as selected by the joins in [DB::get_models].
+fn model_version_from_row(row: &rusqlite::Row) -> anyhow::Result<api_types::ModelVersion> {
+    // rusqlite parses RFC3339 text back with its offset intact, and still accepts the rows written
+    // in its own default format before timestamps were pinned.
+    let (version, import_source, imported_at): (String, String, OffsetDateTime) =
+        (row.get(0)?, row.get(1)?, row.get(2)?);
+    let source: api_types::ImportSource =
+        serde_json::from_str(&import_source).context("parse import_source")?;
+    let (size_bytes, sha256): (Option<u64>, Option<String>) = (row.get(3)?, row.get(4)?);
+    let (found_size_bytes, found_sha256): (Option<u64>, Option<String>) =
+        (row.get(6)?, row.get(7)?);
+
+    Ok(api_types::ModelVersion {
+        version: semver::Version::parse(&version)?,
+        import_metadata: api_types::ImportMetadata {
+            imported_at,
+            source,
+        },
+        file: size_bytes
+            .zip(sha256)
+            .map(|(size_bytes, sha256)| ModelFile { size_bytes, sha256 }),
+        quantization: row.get(5)?,
+        file_on_disk: found_size_bytes
+            .zip(found_sha256)
+            .map(|(size_bytes, sha256)| ModelFile { size_bytes, sha256 }),
+    })
+}
+
+/// A model from its row of the `model` table and its versions, which are put in semver order. SQL can
+/// only sort versions as text, which puts 0.10.0 before 0.2.0.
+fn registered_model(
+    row: &Model,
+    mut versions: Vec<api_types::ModelVersion>,
+) -> anyhow::Result<RegisteredModel> {
+    versions.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(RegisteredModel {
+        id: uuid::Uuid::parse_str(&row.id).context("failed to parse UUID")?,
+        name: row.name.to_string(),
+        model_type: match row.model_type.as_str() {
+            "completion" => api_types::ModelType::Completion,
+            _ => return Err(anyhow::anyhow!("unknown model_type {}", &row.model_type)),
+        },
+        runtime: match row.runtime.as_str() {
+            "ggml" => api_types::Runtime::Ggml,
+            _ => return Err(anyhow::anyhow!("unknown runtime {}", &row.runtime)),
+        },
+        versions,
+        default_quantization: row.default_quantization.clone(),
+    })
+}
 
 /// Handle to the [database connection](rusqlite::Connection)
 pub struct DB {
     // The DB Handle owns the connection
     pub connection: Mutex<Connection>,
+
+    /// Size caps applied by [DB::save_experiment].
+    pub experiment_limits: ExperimentLimits,
+
+    /// Most versions kept of each model, for models without their own limit, see
+    /// [DB::set_max_versions]. `None` keeps every version.
+    pub max_versions: Option<u32>,
+
+    /// Model files live in a store the server mustn't write to, so pruned versions are only removed
+    /// from the DB and their files are left in place.
+    pub read_only_store: bool,
 }
 
 // Constructor
@@ -27,151 +160,497 @@ impl DB {
 
         Ok(Self {
             connection: Mutex::new(conn),
+            experiment_limits: ExperimentLimits::default(),
+            max_versions: None,
+            read_only_store: false,
         })
     }
 }
 
 // General public methods for users of this type
 impl DB {
-    /// Register a new model version with the system
+    /// Register a new model version with the system. If the model then has more versions than it may
+    /// keep, the oldest are pruned, see [prune_versions].
     pub async fn register_model(
         &self,
         request: &RegisterModelRequest,
     ) -> anyhow::Result<uuid::Uuid> {
-        let model_id = uuid::Uuid::new_v4();
-        let model_row = Model {
-            id: model_id.to_string(),
-            name: request.model.clone(),
-            model_type: match request.model_type {
-                ModelType::Completion => "completion".to_string(),
-            },
-            runtime: match request.runtime {
-                Runtime::Ggml => "ggml".to_string(),
-            },
-            description: "".to_string(),
-        };
-
-        {
-            let mut conn = self.connection.lock().await;
-            let tx = conn.transaction().unwrap();
+        let mut conn = self.connection.lock().await;
+        let tx = conn.transaction()?;
+        let model_id = insert_model(&tx, request)?;
+        let pruned_files = prune_versions(&tx, &model_id, &request.version, self.max_versions)?;
+        tx.commit().context("txn commit")?;
+        self.remove_pruned_files(&pruned_files);
 
-            // insert on model
-            tx.prepare(
-                "insert into model values (:id, :name, :model_type, :runtime, :description)",
-            )?
-            .insert(named_params! {
-                ":id": &model_row.id,
-                ":name": &model_row.name,
-                ":model_type": &model_row.model_type,
-                ":runtime": &model_row.runtime,
-                ":description": &model_row.description,
-            })
-            .context("insert model table")?;
+        Ok(model_id)
+    }
 
-            // insert on model_version
-            tx.prepare("insert into model_version values (:id, :version)")?
-                .insert(named_params! { ":id": &model_row.id, ":version": &request.version.to_string() })
-                .context("insert model_version table")?;
+    /// Register several model versions in a single transaction. Every request is attempted, so each
+    /// gets its own result, but the transaction is only committed if all of them succeed.
+    pub async fn register_models(
+        &self,
+        requests: &[RegisterModelRequest],
+    ) -> anyhow::Result<Vec<anyhow::Result<uuid::Uuid>>> {
+        let mut conn = self.connection.lock().await;
+        let mut tx = conn.transaction()?;
 
-            // insert on import_metadata
-            tx.prepare(
-                "insert into import_metadata values (:id, :version, :source_json, :imported_at)",
-            )?
-            .insert(named_params! {
-                ":id": &model_row.id,
-                ":version": &request.version.to_string(),
-                ":source_json": &serde_json::to_string(&request.import_metadata.source)?,
-                ":imported_at": &request.import_metadata.imported_at,
-            })
-            .context("insert import_metadata table")?;
-
-            // insert on model_params
-            tx.prepare("insert into model_params values (:id, :version, :params)")?
-                .insert(named_params! {
-                    ":id": &model_row.id,
-                    ":version": &request.version.to_string(),
-                    ":params": &serde_json::to_string(&request.internal_params)?,
-                })
-                .context("insert model_params table")?;
+        let mut results = Vec::with_capacity(requests.len());
+        let mut pruned_files = Vec::new();
+        for request in requests {
+            // A savepoint per request undoes the partial inserts of a failed one, so later requests
+            // see a consistent DB. It's rolled back if dropped without a commit.
+            let savepoint = tx.savepoint()?;
+            let result = insert_model(&savepoint, request).and_then(|model_id| {
+                let pruned =
+                    prune_versions(&savepoint, &model_id, &request.version, self.max_versions)?;
+                pruned_files.extend(pruned);
+                Ok(model_id)
+            });
+            if result.is_ok() {
+                savepoint.commit()?;
+            }
+            results.push(result);
+        }
 
+        if results.iter().all(|result| result.is_ok()) {
             tx.commit().context("txn commit")?;
+            self.remove_pruned_files(&pruned_files);
+        } else {
+            tx.rollback().context("txn rollback")?;
         }
 
-        Ok(model_id)
+        Ok(results)
     }
 
+    /// Every registered model with its versions, in one query. Models are in the order they were
+    /// registered, and their versions in semver order.
     pub async fn get_models(&self) -> anyhow::Result<Vec<RegisteredModel>> {
-        let mut result_set: Vec<RegisteredModel> = Vec::new();
-        {
-            let mut conn = self.connection.lock().await;
-            let tx = conn.transaction()?;
+        let conn = self.connection.lock().await;
+        // A version is only listed once its params and import metadata are in, and models without any
+        // such version are still listed, with no versions.
+        let mut stmt = conn
+            .prepare(
+                r"
+                select v.version, v.source, v.imported_at, v.size_bytes, v.sha256, v.quantization,
+                    v.found_size_bytes, v.found_sha256,
+                    model.id, model.name, model.model_type, model.runtime, model.description,
+                    model.default_quantization
+                from model left join (
+                    select model_version.model_id, model_version.version, import_metadata.source,
+                        import_metadata.imported_at, model_version.size_bytes, model_version.sha256,
+                        model_version.quantization, model_version.found_size_bytes,
+                        model_version.found_sha256
+                    from model_version, model_params, import_metadata
+                    where   model_version.model_id = model_params.model_id
+                        and model_version.version = model_params.model_version
+                        and model_version.model_id = import_metadata.model_id
+                        and model_version.version = import_metadata.model_version
+                ) as v on v.model_id = model.id
+                order by model.rowid",
+            )
+            .context("prepare join")?;
 
-            let mut stmt =
-                tx.prepare("select id, name, model_type, runtime, description from model")?;
-            let rows = stmt
-                .query_map([], |row| {
-                    Ok(Model {
-                        id: row.get(0)?,
-                        name: row.get(1)?,
-                        model_type: row.get(2)?,
-                        runtime: row.get(3)?,
-                        description: row.get(4)?,
-                    })
-                })
-                .context("query model table")?;
-
-            for row in rows {
-                let row = &row.context("row was malformed")?;
-                let mut stmt = tx.prepare(r"
-                        select model_version.version, import_metadata.source, import_metadata.imported_at
-                        from model, model_version, model_params, import_metadata
-                        where   model.id = model_version.model_id
-                            and model_version.model_id = model_params.model_id
-                            and model_version.version = model_params.model_version
-                            and model_version.model_id = import_metadata.model_id
-                            and model_version.version = import_metadata.model_version
-                            and model_version.model_id = :id
-                            order by model_version.version").context("prepare join")?;
-
-                let mut model_versions: Vec<api_types::ModelVersion> = Vec::new();
-                let mut join_rows = stmt
-                    .query(&[(":id", &row.id)])
-                    .context("query join table")?;
-                while let Some(join_row) = join_rows.next().transpose() {
-                    let join_row = join_row.context("join row was malformed")?;
-                    let (version, import_source, imported_at): (String, String, OffsetDateTime) =
-                        (join_row.get(0)?, join_row.get(1)?, join_row.get(2)?);
-                    let source: api_types::ImportSource =
-                        serde_json::from_str(&import_source).context("parse import_source")?;
-                    model_versions.push(api_types::ModelVersion {
-                        version: semver::Version::parse(&version)?,
-                        import_metadata: api_types::ImportMetadata {
-                            imported_at,
-                            source,
-                        },
-                    })
+        // Rows for the same model are adjacent, so each model is finished once the next one starts.
+        let mut result_set: Vec<RegisteredModel> = Vec::new();
+        let mut current: Option<(Model, Vec<api_types::ModelVersion>)> = None;
+        let mut rows = stmt.query([]).context("query join table")?;
+        while let Some(row) = rows.next().context("join row was malformed")? {
+            let id: String = row.get(8)?;
+            if current.as_ref().map(|(model, _)| &model.id) != Some(&id) {
+                if let Some((model, versions)) = current.take() {
+                    result_set.push(registered_model(&model, versions)?);
                 }
-
-                let model = RegisteredModel {
-                    id: uuid::Uuid::parse_str(&row.id).context("failed to parse UUID")?,
-                    name: row.name.to_string(),
-                    model_type: match row.model_type.as_str() {
-                        "completion" => api_types::ModelType::Completion,
-                        _ => return Err(anyhow::anyhow!("unknown model_type {}", &row.model_type)),
-                    },
-                    runtime: match row.runtime.as_str() {
-                        "ggml" => api_types::Runtime::Ggml,
-                        _ => return Err(anyhow::anyhow!("unknown runtime {}", &row.runtime)),
-                    },
-                    versions: model_versions,
+                let model = Model {
+                    id,
+                    name: row.get(9)?,
+                    model_type: row.get(10)?,
+                    runtime: row.get(11)?,
+                    description: row.get(12)?,
+                    default_quantization: row.get(13)?,
                 };
-                result_set.push(model);
+                current = Some((model, Vec::new()));
+            }
+
+            if row.get::<_, Option<String>>(0)?.is_some() {
+                let (_, versions) = current.as_mut().expect("model row was just read");
+                versions.push(model_version_from_row(row)?);
             }
         }
+        if let Some((model, versions)) = current {
+            result_set.push(registered_model(&model, versions)?);
+        }
 
         Ok(result_set)
     }
 
+    /// Up to `limit` models, ordered by name, starting after the model named `after`. For walking a
+    /// large catalog a page at a time, rather than holding all of it in memory.
+    pub async fn get_models_page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<RegisteredModel>> {
+        let conn = self.connection.lock().await;
+        let page_params = named_params! {":after": after.unwrap_or_default(), ":limit": limit};
+
+        let models: Vec<Model> = conn
+            .prepare(
+                r"select id, name, model_type, runtime, description, default_quantization from model
+                    where name > :after
+                    order by name
+                    limit :limit",
+            )?
+            .query_map(page_params, |row| {
+                Ok(Model {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    model_type: row.get(2)?,
+                    runtime: row.get(3)?,
+                    description: row.get(4)?,
+                    default_quantization: row.get(5)?,
+                })
+            })
+            .context("query model table")?
+            .collect::<Result<_, _>>()
+            .context("row was malformed")?;
+
+        // Versions for the whole page in one query, rather than one per model.
+        let mut versions: HashMap<String, Vec<api_types::ModelVersion>> = HashMap::new();
+        let mut stmt = conn.prepare(
+            r"
+            select model_version.version, import_metadata.source, import_metadata.imported_at,
+                model_version.size_bytes, model_version.sha256, model_version.quantization,
+                model_version.found_size_bytes, model_version.found_sha256, model_version.model_id
+            from model_version, model_params, import_metadata
+            where   model_version.model_id = model_params.model_id
+                and model_version.version = model_params.model_version
+                and model_version.model_id = import_metadata.model_id
+                and model_version.version = import_metadata.model_version
+                and model_version.model_id in (
+                    select id from model where name > :after order by name limit :limit
+                )",
+        )?;
+        let mut rows = stmt.query(page_params).context("query join table")?;
+        while let Some(row) = rows.next().context("join row was malformed")? {
+            versions
+                .entry(row.get(8)?)
+                .or_default()
+                .push(model_version_from_row(row)?);
+        }
+
+        models
+            .iter()
+            .map(|model| registered_model(model, versions.remove(&model.id).unwrap_or_default()))
+            .collect()
+    }
+
+    /// Params of the latest version of a model, used to load it for inference.
+    pub async fn get_model_params(
+        &self,
+        model_name: &str,
+    ) -> anyhow::Result<(semver::Version, api_types::ModelParams)> {
+        let conn = self.connection.lock().await;
+        let mut stmt = conn.prepare(
+            r"select model_params.model_version, model_params.params
+            from model, model_params
+            where model.id = model_params.model_id and model.name = :name",
+        )?;
+        let rows = stmt
+            .query_map(named_params! {":name": model_name}, |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .context("query model_params table")?;
+
+        // Versions are stored as text, so the latest one is picked after parsing rather than in SQL.
+        let mut versions = Vec::new();
+        for row in rows {
+            let (version, params) = row.context("row was malformed")?;
+            versions.push((semver::Version::parse(&version)?, params));
+        }
+
+        let (version, params) = versions
+            .into_iter()
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
+            .with_context(|| format!("no versions found for model {}", model_name))?;
+        let params = serde_json::from_str(&params).context("parse model_params")?;
+
+        Ok((version, params))
+    }
+
+    /// The model file of every registered version, ordered by model name.
+    pub async fn get_model_files(&self) -> anyhow::Result<Vec<RegisteredModelFile>> {
+        let conn = self.connection.lock().await;
+        let mut stmt = conn.prepare(
+            r"select model.name, model_params.model_version,
+                json_extract(model_params.params, '$.model_path')
+            from model, model_params
+            where model.id = model_params.model_id
+            order by model.name",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .context("query model_params table")?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let (model, version, model_path) = row.context("row was malformed")?;
+            files.push(RegisteredModelFile {
+                model,
+                version: semver::Version::parse(&version)?,
+                model_path: PathBuf::from(model_path),
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Runtime of a model, and the params of one of its versions.
+    pub async fn get_model_version_params(
+        &self,
+        model_name: &str,
+        version: &semver::Version,
+    ) -> anyhow::Result<(Runtime, api_types::ModelParams)> {
+        let conn = self.connection.lock().await;
+        let (runtime, params): (String, String) = conn
+            .prepare(
+                r"select model.runtime, model_params.params
+                from model, model_params
+                where   model.id = model_params.model_id
+                    and model.name = :name
+                    and model_params.model_version = :version",
+            )?
+            .query_row(
+                named_params! {":name": model_name, ":version": &version.to_string()},
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context("look up model_params")?;
+
+        let runtime = match runtime.as_str() {
+            "ggml" => Runtime::Ggml,
+            _ => return Err(anyhow::anyhow!("unknown runtime {}", &runtime)),
+        };
+        let params = serde_json::from_str(&params).context("parse model_params")?;
+
+        Ok((runtime, params))
+    }
+
+    /// Replace the params of a model version.
+    pub async fn update_model_version_params(
+        &self,
+        model_name: &str,
+        version: &semver::Version,
+        params: &api_types::ModelParams,
+    ) -> anyhow::Result<()> {
+        let conn = self.connection.lock().await;
+        let updated = conn
+            .prepare(
+                r"update model_params set params = :params
+                where   model_id = (select id from model where name = :name)
+                    and model_version = :version",
+            )?
+            .execute(named_params! {
+                ":name": model_name,
+                ":version": &version.to_string(),
+                ":params": &serde_json::to_string(params)?,
+            })
+            .context("update model_params table")?;
+
+        if updated == 0 {
+            return Err(anyhow::anyhow!(
+                "no params found for model {}@{}",
+                model_name,
+                version
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A model's default quantization, and every version with the quantization it was recorded with.
+    pub async fn get_version_quantizations(
+        &self,
+        model_name: &str,
+    ) -> anyhow::Result<(Option<String>, Vec<(semver::Version, Option<String>)>)> {
+        let conn = self.connection.lock().await;
+        let default_quantization: Option<String> = conn
+            .prepare("select default_quantization from model where name = :name")?
+            .query_row(named_params! {":name": model_name}, |row| row.get(0))
+            .context("look up model")?;
+
+        let mut stmt = conn.prepare(
+            r"select model_version.version, model_version.quantization
+            from model, model_version
+            where model.id = model_version.model_id and model.name = :name",
+        )?;
+        let rows = stmt
+            .query_map(named_params! {":name": model_name}, |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })
+            .context("query model_version table")?;
+
+        let mut versions = Vec::new();
+        for row in rows {
+            let (version, quantization) = row.context("row was malformed")?;
+            versions.push((semver::Version::parse(&version)?, quantization));
+        }
+
+        Ok((default_quantization, versions))
+    }
+
+    /// Set the quantization used when a request doesn't pick a version, or clear it with `None`.
+    pub async fn set_default_quantization(
+        &self,
+        model_name: &str,
+        quantization: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let conn = self.connection.lock().await;
+        let updated = conn
+            .prepare("update model set default_quantization = :quantization where name = :name")?
+            .execute(named_params! {":name": model_name, ":quantization": quantization})
+            .context("update model table")?;
+
+        if updated == 0 {
+            return Err(anyhow::anyhow!("no model found named {}", model_name));
+        }
+
+        Ok(())
+    }
+
+    /// Set how many versions of a model to keep, or go back to the server-wide default with `None`.
+    /// Takes effect the next time a version is registered.
+    pub async fn set_max_versions(
+        &self,
+        model_name: &str,
+        max_versions: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let conn = self.connection.lock().await;
+        let updated = conn
+            .prepare("update model set max_versions = :max_versions where name = :name")?
+            .execute(named_params! {":name": model_name, ":max_versions": max_versions})
+            .context("update model table")?;
+
+        if updated == 0 {
+            return Err(anyhow::anyhow!("no model found named {}", model_name));
+        }
+
+        Ok(())
+    }
+
+    /// Set the most memory in bytes a model may take once loaded, or go back to the server-wide limit
+    /// with `None`. Takes effect the next time the model is loaded.
+    pub async fn set_max_memory(
+        &self,
+        model_name: &str,
+        max_memory_bytes: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let conn = self.connection.lock().await;
+        let updated = conn
+            .prepare("update model set max_memory_bytes = :max_memory_bytes where name = :name")?
+            .execute(named_params! {":name": model_name, ":max_memory_bytes": max_memory_bytes})
+            .context("update model table")?;
+
+        if updated == 0 {
+            return Err(anyhow::anyhow!("no model found named {}", model_name));
+        }
+
+        Ok(())
+    }
+
+    /// The model's own memory limit, see [DB::set_max_memory].
+    pub async fn get_max_memory(&self, model_name: &str) -> anyhow::Result<Option<u64>> {
+        let conn = self.connection.lock().await;
+        let max_memory_bytes = conn
+            .prepare("select max_memory_bytes from model where name = :name")?
+            .query_row(named_params! {":name": model_name}, |row| row.get(0))
+            .context("look up model")?;
+
+        Ok(max_memory_bytes)
+    }
+
+    /// Recorded size and checksum of a model version's file, if any.
+    pub async fn get_model_file(
+        &self,
+        model_name: &str,
+        version: &semver::Version,
+    ) -> anyhow::Result<Option<ModelFile>> {
+        let conn = self.connection.lock().await;
+        let (size_bytes, sha256): (Option<u64>, Option<String>) = conn
+            .prepare(
+                r"select model_version.size_bytes, model_version.sha256
+                from model, model_version
+                where   model.id = model_version.model_id
+                    and model.name = :name
+                    and model_version.version = :version",
+            )?
+            .query_row(
+                named_params! {":name": model_name, ":version": &version.to_string()},
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context("look up model_version")?;
+
+        Ok(size_bytes
+            .zip(sha256)
+            .map(|(size_bytes, sha256)| ModelFile { size_bytes, sha256 }))
+    }
+
+    /// Replace the recorded size and checksum of a model version's file.
+    pub async fn update_model_file(
+        &self,
+        model_name: &str,
+        version: &semver::Version,
+        file: &ModelFile,
+    ) -> anyhow::Result<()> {
+        let conn = self.connection.lock().await;
+        conn.prepare(
+            r"update model_version set size_bytes = :size_bytes, sha256 = :sha256
+            where   model_id = (select id from model where name = :name)
+                and version = :version",
+        )?
+        .execute(named_params! {
+            ":name": model_name,
+            ":version": &version.to_string(),
+            ":size_bytes": &file.size_bytes,
+            ":sha256": &file.sha256,
+        })
+        .context("update model_version table")?;
+
+        Ok(())
+    }
+
+    /// Record the size and checksum of a model version's file as found on disk, when they don't match
+    /// what was recorded for it, or clear them with `None` once the file matches again. The recorded
+    /// size and checksum are left as they are.
+    pub async fn set_file_on_disk(
+        &self,
+        model_name: &str,
+        version: &semver::Version,
+        found: Option<&ModelFile>,
+    ) -> anyhow::Result<()> {
+        let conn = self.connection.lock().await;
+        conn.prepare(
+            r"update model_version
+            set found_size_bytes = :size_bytes, found_sha256 = :sha256
+            where   model_id = (select id from model where name = :name)
+                and version = :version",
+        )?
+        .execute(named_params! {
+            ":name": model_name,
+            ":version": &version.to_string(),
+            ":size_bytes": found.map(|file| file.size_bytes),
+            ":sha256": found.map(|file| &file.sha256),
+        })
+        .context("update model_version table")?;
+
+        Ok(())
+    }
+
     pub async fn get_model_description(&self, model_name: &str) -> anyhow::Result<String> {
         // Model description for type here.
         let mut conn = self.connection.lock().await;
@@ -268,97 +747,682 @@ impl DB {
                     |r| -> Result<String, rusqlite::Error> { Ok(r.get(0)?) },
                 )?;
 
-            let delete_experiment =
-                tx.prepare("delete from saved_experiments where model_id = :model_id and model_version = :version")?;
-            let delete_params = tx.prepare(
-                "delete from model_params where model_id = :model_id and model_version = :version",
-            )?;
-            let delete_import = tx.prepare(
-                "delete from import_metadata where model_id = :model_id and model_version = :version",
-            )?;
-            let delete_version = tx.prepare(
-                "delete from model_version where model_id = :model_id and version = :version",
-            )?;
-
-            for mut stmt in vec![
-                delete_experiment,
-                delete_params,
-                delete_import,
-                delete_version,
-            ] {
-                stmt.execute(
-                    named_params! {":model_id": &model_id, ":version": &version.to_string()},
-                )?;
-            }
+            delete_version_rows(&tx, &model_id, version)?;
 
             tx.commit()?;
         }
         anyhow::Ok(())
     }
-}
-
-/// Root schema for the DB. Should be updated when we add/remove tables
-/// NOTE: This should be merged more cleanly with the migration stuff.
-pub static ROOT_SCHEMA: &'static str = r"
-        create table if not exists model (
-            id          text not null,
-            name        text unique,
-            model_type  text not null,
-            runtime     text not null,
-            description text not null,
 
-            primary key (id)
-        );
+    /// Relabel a version of a model, keeping its import metadata, params and saved experiments. Fails
+    /// with [DBError::VersionExists] rather than merging into another version.
+    pub async fn rename_model_version(
+        &self,
+        model_name: &str,
+        version: &semver::Version,
+        new_version: &semver::Version,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.connection.lock().await;
+        let tx = conn.transaction()?;
+        let model_id: String = tx
+            .prepare("select id from model where name = :name")?
+            .query_row(named_params! {":name": &model_name}, |r| r.get(0))?;
 
-        create table if not exists model_version (
-            model_id    text not null,
-            version     text not null,
+        let version_exists = |version: &semver::Version| -> rusqlite::Result<bool> {
+            tx.prepare(
+                "select count(*) from model_version where model_id = :model_id and version = :version",
+            )?
+            .query_row(
+                named_params! {":model_id": &model_id, ":version": &version.to_string()},
+                |r| r.get::<_, i64>(0),
+            )
+            .map(|count| count > 0)
+        };
+        if !version_exists(version)? {
+            return Err(DBError::VersionNotFound.into());
+        }
+        if version_exists(new_version)? {
+            return Err(DBError::VersionExists.into());
+        }
 
-            primary key (model_id, version),
-            foreign key (model_id) references model(id)
-        );
+        // The version is part of every child table's foreign key, so they only line up again once
+        // all of them are updated. Deferring the checks to commit lets them change one at a time.
+        tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+        for update in [
+            "update model_version set version = :new_version where model_id = :model_id and version = :version",
+            "update import_metadata set model_version = :new_version where model_id = :model_id and model_version = :version",
+            "update model_params set model_version = :new_version where model_id = :model_id and model_version = :version",
+            "update saved_experiments set model_version = :new_version where model_id = :model_id and model_version = :version",
+        ] {
+            tx.execute(
+                update,
+                named_params! {
+                    ":model_id": &model_id,
+                    ":version": &version.to_string(),
+                    ":new_version": &new_version.to_string(),
+                },
+            )?;
+        }
+        tx.commit()?;
 
-        create table if not exists import_metadata (
-            model_id        text not null,
-            model_version   text not null,
-            source text     not null,
-            imported_at     datetime not null,
+        Ok(())
+    }
 
-            primary key (model_id, model_version),
-            foreign key (model_id) references model(id),
-            foreign key (model_version) references model_version(version)
-        );
+    /// Save a completion experiment against a registered model version, returning its ID. Prompts and
+    /// outputs over the [ExperimentLimits] are truncated before they're stored.
+    pub async fn save_experiment(
+        &self,
+        request: &SaveExperimentRequest,
+    ) -> anyhow::Result<uuid::Uuid> {
+        let experiment_id = uuid::Uuid::new_v4();
+        let prompt = truncate_with_marker(&request.prompt, self.experiment_limits.max_prompt_bytes);
+        let output = truncate_with_marker(&request.output, self.experiment_limits.max_output_bytes);
 
-        create table if not exists model_params (
-            model_id        text not null,
-            model_version   text not null,
-            params          text not null,
+        let mut conn = self.connection.lock().await;
+        {
+            let tx = conn.transaction()?;
+            let model_id: String = tx
+                .prepare("select id from model where name = :name")?
+                .query_row(
+                    named_params! {":name": &request.model},
+                    |r| -> Result<String, rusqlite::Error> { r.get(0) },
+                )
+                .context("look up model for experiment")?;
 
-            primary key (model_id, model_version),
-            foreign key (model_id) references model(id),
-            foreign key (model_version) references model_version(version)
-        );
+            let sampling_params = request
+                .sampling
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
 
-        create table if not exists saved_experiments (
-            id              text not null,
-            model_id        text not null,
+            tx.prepare(
+                r"insert into saved_experiments
+                    (id, model_id, model_version, temperature, tokens, prompt, output, created_at, sampling_params)
+                    values (:id, :model_id, :version, :temperature, :tokens, :prompt, :output, :created_at, :sampling_params)",
+            )?
+            .insert(named_params! {
+                ":id": &experiment_id.to_string(),
+                ":model_id": &model_id,
+                ":version": &request.version.to_string(),
+                ":temperature": &request.temperature,
+                ":tokens": &request.tokens,
+                ":prompt": &prompt,
+                ":output": &output,
+                ":created_at": &format_timestamp(&OffsetDateTime::now_utc())?,
+                ":sampling_params": &sampling_params,
+            })
+            .context("insert saved_experiments table")?;
+
+            tx.commit()?;
+        }
+
+        Ok(experiment_id)
+    }
+
+    /// Look up a saved experiment by ID.
+    pub async fn get_experiment(&self, id: &uuid::Uuid) -> anyhow::Result<SavedExperiment> {
+        let conn = self.connection.lock().await;
+        let (model, version, prompt, output, sampling_params) = conn
+            .prepare(
+                r"select model.name, e.model_version, e.prompt, e.output, e.sampling_params
+                from saved_experiments e
+                join model on model.id = e.model_id
+                where e.id = :id",
+            )?
+            .query_row(named_params! {":id": &id.to_string()}, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })
+            .context("look up saved experiment")?;
+
+        Ok(SavedExperiment {
+            id: *id,
+            model,
+            version: semver::Version::parse(&version)?,
+            prompt,
+            output,
+            sampling: sampling_params
+                .map(|params| serde_json::from_str(&params))
+                .transpose()
+                .context("parse experiment sampling params")?,
+        })
+    }
+
+    /// Record the import jobs cut short by a shutdown, replacing those recorded by the last one.
+    pub async fn replace_interrupted_imports(
+        &self,
+        imports: &[InterruptedImport],
+    ) -> anyhow::Result<()> {
+        let mut conn = self.connection.lock().await;
+        let tx = conn.transaction()?;
+        tx.execute("delete from interrupted_import", [])
+            .context("clear interrupted_import table")?;
+        {
+            let mut stmt = tx.prepare(
+                r"insert into interrupted_import (job_id, task, downloaded_bytes, interrupted_at)
+                values (:job_id, :task, :downloaded_bytes, :interrupted_at)",
+            )?;
+            for import in imports {
+                stmt.execute(named_params! {
+                    ":job_id": import.job_id.to_string(),
+                    ":task": serde_json::to_string(&import.task)?,
+                    ":downloaded_bytes": import.downloaded_bytes,
+                    ":interrupted_at": &format_timestamp(&import.interrupted_at)?,
+                })
+                .context("insert into interrupted_import table")?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// The import jobs cut short by the last shutdown, oldest first.
+    pub async fn get_interrupted_imports(&self) -> anyhow::Result<Vec<InterruptedImport>> {
+        let conn = self.connection.lock().await;
+        let mut stmt = conn.prepare(
+            r"select job_id, task, downloaded_bytes, interrupted_at
+            from interrupted_import
+            order by interrupted_at, job_id",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u64>(2)?,
+                    row.get::<_, OffsetDateTime>(3)?,
+                ))
+            })
+            .context("query interrupted_import table")?;
+
+        let mut imports = Vec::new();
+        for row in rows {
+            let (job_id, task, downloaded_bytes, interrupted_at) =
+                row.context("row was malformed")?;
+            imports.push(InterruptedImport {
+                job_id: job_id.parse().context("parse job_id")?,
+                task: serde_json::from_str(&task).context("parse task")?,
+                downloaded_bytes,
+                interrupted_at,
+            });
+        }
+
+        Ok(imports)
+    }
+
+    /// Refresh the query planner's statistics, and rebuild the DB file to return the free pages left
+    /// behind by deletes to the filesystem. Everything else waits on the connection while this runs,
+    /// so the rebuild is skipped when there's nothing to reclaim.
+    pub async fn vacuum(&self) -> anyhow::Result<VacuumReport> {
+        let conn = self.connection.lock().await;
+        let file_size = |conn: &Connection| -> anyhow::Result<u64> {
+            let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+            let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+            Ok(page_count * page_size)
+        };
+
+        conn.execute_batch("PRAGMA optimize")
+            .context("failed to optimize DB")?;
+
+        let bytes_before = file_size(&conn)?;
+        let free_pages: u64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+        let vacuumed = free_pages > 0;
+        if vacuumed {
+            conn.execute_batch("VACUUM")
+                .context("failed to vacuum DB")?;
+        }
+
+        Ok(VacuumReport {
+            bytes_before,
+            bytes_after: file_size(&conn)?,
+            vacuumed,
+        })
+    }
+}
+
+/// Vacuum `db` every `interval`, see [DB::vacuum], logging the space reclaimed.
+pub fn spawn_vacuum_task(db: Arc<DB>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes immediately, don't vacuum while the server is starting up.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match db.vacuum().await {
+                Ok(report) => info!(
+                    "vacuumed DB: reclaimed {} bytes ({} -> {})",
+                    report.reclaimed_bytes(),
+                    report.bytes_before,
+                    report.bytes_after
+                ),
+                Err(err) => error!("scheduled DB vacuum failed: {:#}", err),
+            }
+        }
+    })
+}
+
+/// Delete a version of a model along with its import metadata, params and saved experiments.
+fn delete_version_rows(
+    conn: &Connection,
+    model_id: &str,
+    version: &semver::Version,
+) -> anyhow::Result<()> {
+    let delete_experiment = conn.prepare(
+        "delete from saved_experiments where model_id = :model_id and model_version = :version",
+    )?;
+    let delete_params = conn.prepare(
+        "delete from model_params where model_id = :model_id and model_version = :version",
+    )?;
+    let delete_import = conn.prepare(
+        "delete from import_metadata where model_id = :model_id and model_version = :version",
+    )?;
+    let delete_version = conn
+        .prepare("delete from model_version where model_id = :model_id and version = :version")?;
+
+    for mut stmt in [
+        delete_experiment,
+        delete_params,
+        delete_import,
+        delete_version,
+    ] {
+        stmt.execute(named_params! {":model_id": model_id, ":version": &version.to_string()})?;
+    }
+
+    Ok(())
+}
+
+/// Delete the oldest versions of a model while it has more than it may keep: its own limit, or else
+/// `default_max_versions`. The version just registered is never pruned, and neither is the active
+/// version, the one requests that don't pick a version run. Returns the files of pruned versions
+/// that are safe to delete, see [prunable_file].
+fn prune_versions(
+    conn: &Connection,
+    model_id: &uuid::Uuid,
+    registered: &semver::Version,
+    default_max_versions: Option<u32>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let model_id = model_id.to_string();
+    let (max_versions, default_quantization): (Option<u32>, Option<String>) = conn
+        .prepare("select max_versions, default_quantization from model where id = :id")?
+        .query_row(named_params! {":id": &model_id}, |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .context("look up model")?;
+    let Some(max_versions) = max_versions.or(default_max_versions) else {
+        return Ok(Vec::new());
+    };
+
+    let mut versions = Vec::new();
+    let mut stmt =
+        conn.prepare("select version, quantization from model_version where model_id = :id")?;
+    let rows = stmt.query_map(named_params! {":id": &model_id}, |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+    })?;
+    for row in rows {
+        let (version, quantization) = row.context("row was malformed")?;
+        versions.push((semver::Version::parse(&version)?, quantization));
+    }
+
+    let excess = versions.len().saturating_sub(max_versions as usize);
+    let active = select_version(
+        &versions,
+        default_quantization.as_deref(),
+        &VersionSelector::default(),
+    );
+    let mut candidates: Vec<_> = versions
+        .into_iter()
+        .map(|(version, _)| version)
+        .filter(|version| version != registered && Some(version) != active.as_ref())
+        .collect();
+    candidates.sort();
+
+    let mut files = Vec::new();
+    for version in candidates.into_iter().take(excess) {
+        files.extend(prunable_file(conn, &model_id, &version)?);
+        delete_version_rows(conn, &model_id, &version)?;
+        info!("pruned version {} of model {}", version, model_id);
+    }
+
+    // Another version, possibly of another model, may have been registered from the same file.
+    let mut still_used = conn
+        .prepare("select 1 from model_params where json_extract(params, '$.model_path') = :path")?;
+    let mut prunable = Vec::new();
+    for file in files {
+        let path = file.to_string_lossy();
+        if !still_used.exists(named_params! {":path": path})? {
+            prunable.push(file);
+        }
+    }
+
+    Ok(prunable)
+}
+
+/// The model file of a version, if the server downloaded it. Files imported from disk belong to
+/// whoever put them there, and files picked up from the Hugging Face cache to the cache, so both are
+/// left alone.
+fn prunable_file(
+    conn: &Connection,
+    model_id: &str,
+    version: &semver::Version,
+) -> anyhow::Result<Option<PathBuf>> {
+    let row: Option<(String, String)> = conn
+        .prepare(
+            r"select import_metadata.source, model_params.params
+            from import_metadata, model_params
+            where   import_metadata.model_id = model_params.model_id
+                and import_metadata.model_version = model_params.model_version
+                and model_params.model_id = :id
+                and model_params.model_version = :version",
+        )?
+        .query_row(
+            named_params! {":id": model_id, ":version": &version.to_string()},
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .context("look up model file")?;
+    let Some((source, params)) = row else {
+        return Ok(None);
+    };
+
+    let source: api_types::ImportSource =
+        serde_json::from_str(&source).context("parse import_source")?;
+    let api_types::ModelParams::COMPLETION(params) =
+        serde_json::from_str(&params).context("parse model_params")?;
+
+    let downloaded = matches!(source, api_types::ImportSource::HF { .. })
+        && download::is_download(&params.model_path);
+    Ok(downloaded.then_some(params.model_path))
+}
+
+impl DB {
+    fn remove_pruned_files(&self, paths: &[PathBuf]) {
+        if self.read_only_store {
+            for path in paths {
+                info!(
+                    "keeping pruned model file {:?} in the read-only store",
+                    path
+                );
+            }
+            return;
+        }
+
+        remove_model_files(paths);
+    }
+}
+
+/// Delete the files of pruned versions, see [prunable_file]. Only the registered path goes, so a
+/// download that was a symlink never takes a blob other files may point to with it. Failures are
+/// logged rather than undoing the prune.
+fn remove_model_files(paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(()) => info!("deleted pruned model file {:?}", path),
+            Err(err) => warn!("failed to delete pruned model file {:?}: {}", path, err),
+        }
+    }
+}
+
+/// Insert all rows for a new model version, as part of a larger transaction.
+fn insert_model(conn: &Connection, request: &RegisterModelRequest) -> anyhow::Result<uuid::Uuid> {
+    let model_row = Model {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.model.clone(),
+        model_type: match request.model_type {
+            ModelType::Completion => "completion".to_string(),
+        },
+        runtime: match request.runtime {
+            Runtime::Ggml => "ggml".to_string(),
+        },
+        description: "".to_string(),
+        default_quantization: None,
+    };
+
+    // A new version of an existing model is added to it, e.g. another quantization of the same weights.
+    let existing: Option<(String, String, String)> = conn
+        .prepare("select id, model_type, runtime from model where name = :name")?
+        .query_row(named_params! {":name": &model_row.name}, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()
+        .context("look up model")?;
+
+    let model_row = match existing {
+        Some((id, model_type, runtime)) => {
+            if model_type != model_row.model_type || runtime != model_row.runtime {
+                return Err(anyhow::anyhow!(
+                    "model {} is already registered as a {} {} model",
+                    model_row.name,
+                    runtime,
+                    model_type
+                ));
+            }
+
+            Model { id, ..model_row }
+        }
+        None => {
+            // insert on model
+            conn.prepare(
+                r"insert into model (id, name, model_type, runtime, description)
+                values (:id, :name, :model_type, :runtime, :description)",
+            )?
+            .insert(named_params! {
+                ":id": &model_row.id,
+                ":name": &model_row.name,
+                ":model_type": &model_row.model_type,
+                ":runtime": &model_row.runtime,
+                ":description": &model_row.description,
+            })
+            .context("insert model table")?;
+
+            model_row
+        }
+    };
+    let model_id = uuid::Uuid::parse_str(&model_row.id).context("failed to parse UUID")?;
+
+    // insert on model_version
+    conn.prepare(
+        r"insert into model_version (model_id, version, size_bytes, sha256, quantization)
+        values (:id, :version, :size_bytes, :sha256, :quantization)",
+    )?
+    .insert(named_params! {
+        ":id": &model_row.id,
+        ":version": &request.version.to_string(),
+        ":size_bytes": &request.file.as_ref().map(|file| file.size_bytes),
+        ":sha256": &request.file.as_ref().map(|file| &file.sha256),
+        ":quantization": &request.quantization,
+    })
+    .context("insert model_version table")?;
+
+    // insert on import_metadata
+    conn.prepare("insert into import_metadata values (:id, :version, :source_json, :imported_at)")?
+        .insert(named_params! {
+            ":id": &model_row.id,
+            ":version": &request.version.to_string(),
+            ":source_json": &serde_json::to_string(&request.import_metadata.source)?,
+            ":imported_at": &format_timestamp(&request.import_metadata.imported_at)?,
+        })
+        .context("insert import_metadata table")?;
+
+    // insert on model_params
+    conn.prepare("insert into model_params values (:id, :version, :params)")?
+        .insert(named_params! {
+            ":id": &model_row.id,
+            ":version": &request.version.to_string(),
+            ":params": &serde_json::to_string(&request.internal_params)?,
+        })
+        .context("insert model_params table")?;
+
+    Ok(model_id)
+}
+
+/// Cut `text` down to at most `max_bytes`, ending it with [TRUNCATION_MARKER] if anything was removed.
+/// A cap too small to fit the whole marker gets as much of the marker as fits.
+fn truncate_with_marker(text: &str, max_bytes: usize) -> Cow<'_, str> {
+    if text.len() <= max_bytes {
+        return Cow::Borrowed(text);
+    }
+    if max_bytes < TRUNCATION_MARKER.len() {
+        return Cow::Borrowed(&TRUNCATION_MARKER[..max_bytes]);
+    }
+
+    let mut end = max_bytes - TRUNCATION_MARKER.len();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    Cow::Owned(format!("{}{}", &text[..end], TRUNCATION_MARKER))
+}
+
+/// Format a timestamp for storage in a `datetime` column.
+///
+/// All timestamps are stored as RFC3339 text, which keeps both the original UTC offset and the full
+/// nanosecond precision. rusqlite's default `ToSql` impl converts to UTC and drops the offset, so
+/// timestamps should always go through here rather than being bound directly.
+fn format_timestamp(timestamp: &OffsetDateTime) -> anyhow::Result<String> {
+    timestamp
+        .format(&Rfc3339)
+        .context("failed to format timestamp as RFC3339")
+}
+
+/// Root schema for the DB. Should be updated when we add/remove tables
+/// NOTE: This should be merged more cleanly with the migration stuff.
+pub static ROOT_SCHEMA: &'static str = r"
+        create table if not exists model (
+            id          text not null,
+            name        text unique,
+            model_type  text not null,
+            runtime     text not null,
+            description text not null,
+            default_quantization text,
+            max_versions integer,
+            max_memory_bytes integer,
+
+            primary key (id)
+        );
+
+        create table if not exists model_version (
+            model_id    text not null,
+            version     text not null,
+            size_bytes  integer,
+            sha256      text,
+            quantization text,
+            found_size_bytes integer,
+            found_sha256 text,
+
+            primary key (model_id, version),
+            foreign key (model_id) references model(id)
+        );
+
+        create table if not exists import_metadata (
+            model_id        text not null,
+            model_version   text not null,
+            source text     not null,
+            imported_at     datetime not null,
+
+            primary key (model_id, model_version),
+            foreign key (model_id) references model(id),
+            foreign key (model_version) references model_version(version)
+        );
+
+        create table if not exists model_params (
+            model_id        text not null,
+            model_version   text not null,
+            params          text not null,
+
+            primary key (model_id, model_version),
+            foreign key (model_id) references model(id),
+            foreign key (model_version) references model_version(version)
+        );
+
+        create table if not exists saved_experiments (
+            id              text not null,
+            model_id        text not null,
             model_version   text not null,
             temperature     float not null,
             tokens          integer not null,
             prompt          text not null,
             output          text not null,
             created_at      datetime not null,
+            sampling_params text,
 
             primary key (id),
             foreign key (model_id) references model(id),
             foreign key (model_version) references model(version)
         );
+
+        create table if not exists interrupted_import (
+            job_id              text primary key,
+            task                text not null,
+            downloaded_bytes    integer not null,
+            interrupted_at      datetime not null
+        );
 ";
 
 #[cfg(test)]
-mod test {
-    use super::DB;
-    use super::ROOT_SCHEMA;
+pub(crate) mod test {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        path::PathBuf,
+    };
+
+    use semver::Version;
+    use tempdir::TempDir;
+    use time::macros::datetime;
+
+    use super::{
+        model_version_from_row, registered_model, truncate_with_marker, DBError, ExperimentLimits,
+        DB, ROOT_SCHEMA, TRUNCATION_MARKER,
+    };
+    use crate::api_types::{
+        CompletionModelParams, DiskLocator, HFLocator, ImportJob, ImportMetadata, ImportSource,
+        InterruptedImport, ModelFile, ModelParams, ModelType, RegisterModelRequest,
+        RegisteredModel, Runtime, SamplingParams, SaveExperimentRequest, SavedExperiment,
+    };
+    use crate::db::migration::{Migration, V0, V1, V2, V3, V4, V5, V6};
+    use crate::db_types::Model;
+
+    /// Open a DB in `dir` with all migrations applied.
+    pub(crate) async fn migrated_db(dir: &TempDir) -> DB {
+        let db = DB::open(dir.path().join("test.db")).unwrap();
+        V0.forward(&*db.connection.lock().await).unwrap();
+        V1.forward(&*db.connection.lock().await).unwrap();
+        V2.forward(&*db.connection.lock().await).unwrap();
+        V3.forward(&*db.connection.lock().await).unwrap();
+        V4.forward(&*db.connection.lock().await).unwrap();
+        V5.forward(&*db.connection.lock().await).unwrap();
+        V6.forward(&*db.connection.lock().await).unwrap();
+
+        db
+    }
+
+    pub(crate) fn register_request(name: &str, version: Version) -> RegisterModelRequest {
+        RegisterModelRequest {
+            model: name.to_owned(),
+            version,
+            model_type: ModelType::Completion,
+            runtime: Runtime::Ggml,
+            import_metadata: ImportMetadata {
+                imported_at: datetime!(2023-09-01 12:34:56.123456789 +05:30),
+                source: ImportSource::DISK {
+                    source: DiskLocator {
+                        path: PathBuf::from("/models/model.gguf"),
+                    },
+                },
+            },
+            internal_params: ModelParams::COMPLETION(CompletionModelParams {
+                model_path: PathBuf::from("/models/model.gguf"),
+                default_sampling: None,
+            }),
+            file: None,
+            quantization: None,
+        }
+    }
 
     #[tokio::test]
     async fn test_simple() {
@@ -374,4 +1438,699 @@ mod test {
         // Run the actual test
         assert!(db.get_models().await.unwrap().is_empty());
     }
+
+    /// Every table in `db` with the names of its columns.
+    async fn table_columns(db: &DB) -> BTreeMap<String, BTreeSet<String>> {
+        let conn = db.connection.lock().await;
+        let mut stmt = conn
+            .prepare(
+                "select m.name, p.name from sqlite_master m join pragma_table_info(m.name) p \
+                 where m.type = 'table'",
+            )
+            .unwrap();
+        let mut tables: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for row in stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+        {
+            let (table, column) = row.unwrap();
+            tables.entry(table).or_default().insert(column);
+        }
+
+        tables
+    }
+
+    #[tokio::test]
+    async fn test_root_schema_matches_migrations() {
+        let dir = TempDir::new("db_test").unwrap();
+        let root = DB::open(dir.path().join("root.db")).unwrap();
+        root.connection
+            .lock()
+            .await
+            .execute_batch(ROOT_SCHEMA)
+            .unwrap();
+
+        let columns = table_columns(&root).await;
+        assert!(columns["model"].contains("default_quantization"));
+        assert_eq!(columns, table_columns(&migrated_db(&dir).await).await);
+    }
+
+    #[tokio::test]
+    async fn test_imported_at_round_trip() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+
+        let request = register_request("my-model", Version::new(0, 1, 0));
+        db.register_model(&request).await.unwrap();
+
+        let models = db.get_models().await.unwrap();
+        let imported_at = models[0].versions[0].import_metadata.imported_at;
+
+        // OffsetDateTime equality only compares the instant, so check the offset separately.
+        assert_eq!(imported_at, request.import_metadata.imported_at);
+        assert_eq!(
+            imported_at.offset(),
+            request.import_metadata.imported_at.offset()
+        );
+        assert_eq!(imported_at.nanosecond(), 123_456_789);
+    }
+
+    #[test]
+    fn test_truncate_with_marker_tiny_cap() {
+        assert_eq!(truncate_with_marker("short", 5), "short");
+        assert_eq!(truncate_with_marker("some long text", 0), "");
+        assert_eq!(truncate_with_marker("some long text", 4), "...[");
+
+        let exact = "x".repeat(TRUNCATION_MARKER.len() + 1);
+        assert_eq!(
+            truncate_with_marker(&exact, TRUNCATION_MARKER.len()),
+            TRUNCATION_MARKER
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_experiment_truncates() {
+        let dir = TempDir::new("db_test").unwrap();
+        let mut db = migrated_db(&dir).await;
+        db.experiment_limits = ExperimentLimits {
+            max_prompt_bytes: 32,
+            max_output_bytes: 32,
+        };
+        db.register_model(&register_request("my-model", Version::new(0, 1, 0)))
+            .await
+            .unwrap();
+
+        let id = db
+            .save_experiment(&SaveExperimentRequest {
+                model: "my-model".to_owned(),
+                version: Version::new(0, 1, 0),
+                temperature: 0.7,
+                tokens: 20,
+                prompt: "é".repeat(100),
+                output: "short output".to_owned(),
+                sampling: None,
+            })
+            .await
+            .unwrap();
+
+        let (prompt, output): (String, String) = db
+            .connection
+            .lock()
+            .await
+            .query_row(
+                "select prompt, output from saved_experiments where id = ?",
+                [id.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+
+        assert!(prompt.len() <= 32);
+        assert!(prompt.ends_with(TRUNCATION_MARKER));
+        assert!(prompt
+            .trim_end_matches(TRUNCATION_MARKER)
+            .chars()
+            .all(|c| c == 'é'));
+        assert_eq!(output, "short output");
+    }
+
+    #[tokio::test]
+    async fn test_experiment_sampling_round_trip() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        db.register_model(&register_request("my-model", Version::new(0, 1, 0)))
+            .await
+            .unwrap();
+
+        let sampling = SamplingParams {
+            seed: Some(42),
+            temperature: 0.7,
+            top_p: 0.9,
+            repeat_penalty: 1.1,
+            ..Default::default()
+        };
+        let id = db
+            .save_experiment(&SaveExperimentRequest {
+                model: "my-model".to_owned(),
+                version: Version::new(0, 1, 0),
+                temperature: 0.7,
+                tokens: 20,
+                prompt: "Hello".to_owned(),
+                output: " world".to_owned(),
+                sampling: Some(sampling.clone()),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            db.get_experiment(&id).await.unwrap(),
+            SavedExperiment {
+                id,
+                model: "my-model".to_owned(),
+                version: Version::new(0, 1, 0),
+                prompt: "Hello".to_owned(),
+                output: " world".to_owned(),
+                sampling: Some(sampling),
+            }
+        );
+        assert!(db.get_experiment(&uuid::Uuid::new_v4()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_model_params() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        db.register_model(&register_request("my-model", Version::new(0, 1, 0)))
+            .await
+            .unwrap();
+
+        let (version, ModelParams::COMPLETION(params)) =
+            db.get_model_params("my-model").await.unwrap();
+        assert_eq!(version, Version::new(0, 1, 0));
+        assert_eq!(params.model_path, PathBuf::from("/models/model.gguf"));
+
+        assert!(db.get_model_params("other-model").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_register_models_is_atomic() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+
+        // The second entry clashes with the first on the model version, so neither is registered.
+        let results = db
+            .register_models(&[
+                register_request("model-a", Version::new(0, 1, 0)),
+                register_request("model-a", Version::new(0, 1, 0)),
+            ])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(db.get_models().await.unwrap().is_empty());
+
+        let results = db
+            .register_models(&[
+                register_request("model-a", Version::new(0, 1, 0)),
+                register_request("model-b", Version::new(0, 1, 0)),
+            ])
+            .await
+            .unwrap();
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(db.get_models().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_model_file_round_trip() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        let version = Version::new(0, 1, 0);
+        let file = ModelFile {
+            size_bytes: 11,
+            sha256: "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_owned(),
+        };
+
+        db.register_model(&RegisterModelRequest {
+            file: Some(file.clone()),
+            ..register_request("my-model", version.clone())
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            db.get_models().await.unwrap()[0].versions[0].file,
+            Some(file.clone())
+        );
+
+        let changed = ModelFile {
+            size_bytes: 12,
+            ..file.clone()
+        };
+        db.set_file_on_disk("my-model", &version, Some(&changed))
+            .await
+            .unwrap();
+        // A changed file is reported alongside the recorded one, which is kept.
+        let model_version = db.get_models().await.unwrap()[0].versions[0].clone();
+        assert_eq!(model_version.file, Some(file.clone()));
+        assert_eq!(model_version.file_on_disk, Some(changed.clone()));
+
+        db.set_file_on_disk("my-model", &version, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_models().await.unwrap()[0].versions[0].file_on_disk,
+            None
+        );
+
+        db.update_model_file("my-model", &version, &changed)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_model_file("my-model", &version).await.unwrap(),
+            Some(changed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quantized_versions() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        for (version, quantization) in [
+            (Version::new(0, 1, 0), "Q4_K_M"),
+            (Version::new(0, 2, 0), "Q8_0"),
+        ] {
+            db.register_model(&RegisterModelRequest {
+                quantization: Some(quantization.to_owned()),
+                ..register_request("my-model", version)
+            })
+            .await
+            .unwrap();
+        }
+
+        // Both quantizations are versions of the same model.
+        let models = db.get_models().await.unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].versions[1].quantization.as_deref(), Some("Q8_0"));
+
+        db.set_default_quantization("my-model", Some("Q8_0"))
+            .await
+            .unwrap();
+        let (default_quantization, versions) =
+            db.get_version_quantizations("my-model").await.unwrap();
+        assert_eq!(default_quantization.as_deref(), Some("Q8_0"));
+        assert_eq!(versions.len(), 2);
+
+        assert!(db
+            .set_default_quantization("other-model", None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_vacuum_reclaims_deleted_rows() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+
+        for i in 0..50 {
+            let name = format!("model-{}", i);
+            db.register_model(&register_request(&name, Version::new(0, 1, 0)))
+                .await
+                .unwrap();
+            db.update_model_description(&name, &"x".repeat(4096))
+                .await
+                .unwrap();
+        }
+        for i in 0..50 {
+            db.delete_model(&format!("model-{}", i)).await.unwrap();
+        }
+
+        let report = db.vacuum().await.unwrap();
+        assert!(report.vacuumed);
+        assert!(report.reclaimed_bytes() > 0);
+
+        // Nothing left to reclaim, so the second run skips the rebuild.
+        let report = db.vacuum().await.unwrap();
+        assert!(!report.vacuumed);
+        assert_eq!(report.bytes_before, report.bytes_after);
+    }
+
+    #[tokio::test]
+    async fn test_rename_model_version() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        let (v1, v2, v3) = (
+            Version::new(0, 1, 0),
+            Version::new(0, 2, 0),
+            Version::new(1, 0, 0),
+        );
+        db.register_model(&register_request("my-model", v1.clone()))
+            .await
+            .unwrap();
+        db.register_model(&register_request("my-model", v2.clone()))
+            .await
+            .unwrap();
+
+        let clash = db.rename_model_version("my-model", &v1, &v2).await;
+        assert!(matches!(
+            clash.unwrap_err().downcast_ref::<DBError>(),
+            Some(DBError::VersionExists)
+        ));
+        let missing = db.rename_model_version("my-model", &v3, &v1).await;
+        assert!(matches!(
+            missing.unwrap_err().downcast_ref::<DBError>(),
+            Some(DBError::VersionNotFound)
+        ));
+
+        db.rename_model_version("my-model", &v1, &v3).await.unwrap();
+        let models = db.get_models().await.unwrap();
+        let mut versions: Vec<Version> = models[0]
+            .versions
+            .iter()
+            .map(|version| version.version.clone())
+            .collect();
+        versions.sort();
+        assert_eq!(versions, vec![v2, v3.clone()]);
+
+        // The params and import metadata moved along with the version.
+        db.get_model_version_params("my-model", &v3).await.unwrap();
+        let renamed = models[0]
+            .versions
+            .iter()
+            .find(|version| version.version == v3)
+            .unwrap();
+        assert_eq!(
+            renamed.import_metadata.imported_at,
+            register_request("my-model", v1).import_metadata.imported_at
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_models_page() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        for name in ["c", "a", "e", "b", "d"] {
+            db.register_model(&register_request(name, Version::new(0, 1, 0)))
+                .await
+                .unwrap();
+        }
+        db.register_model(&register_request("b", Version::new(0, 2, 0)))
+            .await
+            .unwrap();
+
+        let mut names = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let page = db.get_models_page(after.as_deref(), 2).await.unwrap();
+            names.extend(page.iter().map(|model| model.name.clone()));
+            if page.len() < 2 {
+                break;
+            }
+            after = page.last().map(|model| model.name.clone());
+        }
+        assert_eq!(names, vec!["a", "b", "c", "d", "e"]);
+
+        // Each model comes with its own versions, same as the full listing.
+        let page = db.get_models_page(Some("a"), 1).await.unwrap();
+        let versions: Vec<Version> = page[0]
+            .versions
+            .iter()
+            .map(|version| version.version.clone())
+            .collect();
+        assert_eq!(versions, vec![Version::new(0, 1, 0), Version::new(0, 2, 0)]);
+        let all = db.get_models().await.unwrap();
+        assert_eq!(
+            page[0],
+            *all.iter().find(|model| model.name == "b").unwrap()
+        );
+    }
+
+    /// [DB::get_models] as it was before it was a single join, querying the versions of each model
+    /// separately, to check the join against.
+    async fn get_models_per_model(db: &DB) -> Vec<RegisteredModel> {
+        let conn = db.connection.lock().await;
+        let models: Vec<Model> = conn
+            .prepare(
+                "select id, name, model_type, runtime, description, default_quantization from model",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok(Model {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    model_type: row.get(2)?,
+                    runtime: row.get(3)?,
+                    description: row.get(4)?,
+                    default_quantization: row.get(5)?,
+                })
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let mut result_set = Vec::new();
+        for model in &models {
+            let mut stmt = conn
+                .prepare(
+                    r"
+                    select model_version.version, import_metadata.source, import_metadata.imported_at,
+                        model_version.size_bytes, model_version.sha256, model_version.quantization,
+                        model_version.found_size_bytes, model_version.found_sha256
+                    from model, model_version, model_params, import_metadata
+                    where   model.id = model_version.model_id
+                        and model_version.model_id = model_params.model_id
+                        and model_version.version = model_params.model_version
+                        and model_version.model_id = import_metadata.model_id
+                        and model_version.version = import_metadata.model_version
+                        and model_version.model_id = :id",
+                )
+                .unwrap();
+            let mut versions = Vec::new();
+            let mut rows = stmt.query(&[(":id", &model.id)]).unwrap();
+            while let Some(row) = rows.next().unwrap() {
+                versions.push(model_version_from_row(row).unwrap());
+            }
+            result_set.push(registered_model(model, versions).unwrap());
+        }
+
+        result_set
+    }
+
+    #[tokio::test]
+    async fn test_get_models_join() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        for (name, version, quantization) in [
+            ("zeta", (0, 1, 0), None),
+            ("alpha", (0, 2, 0), Some("Q4_K_M")),
+            ("alpha", (0, 10, 0), Some("Q8_0")),
+            ("alpha", (0, 1, 0), None),
+            ("beta", (1, 0, 0), None),
+        ] {
+            let version = Version::new(version.0, version.1, version.2);
+            db.register_model(&RegisterModelRequest {
+                file: Some(ModelFile {
+                    size_bytes: version.minor,
+                    sha256: format!("{:064}", version.minor),
+                }),
+                quantization: quantization.map(str::to_owned),
+                ..register_request(name, version)
+            })
+            .await
+            .unwrap();
+        }
+        db.delete_model_version("zeta", &Version::new(0, 1, 0))
+            .await
+            .unwrap();
+        db.update_model_description("beta", "the second one")
+            .await
+            .unwrap();
+        db.set_default_quantization("alpha", Some("Q8_0"))
+            .await
+            .unwrap();
+        let found = ModelFile {
+            size_bytes: 1,
+            sha256: "0".repeat(64),
+        };
+        db.set_file_on_disk("alpha", &Version::new(0, 2, 0), Some(&found))
+            .await
+            .unwrap();
+
+        let models = db.get_models().await.unwrap();
+        assert_eq!(models, get_models_per_model(&db).await);
+
+        // In registration order, including the model left without any versions, with versions in
+        // semver rather than text order.
+        let listed: Vec<(&str, Vec<Version>)> = models
+            .iter()
+            .map(|model| {
+                let versions = model.versions.iter().map(|v| v.version.clone()).collect();
+                (model.name.as_str(), versions)
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("zeta", vec![]),
+                (
+                    "alpha",
+                    vec![
+                        Version::new(0, 1, 0),
+                        Version::new(0, 2, 0),
+                        Version::new(0, 10, 0)
+                    ]
+                ),
+                ("beta", vec![Version::new(1, 0, 0)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_version_retention() {
+        let dir = TempDir::new("db_test").unwrap();
+        let mut db = migrated_db(&dir).await;
+        db.max_versions = Some(2);
+
+        // A version downloaded from Hugging Face, whose file the server owns.
+        let downloads = dir
+            .path()
+            .join("models--TheBloke--Llama-2-7B-GGUF/downloads");
+        std::fs::create_dir_all(&downloads).unwrap();
+        let file = |version: Version| downloads.join(format!("model-{}.gguf", version));
+        let downloaded = |version: Version, quantization: &str| {
+            let model_path = file(version.clone());
+            std::fs::write(&model_path, b"GGUF").unwrap();
+            RegisterModelRequest {
+                import_metadata: ImportMetadata {
+                    imported_at: datetime!(2023-09-01 12:34:56 UTC),
+                    source: ImportSource::HF {
+                        source: HFLocator {
+                            repo: "TheBloke/Llama-2-7B-GGUF".to_owned(),
+                            file: PathBuf::from("llama-2-7b.Q4_K_M.gguf"),
+                        },
+                    },
+                },
+                internal_params: ModelParams::COMPLETION(CompletionModelParams {
+                    model_path,
+                    default_sampling: None,
+                }),
+                quantization: Some(quantization.to_owned()),
+                ..register_request("my-model", version)
+            }
+        };
+        async fn versions(db: &DB) -> Vec<Version> {
+            let (_, versions) = db.get_version_quantizations("my-model").await.unwrap();
+            let mut versions: Vec<_> = versions.into_iter().map(|(version, _)| version).collect();
+            versions.sort();
+            versions
+        }
+
+        db.register_model(&downloaded(Version::new(0, 1, 0), "Q4_K_M"))
+            .await
+            .unwrap();
+        db.register_model(&downloaded(Version::new(0, 2, 0), "Q8_0"))
+            .await
+            .unwrap();
+        db.register_model(&downloaded(Version::new(0, 3, 0), "Q4_K_M"))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(&db).await,
+            [Version::new(0, 2, 0), Version::new(0, 3, 0)]
+        );
+        assert!(!file(Version::new(0, 1, 0)).exists());
+
+        // 0.2.0 is what requests run by default now, so 0.3.0 goes instead. Its file is deleted, but
+        // one imported from disk is left alone.
+        db.set_default_quantization("my-model", Some("Q8_0"))
+            .await
+            .unwrap();
+        db.register_model(&register_request("my-model", Version::new(0, 4, 0)))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(&db).await,
+            [Version::new(0, 2, 0), Version::new(0, 4, 0)]
+        );
+        assert!(file(Version::new(0, 2, 0)).exists());
+        assert!(!file(Version::new(0, 3, 0)).exists());
+
+        // A model's own limit beats the default.
+        db.set_max_versions("my-model", Some(1)).await.unwrap();
+        db.register_model(&register_request("my-model", Version::new(0, 5, 0)))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(&db).await,
+            [Version::new(0, 2, 0), Version::new(0, 5, 0)]
+        );
+
+        // A file picked up from the Hugging Face cache belongs to the cache, and stays.
+        let cached = dir
+            .path()
+            .join("models--TheBloke--Llama-2-7B-GGUF/snapshots/main/model.gguf");
+        std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        std::fs::write(&cached, b"GGUF").unwrap();
+        db.register_model(&RegisterModelRequest {
+            internal_params: ModelParams::COMPLETION(CompletionModelParams {
+                model_path: cached.clone(),
+                default_sampling: None,
+            }),
+            ..downloaded(Version::new(0, 5, 1), "Q4_K_M")
+        })
+        .await
+        .unwrap();
+        db.register_model(&register_request("my-model", Version::new(0, 5, 2)))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(&db).await,
+            [Version::new(0, 2, 0), Version::new(0, 5, 2)]
+        );
+        assert!(cached.exists());
+
+        db.set_max_versions("my-model", None).await.unwrap();
+        db.max_versions = None;
+        db.register_model(&register_request("my-model", Version::new(0, 6, 0)))
+            .await
+            .unwrap();
+        assert_eq!(versions(&db).await.len(), 3);
+
+        assert!(db.set_max_versions("other-model", Some(1)).await.is_err());
+
+        // In a read-only store, pruned versions leave their files in place.
+        db.read_only_store = true;
+        db.max_versions = Some(1);
+        db.register_model(&downloaded(Version::new(0, 7, 0), "Q8_0"))
+            .await
+            .unwrap();
+        assert_eq!(versions(&db).await, [Version::new(0, 7, 0)]);
+        assert!(file(Version::new(0, 2, 0)).exists());
+    }
+
+    #[tokio::test]
+    async fn test_max_memory() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        db.register_model(&register_request("my-model", Version::new(0, 1, 0)))
+            .await
+            .unwrap();
+        assert_eq!(db.get_max_memory("my-model").await.unwrap(), None);
+
+        db.set_max_memory("my-model", Some(8 << 30)).await.unwrap();
+        assert_eq!(db.get_max_memory("my-model").await.unwrap(), Some(8 << 30));
+
+        db.set_max_memory("my-model", None).await.unwrap();
+        assert_eq!(db.get_max_memory("my-model").await.unwrap(), None);
+
+        assert!(db.set_max_memory("other-model", Some(1)).await.is_err());
+        assert!(db.get_max_memory("other-model").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_imports() {
+        let dir = TempDir::new("db_test").unwrap();
+        let db = migrated_db(&dir).await;
+        assert!(db.get_interrupted_imports().await.unwrap().is_empty());
+
+        let interrupted = InterruptedImport {
+            job_id: uuid::Uuid::new_v4(),
+            task: ImportJob::HF {
+                locator: HFLocator {
+                    repo: "TheBloke/Llama-2-7B-GGUF".to_owned(),
+                    file: PathBuf::from("llama-2-7b.Q4_K_M.gguf"),
+                },
+            },
+            downloaded_bytes: 1 << 30,
+            interrupted_at: datetime!(2023-09-01 12:34:56 UTC),
+        };
+        db.replace_interrupted_imports(std::slice::from_ref(&interrupted))
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_interrupted_imports().await.unwrap(),
+            vec![interrupted]
+        );
+
+        // Each shutdown replaces what the last one recorded.
+        db.replace_interrupted_imports(&[]).await.unwrap();
+        assert!(db.get_interrupted_imports().await.unwrap().is_empty());
+    }
 }
@@ -7,6 +7,7 @@ pub struct Model {
     pub model_type: String,
     pub runtime: String,
     pub description: String,
+    pub default_quantization: Option<String>,
 }
 
 /// A specific version of a [RegisteredModel]
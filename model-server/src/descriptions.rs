@@ -0,0 +1,187 @@
+//! Write-behind buffer for model descriptions.
+//!
+//! Editing UIs may save a description on every keystroke. With a write delay configured, each update
+//! replaces the model's pending description, and only the latest one is committed once updates to that
+//! model have been quiet for the delay. Reads see pending descriptions, and
+//! [DescriptionWriter::flush_all] commits whatever is still pending at shutdown.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::error;
+use tokio::sync::Mutex;
+
+use crate::db::tables::DB;
+
+struct PendingDescription {
+    description: String,
+
+    /// Identifies the update, so a timer only flushes the update that started it.
+    seq: u64,
+}
+
+pub struct DescriptionWriter {
+    db: Arc<DB>,
+
+    /// Quiet period before a pending description is committed. `None` writes every update through.
+    write_delay: Option<Duration>,
+
+    /// Uncommitted descriptions, keyed by model name. Held while committing, so reads never miss an
+    /// update that's between the buffer and the DB.
+    pending: Mutex<HashMap<String, PendingDescription>>,
+    next_seq: AtomicU64,
+}
+
+impl DescriptionWriter {
+    pub fn new(db: Arc<DB>, write_delay: Option<Duration>) -> Self {
+        Self {
+            db,
+            write_delay,
+            pending: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Set a model's description, committing it immediately or after the write delay.
+    pub async fn update(
+        self: &Arc<Self>,
+        model_name: &str,
+        description: String,
+    ) -> anyhow::Result<()> {
+        let Some(write_delay) = self.write_delay else {
+            return self
+                .db
+                .update_model_description(model_name, &description)
+                .await;
+        };
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().await.insert(
+            model_name.to_owned(),
+            PendingDescription { description, seq },
+        );
+
+        let writer = Arc::clone(self);
+        let model_name = model_name.to_owned();
+        tokio::spawn(async move {
+            tokio::time::sleep(write_delay).await;
+            writer.flush_update(&model_name, seq).await;
+        });
+
+        Ok(())
+    }
+
+    /// The model's description, including any update that hasn't been committed yet.
+    pub async fn get(&self, model_name: &str) -> anyhow::Result<String> {
+        if let Some(pending) = self.pending.lock().await.get(model_name) {
+            return Ok(pending.description.clone());
+        }
+
+        self.db.get_model_description(model_name).await
+    }
+
+    /// Commit the model's pending description now, e.g. before the model is renamed.
+    pub async fn flush(&self, model_name: &str) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        if let Some(update) = pending.remove(model_name) {
+            self.db
+                .update_model_description(model_name, &update.description)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop the model's pending description, e.g. because the model was deleted.
+    pub async fn discard(&self, model_name: &str) {
+        self.pending.lock().await.remove(model_name);
+    }
+
+    /// Commit every pending description. Keeps going past failures, returning the last one.
+    pub async fn flush_all(&self) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        let mut result = Ok(());
+        for (model_name, update) in pending.drain() {
+            if let Err(err) = self
+                .db
+                .update_model_description(&model_name, &update.description)
+                .await
+            {
+                error!("failed to write description of {}: {:#}", model_name, err);
+                result = Err(err);
+            }
+        }
+
+        result
+    }
+
+    /// Commit the model's pending description if it's still the update identified by `seq`. Later
+    /// updates have their own timers.
+    async fn flush_update(&self, model_name: &str, seq: u64) {
+        let mut pending = self.pending.lock().await;
+        let update = match pending.get(model_name) {
+            Some(update) if update.seq == seq => pending.remove(model_name).unwrap(),
+            _ => return,
+        };
+
+        if let Err(err) = self
+            .db
+            .update_model_description(model_name, &update.description)
+            .await
+        {
+            error!("failed to write description of {}: {:#}", model_name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use semver::Version;
+
+    use super::DescriptionWriter;
+    use crate::db::tables::test::{migrated_db, register_request};
+
+    #[tokio::test]
+    async fn test_updates_coalesce() {
+        let dir = tempdir::TempDir::new("descriptions_test").unwrap();
+        let db = Arc::new(migrated_db(&dir).await);
+        db.register_model(&register_request("llama", Version::new(0, 1, 0)))
+            .await
+            .unwrap();
+        let writer = Arc::new(DescriptionWriter::new(
+            Arc::clone(&db),
+            Some(Duration::from_secs(60)),
+        ));
+
+        writer.update("llama", "draft".to_owned()).await.unwrap();
+        writer.update("llama", "final".to_owned()).await.unwrap();
+
+        // Reads see the latest update before it's committed.
+        assert_eq!(writer.get("llama").await.unwrap(), "final");
+        assert_eq!(db.get_model_description("llama").await.unwrap(), "");
+
+        writer.flush_all().await.unwrap();
+        assert_eq!(db.get_model_description("llama").await.unwrap(), "final");
+    }
+
+    #[tokio::test]
+    async fn test_write_through() {
+        let dir = tempdir::TempDir::new("descriptions_test").unwrap();
+        let db = Arc::new(migrated_db(&dir).await);
+        db.register_model(&register_request("llama", Version::new(0, 1, 0)))
+            .await
+            .unwrap();
+        let writer = Arc::new(DescriptionWriter::new(Arc::clone(&db), None));
+
+        writer.update("llama", "written".to_owned()).await.unwrap();
+        assert_eq!(db.get_model_description("llama").await.unwrap(), "written");
+    }
+}
@@ -0,0 +1,313 @@
+//! Resumable downloads of model files from Hugging Face.
+//!
+//! A download streams into a `.part` file next to its destination. When it's interrupted, retrying
+//! the import picks up after the bytes the failed attempt recorded as downloaded with an HTTP range
+//! request, rather than starting from zero. Once every byte is in, the file is checked against the size and
+//! SHA256 the Hub reports for it before it's moved into place, so a resume that stitched together
+//! mismatched bytes is thrown away instead of registered.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{api_types::ModelFile, checksum::checksum_file};
+
+/// Hugging Face Hub, for the file listings that carry each file's checksum.
+const HF_ENDPOINT: &str = "https://huggingface.co";
+
+/// Directory, under a repo's folder in the download directory, that the server's own downloads are
+/// written to. Anything outside one came from the Hugging Face cache, and isn't the server's to delete.
+pub const DOWNLOADS_DIR: &str = "downloads";
+
+/// Whether `path` is one of the server's own downloads, in a [DOWNLOADS_DIR].
+pub fn is_download(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == DOWNLOADS_DIR)
+}
+
+/// Number of progress reports made over the course of a download.
+const PROGRESS_STEPS: u64 = 100;
+
+/// What the Hub says about a file, to check a finished download against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteFile {
+    pub size: u64,
+
+    /// Hex-encoded SHA256 of the file. Only known for files stored with Git LFS, which covers every
+    /// model file of any size.
+    pub sha256: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TreeEntry {
+    path: String,
+    size: u64,
+    lfs: Option<LfsInfo>,
+}
+
+#[derive(Deserialize)]
+struct LfsInfo {
+    oid: String,
+}
+
+/// Look up `file` in the `main` revision of the model repo `repo`.
+pub async fn remote_file(
+    client: &reqwest::Client,
+    repo: &str,
+    file: &Path,
+) -> anyhow::Result<RemoteFile> {
+    let path = file.to_str().context("file name is not valid UTF-8")?;
+    let url = match file.parent().and_then(Path::to_str) {
+        Some(dir) if !dir.is_empty() => format!("{HF_ENDPOINT}/api/models/{repo}/tree/main/{dir}"),
+        _ => format!("{HF_ENDPOINT}/api/models/{repo}/tree/main"),
+    };
+
+    let body = client
+        .get(&url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("failed to list files of {}", repo))?
+        .bytes()
+        .await?;
+    let entries: Vec<TreeEntry> =
+        serde_json::from_slice(&body).context("failed to parse file listing")?;
+    let entry = entries
+        .into_iter()
+        .find(|entry| entry.path == path)
+        .with_context(|| format!("{} has no file {}", repo, path))?;
+
+    Ok(RemoteFile {
+        size: entry.size,
+        sha256: entry.lfs.map(|lfs| lfs.oid),
+    })
+}
+
+/// Where the bytes of an unfinished download to `dest` are kept.
+pub fn part_path(dest: &Path) -> PathBuf {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Where a download resumes from: the bytes of the `.part` file, `part_len` long, that an earlier
+/// attempt recorded as downloaded. Anything past them is written again, and a file larger than the
+/// one being downloaded is started over.
+fn resume_offset(part_len: u64, downloaded_bytes: u64, size: u64) -> u64 {
+    let offset = part_len.min(downloaded_bytes);
+    if offset > size {
+        0
+    } else {
+        offset
+    }
+}
+
+/// Whether the response to a request for the bytes from `offset` onwards continues the partial file,
+/// or the server ignored the range and sent the whole file, which replaces it.
+fn resumes_at(status: u16, content_range: Option<&str>, offset: u64) -> anyhow::Result<bool> {
+    match status {
+        200 => Ok(false),
+        206 => {
+            // e.g. `bytes 1024-4095/4096`
+            let start = content_range
+                .and_then(|range| range.strip_prefix("bytes "))
+                .and_then(|range| range.split('-').next())
+                .and_then(|start| start.parse::<u64>().ok())
+                .with_context(|| format!("invalid Content-Range {:?}", content_range))?;
+            anyhow::ensure!(
+                start == offset,
+                "asked for bytes from {} but got them from {}",
+                offset,
+                start
+            );
+
+            Ok(true)
+        }
+        status => anyhow::bail!("unexpected status {}", status),
+    }
+}
+
+/// Check a finished download against what the Hub reports for the file.
+pub fn verify(file: &ModelFile, remote: &RemoteFile) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        file.size_bytes == remote.size,
+        "downloaded {} bytes but expected {}",
+        file.size_bytes,
+        remote.size
+    );
+    if let Some(sha256) = &remote.sha256 {
+        anyhow::ensure!(
+            file.sha256.eq_ignore_ascii_case(sha256),
+            "downloaded file has SHA256 {} but expected {}",
+            file.sha256,
+            sha256
+        );
+    }
+
+    Ok(())
+}
+
+/// Download `url` to `dest`, resuming after the first `downloaded_bytes` of its `.part` file, the
+/// progress an earlier attempt made, see [resume_offset]. `on_progress` is called with the number of
+/// bytes downloaded so far, including resumed ones, every so often as the download proceeds.
+pub async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    dest: &Path,
+    remote: &RemoteFile,
+    downloaded_bytes: u64,
+    mut on_progress: impl FnMut(u64),
+) -> anyhow::Result<()> {
+    let part = part_path(dest);
+    if let Some(parent) = part.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("failed to create {:?}", parent))?;
+    }
+
+    let part_len = match tokio::fs::metadata(&part).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    let mut offset = resume_offset(part_len, downloaded_bytes, remote.size);
+    if part_len > remote.size {
+        warn!(
+            "{:?} is larger than the file it's downloading, starting over",
+            part
+        );
+    }
+
+    if offset < remote.size {
+        let mut response = client
+            .get(url)
+            .header("Range", format!("bytes={}-", offset))
+            .send()
+            .await
+            .with_context(|| format!("failed to request {}", url))?;
+        let content_range = response
+            .headers()
+            .get("Content-Range")
+            .and_then(|value| value.to_str().ok());
+        let resume = resumes_at(response.status().as_u16(), content_range, offset)?;
+        if resume && offset > 0 {
+            info!("resuming download of {} from byte {}", url, offset);
+        } else {
+            offset = 0;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(&part)
+            .await
+            .with_context(|| format!("failed to open {:?}", part))?;
+        // Drop whatever follows the recorded progress, appends then carry on from there.
+        if resume {
+            file.set_len(offset)
+                .await
+                .with_context(|| format!("failed to truncate {:?}", part))?;
+        }
+
+        let step = (remote.size / PROGRESS_STEPS).max(1);
+        let mut reported = offset / step;
+        on_progress(offset);
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("download of {} interrupted", url))?
+        {
+            file.write_all(&chunk)
+                .await
+                .with_context(|| format!("failed to write {:?}", part))?;
+            offset += chunk.len() as u64;
+            if offset / step > reported {
+                reported = offset / step;
+                on_progress(offset);
+            }
+        }
+        file.flush().await?;
+    }
+
+    let checksum = {
+        let part = part.clone();
+        tokio::task::spawn_blocking(move || checksum_file(&part)).await??
+    };
+    if let Err(err) = verify(&checksum, remote) {
+        // Resuming from corrupt bytes would only fail again, so the next attempt starts over.
+        tokio::fs::remove_file(&part).await.ok();
+        return Err(err.context(format!("download of {} is corrupt", url)));
+    }
+
+    tokio::fs::rename(&part, dest)
+        .await
+        .with_context(|| format!("failed to move {:?} into place", part))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::api_types::ModelFile;
+
+    use super::{part_path, resume_offset, resumes_at, verify, RemoteFile};
+
+    #[test]
+    fn test_resume() {
+        assert_eq!(
+            part_path(Path::new("/models/model.gguf")),
+            PathBuf::from("/models/model.gguf.part")
+        );
+
+        // Only bytes an earlier attempt recorded are resumed from, and never past the file's size.
+        assert_eq!(resume_offset(2048, 1024, 4096), 1024);
+        assert_eq!(resume_offset(1024, 2048, 4096), 1024);
+        assert_eq!(resume_offset(1024, 0, 4096), 0);
+        assert_eq!(resume_offset(8192, 8192, 4096), 0);
+
+        // A partial response continues the file, a full one replaces it.
+        assert!(resumes_at(206, Some("bytes 1024-4095/4096"), 1024).unwrap());
+        assert!(!resumes_at(200, None, 1024).unwrap());
+        assert!(resumes_at(206, Some("bytes 0-4095/4096"), 1024).is_err());
+        assert!(resumes_at(206, None, 1024).is_err());
+        assert!(resumes_at(404, None, 1024).is_err());
+
+        let file = ModelFile {
+            size_bytes: 11,
+            sha256: "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_owned(),
+        };
+        let remote = RemoteFile {
+            size: 11,
+            sha256: Some(file.sha256.to_uppercase()),
+        };
+        assert!(verify(&file, &remote).is_ok());
+        assert!(verify(
+            &file,
+            &RemoteFile {
+                sha256: None,
+                ..remote.clone()
+            }
+        )
+        .is_ok());
+        assert!(verify(
+            &file,
+            &RemoteFile {
+                size: 12,
+                ..remote.clone()
+            }
+        )
+        .is_err());
+        assert!(verify(
+            &file,
+            &RemoteFile {
+                sha256: Some("00".repeat(32)),
+                ..remote
+            }
+        )
+        .is_err());
+    }
+}
@@ -1,30 +1,100 @@
 use crate::{
     api_types::{
         CompletionModelParams, DiskLocator, HFLocator, ImportJob, ImportJobId, ImportJobStatus,
-        ImportMetadata, ImportSource, ModelParams, ModelType, RegisterModelRequest, Runtime,
+        ImportMetadata, ImportSource, InterruptedImport, Locator, ModelParams, ModelType,
+        RegisterModelRequest, Runtime,
     },
+    checksum::checksum_file,
     db::tables::DB,
+    download,
+    quantization::read_quantization,
 };
 use anyhow::{Context, Ok};
 use axum::async_trait;
-use hf_hub::{api::tokio::Api, Repo};
-use log::info;
+use hf_hub::{api::tokio::Api, Cache, Repo};
+use log::{error, info, warn};
 use semver::Version;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use time::OffsetDateTime;
-use tokio::sync::{
-    mpsc::{channel, Sender},
-    RwLock,
+use tokio::{
+    sync::{
+        mpsc::{channel, Sender},
+        RwLock,
+    },
+    task::JoinHandle,
 };
+use tracing::Instrument;
+
+/// Time to wait between attempts to register an imported model with the DB.
+const REGISTER_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 /// Importer is the trait for types that can conduct external imports.
 /// They receive an [ImportTask] which describes the source of the import along with
 /// any associated metadata necessary to execute the import.
 #[async_trait]
 pub trait Importer {
+    /// Start a job importing from the source `task` describes. Fails with [ImportError::DuplicateJob]
+    /// if another job importing from it is still queued or in progress.
     async fn start_import(&self, task: ImportJob) -> anyhow::Result<ImportJobId>;
     async fn get_import_status(&self, task_id: &ImportJobId) -> anyhow::Result<ImportJobStatus>;
     async fn get_all_job_status(&self) -> anyhow::Result<HashMap<ImportJobId, ImportJobStatus>>;
+
+    /// Find every job, in any state, that imports from the given source.
+    async fn find_jobs_by_source(
+        &self,
+        source: &Locator,
+    ) -> anyhow::Result<Vec<(ImportJobId, ImportJobStatus)>>;
+
+    /// Cancel a job that is still queued or in progress, leaving it [ImportJobStatus::Cancelled].
+    /// Fails with [ImportError::InvalidJobState] if the job has already finished.
+    async fn cancel(&self, task_id: &ImportJobId) -> anyhow::Result<()>;
+
+    /// Cancel every job that is still queued or in progress, returning the IDs of the cancelled jobs.
+    async fn cancel_all(&self) -> anyhow::Result<Vec<ImportJobId>>;
+
+    /// Start a new job that re-runs the import of a failed, cancelled or interrupted job, returning the
+    /// ID of the new job. Downloads resume from the bytes the job downloaded. Fails with
+    /// [ImportError::InvalidJobState] if the job is still queued, in progress or completed.
+    async fn retry_import(&self, task_id: &ImportJobId) -> anyhow::Result<ImportJobId>;
+
+    /// Abort every job that is still queued or in progress as the server shuts down, leaving them
+    /// [ImportJobStatus::Interrupted]. Returns every interrupted job that hasn't been retried since,
+    /// including ones restored with [Importer::restore_interrupted], to record for the next boot.
+    async fn interrupt_unfinished(&self) -> anyhow::Result<Vec<InterruptedImport>>;
+
+    /// List jobs interrupted by an earlier shutdown under their old IDs, so they can be retried.
+    async fn restore_interrupted(&self, imports: Vec<InterruptedImport>) -> anyhow::Result<()>;
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// No job exists with the requested ID.
+    JobNotFound,
+
+    /// The job is not in a state that allows the requested operation.
+    InvalidJobState,
+
+    /// Another job importing from the same source is still queued or in progress. The two would
+    /// download into the same `.part` file.
+    DuplicateJob,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ImportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
 }
 
 /// The default in-memory importer implementation. Uses a multi-producer single-consumer
@@ -35,10 +105,15 @@ pub struct InMemoryImporter {
 
     /// mpsc message channel for communication between the workers and the state-tracker.
     sender: Sender<Message>,
+
+    /// Where Hugging Face downloads are written, see [InMemoryImporter::with_download_dir].
+    download_dir: Option<PathBuf>,
 }
 
 impl InMemoryImporter {
-    pub fn new(db: Arc<DB>) -> Self {
+    /// Create a new importer which registers completed imports in `db`. Registration is attempted up
+    /// to `register_attempts` times before the job is marked as failed.
+    pub fn new(db: Arc<DB>, register_attempts: u32) -> Self {
         // TODO(aduffy): should this be bounded? Or what should the bound be if not?
         let (sender, mut receiver) = channel::<Message>(128);
         let job_status = Arc::new(RwLock::new(HashMap::<ImportJobId, JobEntry>::new()));
@@ -54,97 +129,204 @@ impl InMemoryImporter {
 
                         let job_def = {
                             // Hold the lock for a very small amount of time
-                            let mut table = table_clone.write().await;
-                            let entry = table.get_mut(&job).unwrap();
-                            entry.status = status.clone();
+                            let table = table_clone.read().await;
+                            let entry = table.get(&job).unwrap();
 
-                            entry.task.clone()
-                        };
+                            // Updates can still be in flight after a job was cancelled, drop them
+                            // so a cancelled import never gets registered.
+                            if entry.status.is_finished() {
+                                info!("ignoring update for finished task={}", job);
+                                continue;
+                            }
 
-                        let file_name = match job_def {
-                            ImportJob::DISK { ref locator } => locator
-                                .path
-                                .file_name()
-                                .unwrap()
-                                .to_owned()
-                                .into_string()
-                                .unwrap(),
-                            ImportJob::HF { ref locator } => locator
-                                .file
-                                .file_name()
-                                .unwrap()
-                                .to_owned()
-                                .into_string()
-                                .unwrap(),
+                            entry.task.clone()
                         };
 
-                        // If update is completed, we need to insert the new model into the DB
-                        match status {
+                        // A download isn't complete until the model is registered. If that fails the job
+                        // fails with it, rather than taking down the importer for every other job.
+                        let status = match status {
                             ImportJobStatus::Completed { info } => {
-                                let version = Version::new(0, 1, 0);
-                                info!(
-                                    "registering model with db name={} version={}",
-                                    &file_name, &version
-                                );
-
-                                db.register_model(&RegisterModelRequest {
-                                    version,
-                                    import_metadata: ImportMetadata {
-                                        imported_at: OffsetDateTime::now_utc(),
-                                        source: match job_def {
-                                            ImportJob::HF { ref locator } => ImportSource::HF {
-                                                source: locator.clone(),
-                                            },
-                                            ImportJob::DISK { ref locator } => ImportSource::DISK {
-                                                source: locator.clone(),
-                                            },
-                                        },
-                                    },
-                                    model: file_name,
-                                    model_type: ModelType::Completion,
-                                    runtime: Runtime::Ggml,
-                                    internal_params: ModelParams::COMPLETION(
-                                        CompletionModelParams {
-                                            model_path: PathBuf::from(info.unwrap()),
-                                        },
-                                    ),
-                                })
-                                .await
-                                .unwrap();
+                                if let Err(err) =
+                                    register_import(&db, &job_def, info.clone(), register_attempts)
+                                        .await
+                                {
+                                    error!("failed to register task={}: {:#}", job, err);
+                                    ImportJobStatus::Failed {
+                                        error: Some(format!("{:#}", err)),
+                                    }
+                                } else {
+                                    ImportJobStatus::Completed { info }
+                                }
                             }
-                            _ => (),
+                            status => status,
+                        };
+
+                        let mut table = table_clone.write().await;
+                        let entry = table.get_mut(&job).unwrap();
+                        if !entry.status.is_finished() {
+                            entry.status = status;
+                        }
+                    }
+                    Message::Downloaded { job, bytes, total } => {
+                        let mut table = table_clone.write().await;
+                        let entry = table.get_mut(&job).unwrap();
+                        if !entry.status.is_finished() {
+                            entry.downloaded_bytes = bytes;
+                            entry.status = ImportJobStatus::InProgress {
+                                progress: bytes as f32 / total as f32,
+                            };
                         }
                     }
                 }
             }
         });
 
-        Self { job_status, sender }
+        Self {
+            job_status,
+            sender,
+            download_dir: None,
+        }
     }
-}
 
-#[async_trait]
-impl Importer for InMemoryImporter {
-    async fn start_import(&self, task: ImportJob) -> anyhow::Result<ImportJobId> {
+    /// Download from Hugging Face into `dir` rather than the HF cache, e.g. when the cache is a shared
+    /// store the server mustn't write to. Files already in the HF cache are still used from there.
+    pub fn with_download_dir(mut self, dir: PathBuf) -> Self {
+        self.download_dir = Some(dir);
+        self
+    }
+
+    /// Start a job importing from `task`, resuming a download after its first `downloaded_bytes`.
+    async fn start_job(
+        &self,
+        task: ImportJob,
+        downloaded_bytes: u64,
+    ) -> anyhow::Result<ImportJobId> {
         let task_id = uuid::Uuid::new_v4();
 
         {
+            // Spawn while holding the lock, so the job's first status update can't arrive before its entry.
             let mut jq = self.job_status.write().await;
+            if jq
+                .values()
+                .any(|entry| entry.task == task && !entry.status.is_finished())
+            {
+                return Err(ImportError::DuplicateJob.into());
+            }
+
+            // Submit an async task to execute against the data, updating the jobs table as relevant.
+            let sender = self.sender.clone();
+            // Carry the request's span into the import, so its logs share the request ID.
+            let handle = tokio::spawn(
+                do_import(
+                    task_id,
+                    task.clone(),
+                    self.download_dir.clone(),
+                    downloaded_bytes,
+                    sender,
+                )
+                .instrument(tracing::Span::current()),
+            );
+
             jq.insert(
                 task_id,
                 JobEntry {
-                    task: task.clone(),
+                    task,
                     status: ImportJobStatus::Queued,
+                    downloaded_bytes,
+                    interrupted_at: None,
+                    handle: Some(handle),
                 },
             );
         }
 
-        // Submit an async task to execute against the data, updating the jobs table as relevant.
-        let sender = self.sender.clone();
-        tokio::spawn(do_import(task_id, task.clone(), sender));
-
         Ok(task_id)
     }
+}
+
+/// Register the model downloaded by a completed import job with the DB, retrying up to `attempts` times.
+async fn register_import(
+    db: &DB,
+    job_def: &ImportJob,
+    model_path: Option<String>,
+    attempts: u32,
+) -> anyhow::Result<()> {
+    let file_name = match job_def {
+        ImportJob::DISK { ref locator } => locator.path.file_name(),
+        ImportJob::HF { ref locator } => locator.file.file_name(),
+    }
+    .and_then(|file_name| file_name.to_str())
+    .context("import source has no valid file name")?
+    .to_owned();
+    let model_path = PathBuf::from(model_path.context("import completed without a model path")?);
+
+    let file = {
+        let model_path = model_path.clone();
+        tokio::task::spawn_blocking(move || checksum_file(&model_path)).await?
+    };
+    // Not fatal, the size and checksum are recorded the first time the model is loaded instead.
+    let file = file
+        .map_err(|err| warn!("failed to checksum imported model: {:#}", err))
+        .ok();
+
+    let quantization = {
+        let model_path = model_path.clone();
+        tokio::task::spawn_blocking(move || read_quantization(&model_path)).await?
+    };
+    let quantization = quantization
+        .map_err(|err| warn!("failed to read quantization of imported model: {:#}", err))
+        .ok()
+        .flatten();
+
+    let version = Version::new(0, 1, 0);
+    let request = RegisterModelRequest {
+        version,
+        import_metadata: ImportMetadata {
+            imported_at: OffsetDateTime::now_utc(),
+            source: match job_def {
+                ImportJob::HF { ref locator } => ImportSource::HF {
+                    source: locator.clone(),
+                },
+                ImportJob::DISK { ref locator } => ImportSource::DISK {
+                    source: locator.clone(),
+                },
+            },
+        },
+        model: file_name,
+        model_type: ModelType::Completion,
+        runtime: Runtime::Ggml,
+        internal_params: ModelParams::COMPLETION(CompletionModelParams {
+            model_path,
+            default_sampling: None,
+        }),
+        file,
+        quantization,
+    };
+
+    let mut attempt = 1;
+    loop {
+        info!(
+            "registering model with db name={} version={} attempt={}",
+            &request.model, &request.version, attempt
+        );
+        if let Err(err) = db.register_model(&request).await {
+            if attempt >= attempts {
+                return Err(err.context("failed to register model"));
+            }
+
+            warn!("failed to register model, retrying: {:#}", err);
+            tokio::time::sleep(REGISTER_RETRY_DELAY).await;
+            attempt += 1;
+        } else {
+            return Ok(());
+        }
+    }
+}
+
+#[async_trait]
+impl Importer for InMemoryImporter {
+    async fn start_import(&self, task: ImportJob) -> anyhow::Result<ImportJobId> {
+        self.start_job(task, 0).await
+    }
 
     async fn get_import_status(&self, task_id: &ImportJobId) -> anyhow::Result<ImportJobStatus> {
         // Print out the status of the first job
@@ -165,12 +347,165 @@ impl Importer for InMemoryImporter {
 
         Ok(hm)
     }
+
+    async fn find_jobs_by_source(
+        &self,
+        source: &Locator,
+    ) -> anyhow::Result<Vec<(ImportJobId, ImportJobStatus)>> {
+        let task = ImportJob::from(source.clone());
+        let jobs = self
+            .job_status
+            .read()
+            .await
+            .iter()
+            .filter(|(_, entry)| entry.task == task)
+            .map(|(job_id, entry)| (*job_id, entry.status.clone()))
+            .collect();
+
+        Ok(jobs)
+    }
+
+    async fn cancel(&self, task_id: &ImportJobId) -> anyhow::Result<()> {
+        let mut jq = self.job_status.write().await;
+        let entry = jq.get_mut(task_id).ok_or(ImportError::JobNotFound)?;
+        if entry.status.is_finished() {
+            return Err(ImportError::InvalidJobState.into());
+        }
+
+        entry.cancel();
+        info!("cancelled task={}", task_id);
+        Ok(())
+    }
+
+    async fn cancel_all(&self) -> anyhow::Result<Vec<ImportJobId>> {
+        let mut jq = self.job_status.write().await;
+        let mut cancelled = Vec::new();
+        for (job_id, entry) in jq.iter_mut() {
+            if entry.status.is_finished() {
+                continue;
+            }
+
+            entry.cancel();
+            cancelled.push(*job_id);
+        }
+
+        info!("cancelled {} import jobs", cancelled.len());
+        Ok(cancelled)
+    }
+
+    async fn retry_import(&self, task_id: &ImportJobId) -> anyhow::Result<ImportJobId> {
+        let (task, downloaded_bytes) = {
+            let jq = self.job_status.read().await;
+            let entry = jq.get(task_id).ok_or(ImportError::JobNotFound)?;
+            if !matches!(
+                entry.status,
+                ImportJobStatus::Failed { .. }
+                    | ImportJobStatus::Cancelled
+                    | ImportJobStatus::Interrupted
+            ) {
+                return Err(ImportError::InvalidJobState.into());
+            }
+
+            (entry.task.clone(), entry.downloaded_bytes)
+        };
+
+        // Downloads resume from what the failed job left on disk, so retries don't start from zero.
+        let retry_id = self.start_job(task, downloaded_bytes).await?;
+
+        // Once retried, the job is no longer left for the next boot to retry. Only once the retry has
+        // started, so a retry that fails to start doesn't lose it.
+        if let Some(entry) = self.job_status.write().await.get_mut(task_id) {
+            entry.interrupted_at = None;
+        }
+        info!(
+            "retrying failed task={} as task={} downloaded_bytes={}",
+            task_id, retry_id, downloaded_bytes
+        );
+
+        Ok(retry_id)
+    }
+
+    async fn interrupt_unfinished(&self) -> anyhow::Result<Vec<InterruptedImport>> {
+        let mut jq = self.job_status.write().await;
+        let interrupted_at = OffsetDateTime::now_utc();
+        for (job_id, entry) in jq.iter_mut() {
+            if entry.status.is_finished() {
+                continue;
+            }
+
+            entry.abort(ImportJobStatus::Interrupted);
+            entry.interrupted_at = Some(interrupted_at);
+            warn!(
+                "interrupted task={} source={:?} downloaded_bytes={}",
+                job_id, entry.task, entry.downloaded_bytes
+            );
+        }
+
+        let interrupted = jq
+            .iter()
+            .filter_map(|(job_id, entry)| {
+                entry
+                    .interrupted_at
+                    .map(|interrupted_at| InterruptedImport {
+                        job_id: *job_id,
+                        task: entry.task.clone(),
+                        downloaded_bytes: entry.downloaded_bytes,
+                        interrupted_at,
+                    })
+            })
+            .collect();
+
+        Ok(interrupted)
+    }
+
+    async fn restore_interrupted(&self, imports: Vec<InterruptedImport>) -> anyhow::Result<()> {
+        let mut jq = self.job_status.write().await;
+        for import in imports {
+            jq.insert(
+                import.job_id,
+                JobEntry {
+                    task: import.task,
+                    status: ImportJobStatus::Interrupted,
+                    downloaded_bytes: import.downloaded_bytes,
+                    interrupted_at: Some(import.interrupted_at),
+                    handle: None,
+                },
+            );
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 struct JobEntry {
     task: ImportJob,
     status: ImportJobStatus,
+
+    /// Bytes of the model file downloaded so far, including ones resumed from an earlier job. Survives
+    /// the job failing, as retrying it resumes from them.
+    downloaded_bytes: u64,
+
+    /// When the server shutting down interrupted the job, until it's retried.
+    interrupted_at: Option<OffsetDateTime>,
+
+    /// Handle to the task executing the import, used to abort it on cancellation.
+    handle: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl JobEntry {
+    /// Abort the import and mark it cancelled. Updates it sent before it was aborted are dropped, as
+    /// the job is now finished.
+    fn cancel(&mut self) {
+        self.abort(ImportJobStatus::Cancelled);
+    }
+
+    fn abort(&mut self, status: ImportJobStatus) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+        self.status = status;
+    }
 }
 
 /// Message used by our async task queue which interposes between the main task and the worker tasks doing
@@ -180,11 +515,20 @@ enum Message {
         job: ImportJobId,
         status: ImportJobStatus,
     },
+
+    /// Progress of a download, in bytes of the `total` size of the file.
+    Downloaded {
+        job: ImportJobId,
+        bytes: u64,
+        total: u64,
+    },
 }
 
 async fn do_import(
     task_id: ImportJobId,
     task: ImportJob,
+    download_dir: Option<PathBuf>,
+    downloaded_bytes: u64,
     sender: Sender<Message>,
 ) -> anyhow::Result<()> {
     info!("Job status updating: {:?}", &task);
@@ -198,33 +542,93 @@ async fn do_import(
         .context("failed to send in-progress update")?;
 
     let download_path = match &task {
-        ImportJob::DISK { locator } => import_disk(locator).await,
-        ImportJob::HF { locator } => import_hf(locator).await,
+        ImportJob::DISK { locator } => Ok(import_disk(locator).await),
+        ImportJob::HF { locator } => import_hf(
+            locator,
+            download_dir.as_deref(),
+            downloaded_bytes,
+            |bytes, total| {
+                // Progress is best-effort, it's fine to drop some while the channel is backed up.
+                let _ = sender.try_send(Message::Downloaded {
+                    job: task_id,
+                    bytes,
+                    total,
+                });
+            },
+        )
+        .await
+        .context("download failed"),
     };
 
+    let status = download_path.map_or_else(
+        |err| {
+            error!("import failed task={}: {:#}", task_id, err);
+            ImportJobStatus::Failed {
+                error: Some(format!("{:#}", err)),
+            }
+        },
+        |download_path| ImportJobStatus::Completed {
+            info: download_path.to_str().map(|p| p.to_string()),
+        },
+    );
+
     sender
         .send(Message::UpdateStatus {
             job: task_id.clone(),
-            status: ImportJobStatus::Completed {
-                info: download_path.to_str().map(|p| p.to_string()),
-            },
+            status,
         })
         .await
         .context("failed to send completion update")
 }
 
-async fn import_hf(locator: &HFLocator) -> PathBuf {
-    let client = Api::new().unwrap();
-    info!("Executing download from HF");
-    // Send a stream of results back
-    let download = client
-        .repo(Repo::model(locator.repo.clone()))
-        .get(locator.file.to_str().unwrap())
-        .await
-        .unwrap();
+/// Download a model file from HF into `download_dir`, or else the HF cache, resuming after the
+/// `downloaded_bytes` an earlier interrupted download of it made, see [download]. Files already in the
+/// HF cache aren't downloaded again.
+async fn import_hf(
+    locator: &HFLocator,
+    download_dir: Option<&Path>,
+    downloaded_bytes: u64,
+    on_progress: impl Fn(u64, u64),
+) -> anyhow::Result<PathBuf> {
+    let file = locator
+        .file
+        .to_str()
+        .context("file name is not valid UTF-8")?;
+    let repo = Repo::model(locator.repo.clone());
+    let cache = Cache::default();
+    if let Some(cached) = cache.repo(repo.clone()).get(file) {
+        info!("Using cached download target={:?}", &cached);
+        return Ok(cached);
+    }
+
+    let dest = download_dir
+        .unwrap_or(cache.path())
+        .join(repo.folder_name())
+        .join(download::DOWNLOADS_DIR)
+        .join(&locator.file);
+    if dest.exists() {
+        info!("Using earlier download target={:?}", &dest);
+        return Ok(dest);
+    }
+
+    let api = Api::new()?;
+    let remote = download::remote_file(api.client(), &locator.repo, &locator.file).await?;
+    info!(
+        "Executing download from HF size={} target={:?}",
+        remote.size, &dest
+    );
+    download::download(
+        api.client(),
+        &api.repo(repo).url(file),
+        &dest,
+        &remote,
+        downloaded_bytes,
+        |bytes| on_progress(bytes, remote.size),
+    )
+    .await?;
 
-    info!("Download completed target={:?}", &download);
-    download
+    info!("Download completed target={:?}", &dest);
+    Ok(dest)
 }
 
 async fn import_disk(locator: &DiskLocator) -> PathBuf {
@@ -232,3 +636,247 @@ async fn import_disk(locator: &DiskLocator) -> PathBuf {
 
     locator.path.clone()
 }
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use tempdir::TempDir;
+
+    use super::{ImportError, Importer, InMemoryImporter};
+    use crate::{
+        api_types::{DiskLocator, ImportJob, ImportJobId, ImportJobStatus},
+        db::tables::DB,
+    };
+
+    async fn wait_until_finished(
+        importer: &InMemoryImporter,
+        job: &ImportJobId,
+    ) -> ImportJobStatus {
+        for _ in 0..500 {
+            let status = importer.get_import_status(job).await.unwrap();
+            if status.is_finished() {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("import job {} did not finish", job);
+    }
+
+    fn disk_import(path: &str) -> ImportJob {
+        ImportJob::DISK {
+            locator: DiskLocator {
+                path: PathBuf::from(path),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_failure_fails_job() {
+        let dir = TempDir::new("import_test").unwrap();
+
+        // Without any migrations applied there are no tables, so every registration fails.
+        let db = Arc::new(DB::open(dir.path().join("test.db")).unwrap());
+        let importer = InMemoryImporter::new(db, 1);
+
+        let job = importer
+            .start_import(disk_import("/models/first.gguf"))
+            .await
+            .unwrap();
+        match wait_until_finished(&importer, &job).await {
+            ImportJobStatus::Failed { error } => {
+                assert!(error.unwrap().contains("failed to register model"))
+            }
+            status => panic!("expected failed import, got {:?}", status),
+        }
+
+        // The importer keeps processing new jobs after a failure.
+        let job = importer
+            .start_import(disk_import("/models/second.gguf"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            wait_until_finished(&importer, &job).await,
+            ImportJobStatus::Failed { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_import() {
+        let dir = TempDir::new("import_test").unwrap();
+        let db = Arc::new(DB::open(dir.path().join("test.db")).unwrap());
+        let importer = InMemoryImporter::new(db, 1);
+
+        let job = importer
+            .start_import(disk_import("/models/model.gguf"))
+            .await
+            .unwrap();
+        wait_until_finished(&importer, &job).await;
+
+        let retry = importer.retry_import(&job).await.unwrap();
+        assert_ne!(retry, job);
+        assert!(matches!(
+            wait_until_finished(&importer, &retry).await,
+            ImportJobStatus::Failed { .. }
+        ));
+
+        let err = importer
+            .retry_import(&uuid::Uuid::new_v4())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImportError>(),
+            Some(ImportError::JobNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_import() {
+        let dir = TempDir::new("import_test").unwrap();
+        let db = Arc::new(DB::open(dir.path().join("test.db")).unwrap());
+        let importer = InMemoryImporter::new(db, 1);
+
+        // Nothing has run yet, so the first job is still queued when the second is started.
+        let job = importer
+            .start_import(disk_import("/models/model.gguf"))
+            .await
+            .unwrap();
+        let err = importer
+            .start_import(disk_import("/models/model.gguf"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImportError>(),
+            Some(ImportError::DuplicateJob)
+        ));
+
+        // Other sources aren't held up, and once the job has finished its source can be imported again.
+        assert!(importer
+            .start_import(disk_import("/models/other.gguf"))
+            .await
+            .is_ok());
+        importer.cancel(&job).await.unwrap();
+        assert!(importer
+            .start_import(disk_import("/models/model.gguf"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_import() {
+        let dir = TempDir::new("import_test").unwrap();
+        let db = Arc::new(DB::open(dir.path().join("test.db")).unwrap());
+        let importer = InMemoryImporter::new(db, 1);
+
+        // Nothing has run yet, so the job is still queued.
+        let job = importer
+            .start_import(disk_import("/models/model.gguf"))
+            .await
+            .unwrap();
+        importer.cancel(&job).await.unwrap();
+        assert_eq!(
+            importer.get_import_status(&job).await.unwrap(),
+            ImportJobStatus::Cancelled
+        );
+
+        // Cancelled jobs are finished, so they can't be cancelled again, but can be retried.
+        let err = importer.cancel(&job).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImportError>(),
+            Some(ImportError::InvalidJobState)
+        ));
+        assert!(importer.retry_import(&job).await.is_ok());
+
+        let err = importer.cancel(&uuid::Uuid::new_v4()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImportError>(),
+            Some(ImportError::JobNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_unfinished() {
+        let dir = TempDir::new("import_test").unwrap();
+        let db = Arc::new(DB::open(dir.path().join("test.db")).unwrap());
+        let importer = InMemoryImporter::new(Arc::clone(&db), 1);
+
+        let finished = importer
+            .start_import(disk_import("/models/finished.gguf"))
+            .await
+            .unwrap();
+        wait_until_finished(&importer, &finished).await;
+        let queued = importer
+            .start_import(disk_import("/models/queued.gguf"))
+            .await
+            .unwrap();
+
+        // Only jobs that hadn't finished are interrupted.
+        let interrupted = importer.interrupt_unfinished().await.unwrap();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].job_id, queued);
+        assert_eq!(interrupted[0].task, disk_import("/models/queued.gguf"));
+        assert_eq!(
+            importer.get_import_status(&queued).await.unwrap(),
+            ImportJobStatus::Interrupted
+        );
+
+        // After a restart, they're listed under their old IDs and can be retried.
+        let restarted = InMemoryImporter::new(db, 1);
+        restarted.restore_interrupted(interrupted).await.unwrap();
+        assert_eq!(
+            restarted.get_import_status(&queued).await.unwrap(),
+            ImportJobStatus::Interrupted
+        );
+        assert_eq!(restarted.interrupt_unfinished().await.unwrap().len(), 1);
+
+        // A retry that can't start leaves them for the next boot.
+        let duplicate = restarted
+            .start_import(disk_import("/models/queued.gguf"))
+            .await
+            .unwrap();
+        let err = restarted.retry_import(&queued).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImportError>(),
+            Some(ImportError::DuplicateJob)
+        ));
+        restarted.cancel(&duplicate).await.unwrap();
+        assert_eq!(restarted.interrupt_unfinished().await.unwrap().len(), 1);
+
+        // Once retried, they're no longer carried over to the next boot.
+        let retry = restarted.retry_import(&queued).await.unwrap();
+        wait_until_finished(&restarted, &retry).await;
+        assert!(restarted.interrupt_unfinished().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_job_status_serde() {
+        for (status, json) in [
+            (ImportJobStatus::Queued, r#"{"type":"queued"}"#),
+            (
+                ImportJobStatus::InProgress { progress: 0.5 },
+                r#"{"type":"in-progress","progress":0.5}"#,
+            ),
+            (
+                ImportJobStatus::Completed {
+                    info: Some("/models/model.gguf".to_owned()),
+                },
+                r#"{"type":"completed","info":"/models/model.gguf"}"#,
+            ),
+            (
+                ImportJobStatus::Failed {
+                    error: Some("no such file".to_owned()),
+                },
+                r#"{"type":"finished","error":"no such file"}"#,
+            ),
+            (ImportJobStatus::Cancelled, r#"{"type":"cancelled"}"#),
+            (ImportJobStatus::Interrupted, r#"{"type":"interrupted"}"#),
+        ] {
+            assert_eq!(serde_json::to_string(&status).unwrap(), json);
+            assert_eq!(
+                serde_json::from_str::<ImportJobStatus>(json).unwrap(),
+                status
+            );
+        }
+    }
+}
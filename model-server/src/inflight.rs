@@ -0,0 +1,188 @@
+//! Registry of the generations currently running, for diagnosing a server whose inference is wedged.
+//!
+//! With `DEBUG_SIGNALS=true`, the server handles two signals:
+//!
+//! - `SIGUSR1` logs every in-flight generation: its model, how long ago it started and how many tokens
+//!   it has produced so far. A generation whose token count stops moving is stuck inside llama.cpp.
+//! - `SIGUSR2` cancels every in-flight generation. Each one stops before evaluating its next token and
+//!   finishes with a `cancelled` finish reason. A generation hung inside a single evaluation can't be
+//!   interrupted, and keeps holding its model.
+//!
+//! The signals are off by default, since SIGUSR1 and SIGUSR2 otherwise terminate the process.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use llamacpp::GenerationControl;
+use log::{info, warn};
+
+/// Every generation currently running on the server.
+#[derive(Default)]
+pub struct InFlightGenerations {
+    next_id: AtomicU64,
+    generations: Mutex<HashMap<u64, InFlight>>,
+}
+
+struct InFlight {
+    model: String,
+    started_at: Instant,
+    control: Arc<GenerationControl>,
+}
+
+/// What an in-flight generation is up to, see [InFlightGenerations::snapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlightSummary {
+    pub model: String,
+    pub elapsed: Duration,
+    pub n_generated: usize,
+    pub cancelled: bool,
+}
+
+impl InFlightGenerations {
+    /// Track a generation on `model` until the returned guard is dropped. Pass
+    /// [InFlightGuard::control] to the generation so it reports progress and can be cancelled.
+    pub fn register(self: &Arc<Self>, model: &str) -> InFlightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let control = Arc::new(GenerationControl::default());
+        self.generations.lock().unwrap().insert(
+            id,
+            InFlight {
+                model: model.to_owned(),
+                started_at: Instant::now(),
+                control: Arc::clone(&control),
+            },
+        );
+
+        InFlightGuard {
+            registry: Arc::clone(self),
+            id,
+            control,
+        }
+    }
+
+    /// Every in-flight generation, in the order they started.
+    pub fn snapshot(&self) -> Vec<InFlightSummary> {
+        let generations = self.generations.lock().unwrap();
+        let mut ids: Vec<_> = generations.keys().copied().collect();
+        ids.sort_unstable();
+
+        ids.iter()
+            .map(|id| {
+                let generation = &generations[id];
+                InFlightSummary {
+                    model: generation.model.clone(),
+                    elapsed: generation.started_at.elapsed(),
+                    n_generated: generation.control.n_generated(),
+                    cancelled: generation.control.is_cancelled(),
+                }
+            })
+            .collect()
+    }
+
+    /// Ask every in-flight generation to stop, returning how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let generations = self.generations.lock().unwrap();
+        for generation in generations.values() {
+            generation.control.cancel();
+        }
+
+        generations.len()
+    }
+
+    /// Log every in-flight generation, see [InFlightGenerations::snapshot].
+    pub fn log_snapshot(&self) {
+        let snapshot = self.snapshot();
+        info!("{} generations in flight", snapshot.len());
+        for generation in snapshot {
+            info!(
+                "in flight: model={} elapsed={:?} tokens={} cancelled={}",
+                generation.model, generation.elapsed, generation.n_generated, generation.cancelled
+            );
+        }
+    }
+}
+
+/// Keeps a generation listed in [InFlightGenerations] while it's held.
+pub struct InFlightGuard {
+    registry: Arc<InFlightGenerations>,
+    id: u64,
+    control: Arc<GenerationControl>,
+}
+
+impl InFlightGuard {
+    pub fn control(&self) -> Arc<GenerationControl> {
+        Arc::clone(&self.control)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.registry.generations.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Dump in-flight generations on SIGUSR1 and cancel them on SIGUSR2, as described in the module docs.
+#[cfg(unix)]
+pub fn spawn_signal_handlers(
+    registry: Arc<InFlightGenerations>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    use anyhow::Context;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut dump = signal(SignalKind::user_defined1()).context("failed to listen for SIGUSR1")?;
+    let mut cancel = signal(SignalKind::user_defined2()).context("failed to listen for SIGUSR2")?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = dump.recv() => registry.log_snapshot(),
+                Some(()) = cancel.recv() => {
+                    let cancelled = registry.cancel_all();
+                    warn!("SIGUSR2: cancelled {} in-flight generations", cancelled);
+                }
+                else => break,
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::InFlightGenerations;
+
+    #[test]
+    fn test_in_flight_generations() {
+        let registry = Arc::new(InFlightGenerations::default());
+        let first = registry.register("llama");
+        let second = registry.register("mistral");
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].model, "llama");
+        assert_eq!(snapshot[0].n_generated, 0);
+        assert!(!snapshot[0].cancelled);
+
+        assert_eq!(registry.cancel_all(), 2);
+        assert!(first.control().is_cancelled());
+        assert!(second.control().is_cancelled());
+
+        // Finished generations drop out of the registry.
+        drop(first);
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].model, "mistral");
+        assert!(snapshot[0].cancelled);
+
+        drop(second);
+        assert!(registry.snapshot().is_empty());
+        assert_eq!(registry.cancel_all(), 0);
+    }
+}
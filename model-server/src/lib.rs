@@ -1,6 +1,16 @@
 pub mod api_types;
+pub mod capabilities;
+pub mod chat;
+pub mod checksum;
 pub mod db;
 pub mod db_types;
+pub mod descriptions;
+pub mod download;
 pub mod import;
+pub mod inflight;
+pub mod orphans;
+pub mod pool;
+pub mod quantization;
+pub mod redaction;
 pub mod router;
 pub mod state;
@@ -2,17 +2,28 @@ use std::{
     net::{Ipv4Addr, SocketAddr},
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
 
-use llamacpp::Backend;
+use llamacpp::{Backend, ContextSize, LoadParams};
 
 use model_server::{
-    db::{manager::LinearMigrationManager, manager::MigrationManager, migration::V0, tables::DB},
-    import::InMemoryImporter,
-    router::app_router,
-    state::{AppState, ManagedModel},
+    db::{
+        manager::LinearMigrationManager,
+        manager::MigrationManager,
+        migration::{V0, V1, V2, V3, V4, V5, V6},
+        tables::{spawn_vacuum_task, ExperimentLimits, DB},
+    },
+    descriptions::DescriptionWriter,
+    import::{Importer, InMemoryImporter},
+    inflight::InFlightGenerations,
+    pool::{spawn_idle_unloader, IdleUnloadConfig, ModelPool},
+    redaction::{self, Redactor},
+    router::{app_router, cors::CorsConfig, RouterConfig},
+    state::{AppState, GenerationLimiter, StreamConfig},
 };
 use serde::Deserialize;
 
@@ -24,6 +35,126 @@ struct EnvVars {
     port: u16,
     #[serde(default = "default_db_path")]
     db_path: String,
+    /// Directory model files are kept in, checked by `GET /v1/models/orphans` for files that no model
+    /// is registered from.
+    models_dir: Option<PathBuf>,
+    /// Number of times to try registering an imported model with the DB before failing the import.
+    #[serde(default = "default_import_register_attempts")]
+    import_register_attempts: u32,
+    /// Record imports still running at shutdown in the DB, so they're listed as interrupted and can be
+    /// retried after the next boot. They're logged either way.
+    #[serde(default = "default_persist_interrupted_imports")]
+    persist_interrupted_imports: bool,
+    /// Size caps in bytes for the prompt and output of saved experiments, see [ExperimentLimits].
+    #[serde(default = "default_experiment_max_prompt_bytes")]
+    experiment_max_prompt_bytes: usize,
+    #[serde(default = "default_experiment_max_output_bytes")]
+    experiment_max_output_bytes: usize,
+    /// Most versions kept of each model that doesn't set its own limit. Registering more prunes the
+    /// oldest. Unset keeps every version.
+    max_versions_per_model: Option<u32>,
+    /// Treat model files as a store shared with other servers that nothing may write to, e.g. an NFS
+    /// or EFS mount. Models are mapped read-only and share-locked while loaded, see
+    /// [llamacpp::LoadParams::read_only], and pruned versions keep their files. Needs `SCRATCH_DIR`.
+    #[serde(default)]
+    model_store_read_only: bool,
+    /// Writable directory for files the server creates, such as downloads, so nothing is written into
+    /// a read-only model store. Unset downloads into the Hugging Face cache.
+    scratch_dir: Option<PathBuf>,
+    /// Most memory in bytes a model that doesn't set its own limit may take once loaded, by the estimate
+    /// of its weights plus the KV cache for its context. Loads over it are refused. Unset means no limit.
+    model_max_memory_bytes: Option<u64>,
+    /// Seconds between SSE heartbeat comments on streaming responses, 0 to disable them.
+    #[serde(default = "default_sse_heartbeat_secs")]
+    sse_heartbeat_secs: u64,
+    /// Most generations that may run at once across all models, beyond which requests get a 503.
+    /// Defaults to one per [llamacpp::N_THREADS] cores.
+    max_concurrent_generations: Option<usize>,
+    /// Seconds a loaded model may go unused before it's unloaded, 0 to keep models loaded forever.
+    #[serde(default = "default_model_idle_timeout_secs")]
+    model_idle_timeout_secs: u64,
+    /// Seconds between scheduled compactions of the DB, 0 to only compact via `POST /admin/vacuum`.
+    #[serde(default = "default_db_vacuum_interval_secs")]
+    db_vacuum_interval_secs: u64,
+    /// Seconds between checks for idle models.
+    #[serde(default = "default_model_idle_check_secs")]
+    model_idle_check_secs: u64,
+    /// Context window for loaded models: a number of tokens, or `auto` to fit the largest context
+    /// the memory budget allows. Unset uses llama.cpp's default.
+    model_context_size: Option<String>,
+    /// Memory budget per model in bytes, covering both weights and KV cache. Required for `auto`.
+    model_memory_budget_bytes: Option<u64>,
+    /// Milliseconds a model's description must go without updates before it's written to the DB, so
+    /// rapid edits coalesce into one write. 0 writes every update immediately.
+    #[serde(default)]
+    description_write_delay_ms: u64,
+    /// Header used to read, generate and echo request IDs.
+    #[serde(default = "default_request_id_header")]
+    request_id_header: String,
+    /// File of regexes, one per line, for text to redact from prompts and completions before they're
+    /// logged or saved as experiments. Blank lines are skipped. Read from a file rather than the
+    /// environment, as regexes can't be split on commas.
+    redact_patterns_file: Option<PathBuf>,
+    /// Also redact email addresses and common API key formats.
+    #[serde(default)]
+    redact_common_patterns: bool,
+    /// Log in-flight generations on SIGUSR1 and cancel them on SIGUSR2, for debugging a wedged
+    /// server, see [model_server::inflight].
+    #[serde(default)]
+    debug_signals: bool,
+    /// Comma-separated origins allowed to make cross-origin requests. Unset allows any origin.
+    cors_allowed_origins: Option<Vec<String>>,
+    /// Comma-separated methods and headers allowed in preflights, when origins are restricted.
+    cors_allowed_methods: Option<Vec<String>>,
+    cors_allowed_headers: Option<Vec<String>>,
+}
+
+impl EnvVars {
+    fn cors_config(&self) -> Result<CorsConfig> {
+        let mut config = CorsConfig::default();
+        if let Some(origins) = &self.cors_allowed_origins {
+            let origins = origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin.trim()))
+                .collect::<Result<_, _>>()
+                .context("invalid CORS_ALLOWED_ORIGINS")?;
+            config.allowed_origins = Some(origins);
+        }
+        if let Some(methods) = &self.cors_allowed_methods {
+            config.allowed_methods = methods
+                .iter()
+                .map(|method| Method::from_bytes(method.trim().as_bytes()))
+                .collect::<Result<_, _>>()
+                .context("invalid CORS_ALLOWED_METHODS")?;
+        }
+        if let Some(headers) = &self.cors_allowed_headers {
+            config.allowed_headers = headers
+                .iter()
+                .map(|header| HeaderName::try_from(header.trim()))
+                .collect::<Result<_, _>>()
+                .context("invalid CORS_ALLOWED_HEADERS")?;
+        }
+
+        Ok(config)
+    }
+
+    fn context_size(&self) -> Result<ContextSize> {
+        match self.model_context_size.as_deref() {
+            None => Ok(ContextSize::Default),
+            Some("auto") => {
+                let memory_budget_bytes = self.model_memory_budget_bytes.ok_or_else(|| {
+                    anyhow!("MODEL_CONTEXT_SIZE=auto requires MODEL_MEMORY_BUDGET_BYTES")
+                })?;
+                Ok(ContextSize::Auto {
+                    memory_budget_bytes,
+                })
+            }
+            Some(n_ctx) => n_ctx
+                .parse()
+                .map(ContextSize::Explicit)
+                .context("MODEL_CONTEXT_SIZE must be a number of tokens or auto"),
+        }
+    }
 }
 
 fn default_listen_addr() -> Ipv4Addr {
@@ -38,6 +169,72 @@ fn default_db_path() -> String {
     String::from("prod.db")
 }
 
+fn default_import_register_attempts() -> u32 {
+    3
+}
+
+fn default_persist_interrupted_imports() -> bool {
+    true
+}
+
+fn default_experiment_max_prompt_bytes() -> usize {
+    ExperimentLimits::default().max_prompt_bytes
+}
+
+fn default_experiment_max_output_bytes() -> usize {
+    ExperimentLimits::default().max_output_bytes
+}
+
+fn default_db_vacuum_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_model_idle_timeout_secs() -> u64 {
+    15 * 60
+}
+
+fn default_model_idle_check_secs() -> u64 {
+    60
+}
+
+fn default_request_id_header() -> String {
+    RouterConfig::default().request_id_header.to_string()
+}
+
+fn default_sse_heartbeat_secs() -> u64 {
+    StreamConfig::default()
+        .heartbeat_interval
+        .map_or(0, |interval| interval.as_secs())
+}
+
+/// Resolves once the server is asked to stop, by Ctrl-C or, on Unix, by the SIGTERM that docker and
+/// kubernetes send, so either way the shutdown work after serving still runs.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        signal(SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    log::info!("Shutting down");
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // env_logger::init_from_env(Env::default().default_filter_or("info"));
@@ -47,17 +244,50 @@ async fn main() -> Result<()> {
     log::info!("Loading .env");
     let env: EnvVars = envy::from_env()?;
     log::info!("Environment: {:?}", &env);
-
-    // TODO(aduffy): Replace model mutex with ModelPool
-    let backend = Backend::new();
-    let model = backend.load_model(&PathBuf::from("/Users/aduffy/Documents/llama2_gguf.bin"))?;
+    let context_size = env.context_size()?;
+    let cors = env.cors_config()?;
+    if env.max_versions_per_model == Some(0) {
+        return Err(anyhow!("MAX_VERSIONS_PER_MODEL must be at least 1"));
+    }
+    if env.model_store_read_only && env.scratch_dir.is_none() {
+        return Err(anyhow!(
+            "MODEL_STORE_READ_ONLY needs a writable SCRATCH_DIR"
+        ));
+    }
+    if env.model_max_memory_bytes == Some(0) {
+        return Err(anyhow!("MODEL_MAX_MEMORY_BYTES must be at least 1"));
+    }
+    if env.max_concurrent_generations == Some(0) {
+        return Err(anyhow!("MAX_CONCURRENT_GENERATIONS must be at least 1"));
+    }
+    let redact_patterns = match &env.redact_patterns_file {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read REDACT_PATTERNS_FILE {:?}", path))?,
+        None => String::new(),
+    };
+    let redactor = Redactor::new(
+        &redaction::parse_patterns(&redact_patterns),
+        env.redact_common_patterns,
+    )?;
 
     // Generate a managed connection for the SQLite DB.
-    let db = DB::open(env.db_path).context("failed to load DB")?;
+    let mut db = DB::open(env.db_path).context("failed to load DB")?;
+    db.experiment_limits = ExperimentLimits {
+        max_prompt_bytes: env.experiment_max_prompt_bytes,
+        max_output_bytes: env.experiment_max_output_bytes,
+    };
+    db.max_versions = env.max_versions_per_model;
+    db.read_only_store = env.model_store_read_only;
 
     // Register migrations
     let mut migration_manager = LinearMigrationManager::new();
     migration_manager.register_migration(Arc::new(V0));
+    migration_manager.register_migration(Arc::new(V1));
+    migration_manager.register_migration(Arc::new(V2));
+    migration_manager.register_migration(Arc::new(V3));
+    migration_manager.register_migration(Arc::new(V4));
+    migration_manager.register_migration(Arc::new(V5));
+    migration_manager.register_migration(Arc::new(V6));
 
     // Execute migrations
     {
@@ -73,29 +303,93 @@ async fn main() -> Result<()> {
     }
 
     let db = Arc::new(db);
+    if env.db_vacuum_interval_secs > 0 {
+        spawn_vacuum_task(
+            Arc::clone(&db),
+            Duration::from_secs(env.db_vacuum_interval_secs),
+        );
+    }
 
     // Create an Importer
-    let importer = InMemoryImporter::new(Arc::clone(&db));
+    let mut importer = InMemoryImporter::new(Arc::clone(&db), env.import_register_attempts);
+    if let Some(scratch_dir) = &env.scratch_dir {
+        importer = importer.with_download_dir(scratch_dir.join("downloads"));
+    }
+    let importer = Arc::new(importer);
+    if env.persist_interrupted_imports {
+        let interrupted = db
+            .get_interrupted_imports()
+            .await
+            .context("failed to read interrupted imports")?;
+        for import in &interrupted {
+            log::warn!(
+                "import job {} from {:?} was interrupted by shutdown at {} with {} bytes downloaded, \
+                retry it with POST /v1/imports/{}/retry",
+                import.job_id,
+                import.task,
+                import.interrupted_at,
+                import.downloaded_bytes,
+                import.job_id
+            );
+        }
+        importer.restore_interrupted(interrupted).await?;
+    }
+
+    // Models are loaded on first use, and unloaded again once they sit idle.
+    let load_params = LoadParams {
+        context_size,
+        max_memory_bytes: env.model_max_memory_bytes,
+        read_only: env.model_store_read_only,
+    };
+    let pool = Arc::new(ModelPool::new(Backend::new(), load_params, Arc::clone(&db)));
+    if env.model_idle_timeout_secs > 0 {
+        spawn_idle_unloader(
+            Arc::clone(&pool),
+            IdleUnloadConfig {
+                idle_timeout: Duration::from_secs(env.model_idle_timeout_secs),
+                check_interval: Duration::from_secs(env.model_idle_check_secs),
+            },
+        );
+    }
+
+    let descriptions = Arc::new(DescriptionWriter::new(
+        Arc::clone(&db),
+        (env.description_write_delay_ms > 0)
+            .then(|| Duration::from_millis(env.description_write_delay_ms)),
+    ));
+
+    let inflight = Arc::new(InFlightGenerations::default());
+    if env.debug_signals {
+        #[cfg(unix)]
+        model_server::inflight::spawn_signal_handlers(Arc::clone(&inflight))?;
+        #[cfg(not(unix))]
+        log::warn!("DEBUG_SIGNALS is only supported on Unix");
+    }
 
     let state = AppState {
-        model: Arc::new(ManagedModel::new(model)),
-        importer: Arc::new(importer),
-        db,
+        pool,
+        importer: Arc::clone(&importer) as _,
+        db: Arc::clone(&db),
+        descriptions: Arc::clone(&descriptions),
+        generations: Arc::new(GenerationLimiter::new(
+            env.max_concurrent_generations
+                .unwrap_or_else(GenerationLimiter::default_limit),
+        )),
+        inflight,
+        redactor: Arc::new(redactor),
+        models_dir: env.models_dir.clone().map(Arc::from),
+        stream_config: StreamConfig {
+            heartbeat_interval: (env.sse_heartbeat_secs > 0)
+                .then(|| Duration::from_secs(env.sse_heartbeat_secs)),
+        },
     };
 
-    let app = app_router()
-        .layer(
-            tower_http::trace::TraceLayer::new_for_http()
-                .make_span_with(
-                    tower_http::trace::DefaultMakeSpan::new().level(tracing::Level::INFO),
-                )
-                .on_response(
-                    tower_http::trace::DefaultOnResponse::new()
-                        .level(tracing::Level::INFO)
-                        .latency_unit(tower_http::LatencyUnit::Micros),
-                ),
-        )
-        .with_state(state);
+    let router_config = RouterConfig {
+        request_id_header: HeaderName::try_from(env.request_id_header.as_str())
+            .context("invalid REQUEST_ID_HEADER")?,
+        cors,
+    };
+    let app = app_router(&router_config).with_state(state);
 
     let listen_addr: SocketAddr = format!("{}:{}", &env.host, &env.port)
         .parse()
@@ -103,9 +397,30 @@ async fn main() -> Result<()> {
         .unwrap();
     axum::Server::bind(&listen_addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .context("failed to start axum server")
         .unwrap();
 
+    // Imports can't outlive the process, so record which ones were cut short for the next boot.
+    let interrupted = importer
+        .interrupt_unfinished()
+        .await
+        .context("failed to interrupt imports")?;
+    if !interrupted.is_empty() {
+        log::warn!("{} import jobs were interrupted", interrupted.len());
+    }
+    if env.persist_interrupted_imports {
+        db.replace_interrupted_imports(&interrupted)
+            .await
+            .context("failed to record interrupted imports")?;
+    }
+
+    // Don't lose description updates still waiting out their write delay.
+    descriptions
+        .flush_all()
+        .await
+        .context("failed to write pending descriptions")?;
+
     Ok(())
 }
@@ -0,0 +1,112 @@
+//! Drift between the registered models and the files on disk, e.g. after model files were moved or
+//! deleted by hand.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::api_types::{OrphansResponse, RegisteredModelFile};
+
+/// Suffix of the files in-progress downloads write to, see [crate::download::part_path].
+const PART_SUFFIX: &str = ".part";
+
+/// Cross-reference the model path of every `registered` version against the disk. Versions whose
+/// file is gone are reported as missing. With `models_dir`, files under it that no version refers to are
+/// reported too. Walks the filesystem, so call it from a blocking context.
+pub fn find_orphans(
+    registered: Vec<RegisteredModelFile>,
+    models_dir: Option<&Path>,
+) -> anyhow::Result<OrphansResponse> {
+    // Paths are compared after resolving symlinks, as both imports and registrations may go via one.
+    let referenced: HashSet<PathBuf> = registered
+        .iter()
+        .filter_map(|version| version.model_path.canonicalize().ok())
+        .collect();
+    let missing_files = registered
+        .into_iter()
+        .filter(|version| !version.model_path.is_file())
+        .collect();
+
+    let unregistered_files = models_dir
+        .map(|dir| {
+            let mut files = Vec::new();
+            walk(dir, &mut files)?;
+            files.retain(|file| {
+                !file.to_string_lossy().ends_with(PART_SUFFIX)
+                    && file
+                        .canonicalize()
+                        .is_ok_and(|file| !referenced.contains(&file))
+            });
+            files.sort();
+            anyhow::Ok(files)
+        })
+        .transpose()?;
+
+    Ok(OrphansResponse {
+        missing_files,
+        unregistered_files,
+    })
+}
+
+/// Collect every file under `dir`, recursively.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("failed to list {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use semver::Version;
+    use tempdir::TempDir;
+
+    use super::find_orphans;
+    use crate::api_types::RegisteredModelFile;
+
+    #[test]
+    fn test_find_orphans() {
+        let dir = TempDir::new("orphans_test").unwrap();
+        std::fs::create_dir(dir.path().join("llama")).unwrap();
+        for file in ["llama/model.gguf", "stray.gguf", "partial.gguf.part"] {
+            std::fs::write(dir.path().join(file), b"GGUF").unwrap();
+        }
+
+        let registered = vec![
+            RegisteredModelFile {
+                model: "llama".to_owned(),
+                version: Version::new(0, 1, 0),
+                model_path: dir.path().join("llama/model.gguf"),
+            },
+            RegisteredModelFile {
+                model: "mistral".to_owned(),
+                version: Version::new(0, 1, 0),
+                model_path: PathBuf::from("/nonexistent/mistral.gguf"),
+            },
+        ];
+
+        let orphans = find_orphans(registered.clone(), Some(dir.path())).unwrap();
+        assert_eq!(orphans.missing_files, vec![registered[1].clone()]);
+        // In-progress downloads aren't orphans.
+        assert_eq!(
+            orphans.unregistered_files,
+            Some(vec![dir.path().join("stray.gguf")])
+        );
+
+        // Without a managed directory, only missing files are reported.
+        let orphans = find_orphans(registered, None).unwrap();
+        assert_eq!(orphans.missing_files.len(), 1);
+        assert_eq!(orphans.unregistered_files, None);
+    }
+}
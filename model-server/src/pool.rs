@@ -0,0 +1,288 @@
+//! Lazily loaded set of models, keyed by model name.
+//!
+//! Models are loaded the first time a request names them, and stay resident until they've been idle
+//! for longer than the configured timeout, see [spawn_idle_unloader]. Loading them again on the next
+//! request is transparent to callers.
+
+use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use llamacpp::{Backend, LoadParams, MemoryLimitExceeded};
+use log::{error, info, warn};
+use semver::Version;
+use tokio::{
+    sync::{Mutex, OnceCell},
+    task::JoinHandle,
+};
+
+use crate::{
+    api_types::ModelParams,
+    checksum::checksum_file,
+    db::tables::DB,
+    quantization::{select_version, VersionSelector},
+    state::ManagedModel,
+};
+
+#[derive(Debug)]
+pub enum PoolError {
+    /// No model with the requested name has been registered.
+    ModelNotFound,
+
+    /// The model has no version matching the requested version or quantization.
+    VersionNotFound,
+
+    /// Loading the model would take more memory than its limit allows.
+    MemoryLimitExceeded(MemoryLimitExceeded),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// Report a failed DB lookup as `not_found` if it failed because there was no matching row, leaving
+/// any other failure as it is so it isn't mistaken for a missing model.
+fn or_not_found<T>(result: anyhow::Result<T>, not_found: PoolError) -> anyhow::Result<T> {
+    result.map_err(|err| match err.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::QueryReturnedNoRows) => err.context(not_found),
+        _ => err,
+    })
+}
+
+/// A model that's loaded, or being loaded by the first request that asked for it.
+type ModelSlot = Arc<OnceCell<Arc<ManagedModel>>>;
+
+pub struct ModelPool {
+    backend: Arc<Backend>,
+    load_params: LoadParams,
+    db: Arc<DB>,
+    /// Loaded models, keyed by name and version.
+    models: Mutex<HashMap<(String, Version), ModelSlot>>,
+}
+
+impl ModelPool {
+    pub fn new(backend: Backend, load_params: LoadParams, db: Arc<DB>) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            load_params,
+            db,
+            models: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Params models are loaded with, before any per-model memory limit is applied.
+    pub fn load_params(&self) -> &LoadParams {
+        &self.load_params
+    }
+
+    /// Get the version of a model picked by `selector`, see [select_version], loading it if it isn't
+    /// resident yet. Marks the model as used.
+    pub async fn get(
+        &self,
+        model_name: &str,
+        selector: &VersionSelector,
+    ) -> anyhow::Result<Arc<ManagedModel>> {
+        let (default_quantization, versions) = or_not_found(
+            self.db.get_version_quantizations(model_name).await,
+            PoolError::ModelNotFound,
+        )?;
+        let version = select_version(&versions, default_quantization.as_deref(), selector)
+            .ok_or(PoolError::VersionNotFound)?;
+
+        // Concurrent requests for a cold model wait on the same slot, so it's only loaded once. The
+        // pool itself isn't locked during the load, so requests for other models carry on. A failed
+        // load leaves the slot empty for the next request to try again.
+        let slot = Arc::clone(
+            self.models
+                .lock()
+                .await
+                .entry((model_name.to_owned(), version.clone()))
+                .or_default(),
+        );
+        let model = slot
+            .get_or_try_init(|| self.load(model_name, version, versions))
+            .await?;
+        model.touch();
+
+        Ok(Arc::clone(model))
+    }
+
+    /// Load a version of a model. `versions` are all the model's versions with their quantizations.
+    async fn load(
+        &self,
+        model_name: &str,
+        version: Version,
+        versions: Vec<(Version, Option<String>)>,
+    ) -> anyhow::Result<Arc<ManagedModel>> {
+        let (_, params) = or_not_found(
+            self.db.get_model_version_params(model_name, &version).await,
+            PoolError::VersionNotFound,
+        )?;
+        let ModelParams::COMPLETION(params) = params;
+        // The model's own limit beats the server-wide one.
+        let max_memory_bytes = or_not_found(
+            self.db.get_max_memory(model_name).await,
+            PoolError::ModelNotFound,
+        )?;
+
+        info!(
+            "loading model {}@{} from {:?}",
+            model_name, version, params.model_path
+        );
+        let backend = Arc::clone(&self.backend);
+        let model_path = params.model_path.clone();
+        let load_params = LoadParams {
+            max_memory_bytes: max_memory_bytes.or(self.load_params.max_memory_bytes),
+            ..self.load_params.clone()
+        };
+        let model = tokio::task::spawn_blocking(move || {
+            backend.load_model_with_params(&model_path, &load_params)
+        })
+        .await?
+        .map_err(|err| match err.downcast_ref::<MemoryLimitExceeded>() {
+            Some(exceeded) => {
+                warn!("not loading model {}@{}: {}", model_name, version, exceeded);
+                let exceeded = exceeded.clone();
+                err.context(PoolError::MemoryLimitExceeded(exceeded))
+            }
+            None => err,
+        })
+        .with_context(|| format!("failed to load model {}@{}", model_name, version))?;
+
+        // Hashing can take a while for large models, so don't hold up the request for it.
+        tokio::spawn(verify_model_file(
+            Arc::clone(&self.db),
+            model_name.to_owned(),
+            version.clone(),
+            params.model_path,
+        ));
+
+        let quantization = versions
+            .into_iter()
+            .find(|(candidate, _)| *candidate == version)
+            .and_then(|(_, quantization)| quantization);
+
+        Ok(Arc::new(ManagedModel::new(
+            model,
+            version,
+            quantization,
+            params.default_sampling,
+        )))
+    }
+
+    /// Get every loaded version of a model, without marking them as used.
+    pub async fn get_loaded(&self, model_name: &str) -> Vec<Arc<ManagedModel>> {
+        self.models
+            .lock()
+            .await
+            .iter()
+            .filter(|((name, _), _)| name == model_name)
+            .filter_map(|(_, slot)| slot.get().map(Arc::clone))
+            .collect()
+    }
+
+    /// Unload every version of a model so the next request loads it afresh, e.g. after its params
+    /// changed. Returns whether any version was loaded. Loads still in progress finish for the
+    /// requests waiting on them, but aren't kept.
+    pub async fn unload(&self, model_name: &str) -> bool {
+        let mut models = self.models.lock().await;
+        let mut unloaded = false;
+        models.retain(|(name, _), slot| {
+            let keep = name != model_name;
+            unloaded |= !keep && slot.initialized();
+            keep
+        });
+
+        unloaded
+    }
+
+    /// Unload every model that hasn't been used for at least `idle_timeout`, returning their names
+    /// and versions. Models that are busy generating are never unloaded.
+    pub async fn unload_idle(&self, idle_timeout: Duration) -> Vec<(String, Version)> {
+        let mut models = self.models.lock().await;
+        let idle: Vec<(String, Version)> = models
+            .iter()
+            .filter(|(_, slot)| {
+                // Models that are still loading aren't idle.
+                slot.get().is_some_and(|model| {
+                    model.idle_for() >= idle_timeout && model.model.try_lock().is_ok()
+                })
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        // Requests that already hold a handle keep the model alive until they finish with it.
+        for key in &idle {
+            models.remove(key);
+        }
+
+        idle
+    }
+}
+
+/// Checksum a model's file and compare it with what was recorded when it was registered. If the file
+/// changed on disk since, the mismatch is logged and recorded next to the version, see
+/// [DB::set_file_on_disk], while the recorded size and checksum are kept so it's flagged again on every
+/// load. Versions registered without a checksum get the file's recorded.
+async fn verify_model_file(db: Arc<DB>, model_name: String, version: Version, path: PathBuf) {
+    let file = match tokio::task::spawn_blocking(move || checksum_file(&path)).await {
+        Ok(Ok(file)) => file,
+        Ok(Err(err)) => return warn!("failed to checksum model {}: {:#}", model_name, err),
+        Err(err) => return warn!("failed to checksum model {}: {}", model_name, err),
+    };
+
+    let result = match db.get_model_file(&model_name, &version).await {
+        Ok(Some(recorded)) if recorded == file => {
+            db.set_file_on_disk(&model_name, &version, None).await
+        }
+        Ok(Some(recorded)) => {
+            error!(
+                "model file for {}@{} changed on disk: recorded {} bytes with sha256 {}, found {} bytes with sha256 {}",
+                model_name, version, recorded.size_bytes, recorded.sha256, file.size_bytes, file.sha256
+            );
+            db.set_file_on_disk(&model_name, &version, Some(&file))
+                .await
+        }
+        Ok(None) => {
+            info!(
+                "recording size and checksum of model file for {}@{}",
+                model_name, version
+            );
+            db.update_model_file(&model_name, &version, &file).await
+        }
+        Err(err) => return warn!("failed to look up model file for {}: {:#}", model_name, err),
+    };
+
+    if let Err(err) = result {
+        warn!("failed to record model file for {}: {:#}", model_name, err);
+    }
+}
+
+/// When and how often to unload idle models.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleUnloadConfig {
+    /// Models unused for this long are unloaded.
+    pub idle_timeout: Duration,
+
+    /// How often to check for idle models.
+    pub check_interval: Duration,
+}
+
+/// Periodically unload models from `pool` which have gone idle, logging each eviction.
+pub fn spawn_idle_unloader(pool: Arc<ModelPool>, config: IdleUnloadConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.check_interval);
+        loop {
+            interval.tick().await;
+            for (name, version) in pool.unload_idle(config.idle_timeout).await {
+                info!(
+                    "unloaded model {}@{} after being idle for at least {:?}",
+                    name, version, config.idle_timeout
+                );
+            }
+        }
+    })
+}
@@ -0,0 +1,157 @@
+//! Quantization of model versions, and picking which version of a model a request runs.
+//!
+//! Several quantizations of the same weights, e.g. Q4_K_M and Q8_0, can be registered as versions of
+//! one model. Each version records its quantization from the GGUF metadata when it's registered.
+
+use std::{cmp::Reverse, path::Path};
+
+use llamacpp::gguf::{quantization_rank, GgufMetadata};
+use semver::Version;
+
+/// Read the quantization of a model file from its GGUF metadata. `None` if the file type isn't one
+/// we recognise.
+pub fn read_quantization(path: &Path) -> anyhow::Result<Option<String>> {
+    let metadata = GgufMetadata::read(path)?;
+
+    Ok(metadata.quantization().map(str::to_owned))
+}
+
+/// Which version of a model a request asked for. Anything left out is picked by [select_version].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionSelector {
+    pub version: Option<Version>,
+    pub quantization: Option<String>,
+}
+
+/// Pick a version from `versions`, each with its recorded quantization:
+///
+/// 1. An explicitly requested version, as long as it has the requested quantization, if any.
+/// 2. The latest version with the requested quantization.
+/// 3. The latest version with the model's default quantization.
+/// 4. The smallest quantization, preferring the latest version among equals. If any version's
+///    quantization is unknown, sizes can't be compared and the latest version is used instead.
+///
+/// Returns `None` when nothing matches what was requested.
+pub fn select_version(
+    versions: &[(Version, Option<String>)],
+    default_quantization: Option<&str>,
+    selector: &VersionSelector,
+) -> Option<Version> {
+    let has_quantization = |quantization: &Option<String>, wanted: &str| {
+        quantization
+            .as_deref()
+            .is_some_and(|quantization| quantization.eq_ignore_ascii_case(wanted))
+    };
+    let latest_with = |wanted: &str| {
+        versions
+            .iter()
+            .filter(|(_, quantization)| has_quantization(quantization, wanted))
+            .map(|(version, _)| version)
+            .max()
+            .cloned()
+    };
+
+    if let Some(wanted) = &selector.version {
+        return versions
+            .iter()
+            .find(|(version, quantization)| {
+                version == wanted
+                    && match selector.quantization.as_deref() {
+                        Some(wanted) => has_quantization(quantization, wanted),
+                        None => true,
+                    }
+            })
+            .map(|(version, _)| version.clone());
+    }
+
+    if let Some(wanted) = &selector.quantization {
+        return latest_with(wanted);
+    }
+
+    if let Some(version) = default_quantization.and_then(latest_with) {
+        return Some(version);
+    }
+
+    let ranked: Option<Vec<(usize, &Version)>> = versions
+        .iter()
+        .map(|(version, quantization)| {
+            let rank = quantization_rank(quantization.as_deref()?)?;
+            Some((rank, version))
+        })
+        .collect();
+    match ranked {
+        Some(ranked) => ranked
+            .into_iter()
+            .min_by_key(|&(rank, version)| (rank, Reverse(version)))
+            .map(|(_, version)| version.clone()),
+        None => versions.iter().map(|(version, _)| version).max().cloned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use semver::Version;
+
+    use super::{select_version, VersionSelector};
+
+    fn versions() -> Vec<(Version, Option<String>)> {
+        vec![
+            (Version::new(1, 0, 0), Some("Q8_0".to_owned())),
+            (Version::new(1, 1, 0), Some("Q4_K_M".to_owned())),
+            (Version::new(1, 2, 0), Some("Q5_K_M".to_owned())),
+            (Version::new(1, 3, 0), Some("Q4_K_M".to_owned())),
+        ]
+    }
+
+    #[test]
+    fn test_select_version() {
+        let versions = versions();
+        let select =
+            |default: Option<&str>, version: Option<Version>, quantization: Option<&str>| {
+                select_version(
+                    &versions,
+                    default,
+                    &VersionSelector {
+                        version,
+                        quantization: quantization.map(str::to_owned),
+                    },
+                )
+            };
+
+        // Smallest quantization, latest among equals.
+        assert_eq!(select(None, None, None), Some(Version::new(1, 3, 0)));
+        // The model's default wins over the smallest.
+        assert_eq!(
+            select(Some("Q8_0"), None, None),
+            Some(Version::new(1, 0, 0))
+        );
+        // A default nothing has falls back to the smallest.
+        assert_eq!(select(Some("F16"), None, None), Some(Version::new(1, 3, 0)));
+        // Requests win over the default, and match case-insensitively.
+        assert_eq!(
+            select(Some("Q8_0"), None, Some("q5_k_m")),
+            Some(Version::new(1, 2, 0))
+        );
+        assert_eq!(
+            select(None, Some(Version::new(1, 1, 0)), None),
+            Some(Version::new(1, 1, 0))
+        );
+        // A version and quantization that disagree match nothing.
+        assert_eq!(
+            select(None, Some(Version::new(1, 1, 0)), Some("Q8_0")),
+            None
+        );
+        assert_eq!(select(None, Some(Version::new(2, 0, 0)), None), None);
+        assert_eq!(select(None, None, Some("F16")), None);
+    }
+
+    #[test]
+    fn test_select_version_unknown_quantization() {
+        let mut versions = versions();
+        versions.push((Version::new(2, 0, 0), None));
+        assert_eq!(
+            select_version(&versions, None, &VersionSelector::default()),
+            Some(Version::new(2, 0, 0))
+        );
+    }
+}
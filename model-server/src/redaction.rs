@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+
+use anyhow::Context;
+use log::{debug, log_enabled, Level};
+use regex::Regex;
+
+/// Log target for prompts and completions. They're only logged at debug level, so content logging is
+/// enabled with e.g. `RUST_LOG=model_server::content=debug`.
+pub const CONTENT_LOG_TARGET: &str = "model_server::content";
+
+/// What redacted text is replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Email addresses.
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+
+/// Common API key and token formats: OpenAI and Anthropic style `sk-` keys, Hugging Face tokens, AWS
+/// access key IDs and GitHub tokens.
+const API_KEY_PATTERN: &str = r"\b(?:sk-[A-Za-z0-9_-]{20,}|hf_[A-Za-z0-9]{30,}|AKIA[0-9A-Z]{16}|gh[pousr]_[A-Za-z0-9]{36,})\b";
+
+/// Regexes listed one per line, as in a `REDACT_PATTERNS_FILE`, skipping blank lines.
+pub fn parse_patterns(list: &str) -> Vec<&str> {
+    list.lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect()
+}
+
+/// Scrubs sensitive text from prompts and completions before they're logged or saved as experiments.
+/// With no patterns, text passes through untouched.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Redact matches of any of `patterns`, and of the built-in email and API key patterns if
+    /// `common_patterns` is set.
+    pub fn new<S: AsRef<str>>(patterns: &[S], common_patterns: bool) -> anyhow::Result<Self> {
+        let common = common_patterns
+            .then_some([EMAIL_PATTERN, API_KEY_PATTERN])
+            .into_iter()
+            .flatten();
+        let patterns = common
+            .chain(patterns.iter().map(AsRef::as_ref))
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("invalid redaction pattern {pattern:?}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(redacted);
+            }
+        }
+
+        text
+    }
+
+    /// Log a prompt and its completion to [CONTENT_LOG_TARGET], redacted.
+    pub fn log_completion(&self, model_id: &str, prompt: &str, completion: &str) {
+        if log_enabled!(target: CONTENT_LOG_TARGET, Level::Debug) {
+            debug!(
+                target: CONTENT_LOG_TARGET,
+                "model={} prompt={:?} completion={:?}",
+                model_id,
+                self.redact(prompt),
+                self.redact(completion)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use super::{parse_patterns, Redactor};
+
+    #[test]
+    fn test_redact() {
+        let redactor = Redactor::new(&[r"\bACCT-\d+\b"], true).unwrap();
+        assert_eq!(
+            redactor.redact(
+                "mail jane.doe@example.com about ACCT-1234, key sk-abcdefghijklmnopqrstuvwx"
+            ),
+            "mail [REDACTED] about [REDACTED], key [REDACTED]"
+        );
+
+        // Clean text isn't copied.
+        assert!(matches!(
+            redactor.redact("nothing to see"),
+            Cow::Borrowed(_)
+        ));
+
+        // Without any patterns, everything passes through.
+        let redactor = Redactor::new::<&str>(&[], false).unwrap();
+        assert_eq!(
+            redactor.redact("jane.doe@example.com"),
+            "jane.doe@example.com"
+        );
+
+        assert!(Redactor::new(&["("], false).is_err());
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        let patterns = parse_patterns("\\b\\d{3,4}-\\d{4}\\b\n\n  \nACCT-\\d+\r\n");
+        assert_eq!(patterns, [r"\b\d{3,4}-\d{4}\b", r"ACCT-\d+"]);
+
+        let redactor = Redactor::new(&patterns, false).unwrap();
+        assert_eq!(
+            redactor.redact("call 555-1234 or 5555-1234 about ACCT-9"),
+            "call [REDACTED] or [REDACTED] about [REDACTED]"
+        );
+    }
+}
@@ -0,0 +1,22 @@
+use crate::{api_types::VacuumResponse, state::AppState};
+use anyhow::Context;
+use axum::{extract::State, http::StatusCode, Json};
+
+/// Compact the DB on demand, outside the regular schedule, e.g. after deleting many models.
+pub async fn vacuum_db(
+    State(app_state): State<AppState>,
+) -> Result<Json<VacuumResponse>, StatusCode> {
+    let report = app_state
+        .db
+        .vacuum()
+        .await
+        .context("failed to vacuum DB")
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(VacuumResponse {
+        bytes_before: report.bytes_before,
+        bytes_after: report.bytes_after,
+        reclaimed_bytes: report.reclaimed_bytes(),
+        vacuumed: report.vacuumed,
+    }))
+}
@@ -0,0 +1,29 @@
+use crate::{
+    api_types::{ChatRenderRequest, ChatRenderResponse},
+    chat::render_llama2,
+};
+use anyhow::Context;
+use axum::{http::StatusCode, Json};
+
+/// Render a conversation into a prompt with the Llama 2 chat template, without running inference.
+/// Useful for checking the chat template when output quality is off.
+///
+/// This is a preview only: the completion endpoints take a plain prompt and never apply a template
+/// themselves. To run a conversation, send the rendered prompt as the completion's `prompt`.
+#[utoipa::path(
+    post, path = "/v1/chat/render", tag = "chat",
+    request_body = ChatRenderRequest,
+    responses(
+        (status = 200, body = ChatRenderResponse, description = "Preview only, completions don't apply the template"),
+        (status = 400, description = "The messages don't form a valid conversation")
+    )
+)]
+pub async fn render_chat(
+    Json(request): Json<ChatRenderRequest>,
+) -> Result<Json<ChatRenderResponse>, StatusCode> {
+    let prompt = render_llama2(&request.messages)
+        .context("failed to render chat messages")
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    Ok(Json(ChatRenderResponse { prompt }))
+}
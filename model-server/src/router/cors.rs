@@ -0,0 +1,199 @@
+//! Cross-origin request handling.
+//!
+//! By default any origin may call the API. Once an allowlist of origins is configured, preflights
+//! from allowed origins get the configured methods and headers back, and requests from any other
+//! origin are rejected with a 403 that says why, instead of failing silently in the browser.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::api_types::ErrorResponse;
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, e.g. `https://app.example.com`. `None` allows any origin.
+    pub allowed_origins: Option<Vec<HeaderValue>>,
+
+    /// Methods and headers preflights allow, when `allowed_origins` is set.
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: None,
+            allowed_methods: vec![Method::GET, Method::POST, Method::PUT, Method::DELETE],
+            allowed_headers: vec![header::CONTENT_TYPE, header::ACCEPT],
+        }
+    }
+}
+
+/// Wrap `router` with CORS handling per `config`. `expose_headers` are readable by browser clients,
+/// and are always allowed on requests too.
+pub fn with_cors<S>(
+    router: Router<S>,
+    config: &CorsConfig,
+    expose_headers: &[HeaderName],
+) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let Some(allowed_origins) = &config.allowed_origins else {
+        return router.layer(
+            CorsLayer::new()
+                .allow_origin(Any)
+                .allow_headers(Any)
+                .allow_methods(Any)
+                .expose_headers(expose_headers.to_vec()),
+        );
+    };
+
+    let allowed_headers: Vec<HeaderName> = config
+        .allowed_headers
+        .iter()
+        .chain(expose_headers)
+        .cloned()
+        .collect();
+
+    // Layers run outside-in, so disallowed origins are turned away before the CORS layer sees them.
+    router
+        .layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::list(allowed_origins.clone()))
+                .allow_methods(config.allowed_methods.clone())
+                .allow_headers(allowed_headers)
+                .expose_headers(expose_headers.to_vec()),
+        )
+        .layer(middleware::from_fn_with_state(
+            Arc::new(allowed_origins.clone()),
+            reject_disallowed_origin,
+        ))
+}
+
+/// Answer requests from origins that aren't allowed with a 403 naming the origin. Requests without
+/// an `Origin` header, e.g. from curl or other servers, aren't subject to CORS and pass through.
+async fn reject_disallowed_origin<B>(
+    State(allowed_origins): State<Arc<Vec<HeaderValue>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(origin) = request.headers().get(header::ORIGIN) else {
+        return next.run(request).await;
+    };
+    if allowed_origins.contains(origin) {
+        return next.run(request).await;
+    }
+
+    let mut body = ErrorResponse::new(
+        "cors_origin_not_allowed",
+        format!(
+            "origin {} is not allowed to call this server, add it to CORS_ALLOWED_ORIGINS",
+            origin.to_str().unwrap_or("<invalid>")
+        ),
+    );
+    body.error.path = Some(request.uri().path().to_owned());
+
+    (StatusCode::FORBIDDEN, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header, HeaderName, HeaderValue, Method, Request, StatusCode},
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::{with_cors, CorsConfig};
+
+    fn router() -> Router {
+        let config = CorsConfig {
+            allowed_origins: Some(vec![HeaderValue::from_static("https://app.example.com")]),
+            ..CorsConfig::default()
+        };
+
+        with_cors(
+            Router::new().route("/v1/complete", post(|| async { "ok" })),
+            &config,
+            &[HeaderName::from_static("x-request-id")],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_preflight_from_allowed_origin() {
+        let response = router()
+            .oneshot(
+                Request::builder()
+                    .method(Method::OPTIONS)
+                    .uri("/v1/complete")
+                    .header(header::ORIGIN, "https://app.example.com")
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET,POST,PUT,DELETE"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type,accept,x-request-id"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_origin_is_forbidden() {
+        let response = router()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/v1/complete")
+                    .header(header::ORIGIN, "https://evil.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = response.into_body().data().await.unwrap().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "cors_origin_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn test_requests_without_origin_pass() {
+        let response = router()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/v1/complete")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,169 @@
+//! MessagePack responses, for clients that send `Accept: application/msgpack`.
+//!
+//! Handlers keep returning JSON. Their responses are re-encoded as MessagePack on the way out, so every
+//! endpoint supports it and the payload has exactly the shape of the JSON one. Streaming responses
+//! such as SSE and NDJSON, and anything else that isn't JSON, are passed through untouched. Clients
+//! that don't ask for MessagePack keep getting JSON.
+
+use axum::{
+    body::{self, Bytes, HttpBody},
+    http::{header, HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::error;
+
+/// Media type of MessagePack responses.
+pub const MSGPACK: &str = "application/msgpack";
+
+/// Also accepted in the `Accept` header, as older clients use it.
+const X_MSGPACK: &str = "application/x-msgpack";
+
+/// Whether the first media range in the `Accept` header that we can serve is MessagePack, in the order
+/// the client listed them. Quality values are not taken into account.
+fn wants_msgpack(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+
+    for media_range in accept.split(',') {
+        match media_range.split(';').next().unwrap_or_default().trim() {
+            MSGPACK | X_MSGPACK => return true,
+            "application/json" | "application/*" | "*/*" => return false,
+            _ => continue,
+        }
+    }
+
+    false
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"))
+}
+
+/// Add `Accept` to the `Vary` header, so caches keep the JSON and MessagePack encodings of a response
+/// apart. Values already there are kept.
+fn vary_on_accept(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|vary| vary.to_str().ok())
+        .flat_map(|vary| vary.split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept"));
+    if !varies {
+        headers.append(header::VARY, HeaderValue::from_static("accept"));
+    }
+}
+
+/// Re-encode a JSON document as MessagePack. Objects become maps keyed by field name, like the JSON.
+fn json_to_msgpack(json: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let value: serde_json::Value = serde_json::from_slice(json)?;
+
+    Ok(rmp_serde::to_vec_named(&value)?)
+}
+
+/// Middleware that re-encodes JSON responses as MessagePack for clients that prefer it. Every JSON
+/// response varies on `Accept`, whichever encoding it went out in.
+pub async fn negotiate_encoding<B>(request: Request<B>, next: Next<B>) -> Response {
+    let msgpack = wants_msgpack(request.headers());
+    let mut response = next.run(request).await;
+    if !is_json(response.headers()) {
+        return response;
+    }
+    vary_on_accept(response.headers_mut());
+    if !msgpack {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    let mut json = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => json.extend_from_slice(&chunk),
+            Err(err) => {
+                error!("failed to read response body: {}", err);
+                return parts.status.into_response();
+            }
+        }
+    }
+
+    match json_to_msgpack(&json) {
+        Ok(msgpack) => {
+            parts
+                .headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(MSGPACK));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, body::boxed(body::Full::from(Bytes::from(msgpack))))
+        }
+        Err(err) => {
+            // Send the JSON after all rather than nothing.
+            error!("failed to encode response as MessagePack: {:#}", err);
+            Response::from_parts(parts, body::boxed(body::Full::from(Bytes::from(json))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{
+        header::{ACCEPT, VARY},
+        HeaderMap, HeaderValue,
+    };
+
+    use super::{json_to_msgpack, vary_on_accept, wants_msgpack};
+
+    #[test]
+    fn test_msgpack_encoding() {
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(wants_msgpack(&accept("application/msgpack")));
+        assert!(wants_msgpack(&accept(
+            "text/html, application/x-msgpack;q=0.9"
+        )));
+        assert!(!wants_msgpack(&accept(
+            "application/json, application/msgpack"
+        )));
+        assert!(!wants_msgpack(&accept("*/*")));
+        assert!(!wants_msgpack(&HeaderMap::new()));
+
+        let json =
+            r#"{"model_id":"llama","choices":[{"index":0,"logprobs":null}],"temperature":0.5}"#;
+        let msgpack = json_to_msgpack(json.as_bytes()).unwrap();
+        assert!(msgpack.len() < json.len());
+        let decoded: serde_json::Value = rmp_serde::from_slice(&msgpack).unwrap();
+        assert_eq!(
+            decoded,
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+
+        assert!(json_to_msgpack(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_vary_on_accept() {
+        let mut headers = HeaderMap::new();
+        vary_on_accept(&mut headers);
+        assert_eq!(headers.get_all(VARY).iter().count(), 1);
+        assert_eq!(headers[VARY], "accept");
+
+        // Other values are kept, and Accept is only listed once.
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("origin"));
+        vary_on_accept(&mut headers);
+        vary_on_accept(&mut headers);
+        let vary: Vec<_> = headers.get_all(VARY).iter().collect();
+        assert_eq!(vary, ["origin", "accept"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Origin, Accept"));
+        vary_on_accept(&mut headers);
+        assert_eq!(headers.get_all(VARY).iter().count(), 1);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    api_types::{ReplayExperimentResponse, SaveExperimentRequest, SaveExperimentResponse},
+    quantization::VersionSelector,
+    router::{
+        generate::{acquire_generation_slot, generation_error, get_model},
+        ApiError,
+    },
+    state::AppState,
+};
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use llamacpp::GenerateParams;
+
+/// Save the prompt and output of a completion. Oversized prompts and outputs are truncated, see
+/// [ExperimentLimits](crate::db::tables::ExperimentLimits), and sensitive text is redacted, see
+/// [Redactor](crate::redaction::Redactor). Replays run on the redacted prompt.
+pub async fn save_experiment(
+    State(app_state): State<AppState>,
+    Json(mut request): Json<SaveExperimentRequest>,
+) -> Result<Json<SaveExperimentResponse>, StatusCode> {
+    request.prompt = app_state.redactor.redact(&request.prompt).into_owned();
+    request.output = app_state.redactor.redact(&request.output).into_owned();
+    let id = app_state
+        .db
+        .save_experiment(&request)
+        .await
+        .context("failed to save experiment")
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(SaveExperimentResponse { id }))
+}
+
+/// Re-run a saved experiment's prompt with the sampling params stored alongside it. Experiments saved
+/// without sampling params can't be replayed.
+pub async fn replay_experiment(
+    Path(id): Path<uuid::Uuid>,
+    State(app_state): State<AppState>,
+) -> Result<Json<ReplayExperimentResponse>, ApiError> {
+    let experiment = app_state
+        .db
+        .get_experiment(&id)
+        .await
+        .context("failed to look up experiment")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let sampling = experiment.sampling.ok_or(StatusCode::CONFLICT)?;
+
+    // Replay on the version the experiment was run against, not whatever is the default now.
+    let selector = VersionSelector {
+        version: Some(experiment.version.clone()),
+        quantization: None,
+    };
+    let slot = acquire_generation_slot(&app_state)?;
+    let inflight = app_state.inflight.register(&experiment.model);
+    let model = get_model(&app_state, &experiment.model, &selector).await?;
+    let prompt = experiment.prompt.clone();
+    let completion = model
+        .lock_for_generation()
+        .await
+        .run_blocking(move |model| {
+            let _slot = slot;
+            model
+                .generate(
+                    &prompt,
+                    &GenerateParams {
+                        sampling: sampling.into(),
+                        control: Some(inflight.control()),
+                        ..Default::default()
+                    },
+                )
+                .map_err(generation_error)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    app_state
+        .redactor
+        .log_completion(&experiment.model, &experiment.prompt, &completion.text);
+
+    Ok(Json(ReplayExperimentResponse {
+        id,
+        completion: completion.text,
+        original_output: experiment.output,
+    }))
+}
@@ -1,27 +1,820 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    sync::Arc,
+};
+
 use crate::{
-    api_types::{GenerateRequest, GenerateResponse},
-    state::AppState,
+    api_types::{
+        AnswerVotes, BatchGenerateRequest, BatchStreamEvent, ChoicesResponse, CompletionChoice,
+        ContinuationSource, ContinueFrom, ErrorResponse, FinishReason, GenerateRequest,
+        GenerateResponse, GenerateResponseFormat, Guidance, LogitBias, LogitBiasToken, MemoryLimit,
+        SamplingParams, SelfConsistencyResult, StopSequence, SweepCompletion, SweepRequest,
+        SweepResponse, Timings, TokenAlternative,
+    },
+    pool::PoolError,
+    quantization::VersionSelector,
+    router::ApiError,
+    state::{AppState, ManagedModel},
+};
+
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::{
+        header::{ACCEPT, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        AppendHeaders, IntoResponse, Response,
+    },
+    Json,
+};
+use llamacpp::{GenerateParams, PromptError, StreamMessage};
+use log::error;
+use regex::Regex;
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        OwnedSemaphorePermit,
+    },
 };
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Most completions a single seed sweep may ask for.
+const MAX_SWEEP_SEEDS: usize = 32;
+
+/// Most completions a self-consistency vote may run.
+const MAX_SELF_CONSISTENCY_N: u32 = 32;
+
+/// Tells reverse proxies such as nginx to pass a streaming response through without buffering it.
+const X_ACCEL_BUFFERING: &str = "x-accel-buffering";
+
+/// Version of the model that served a response with no JSON body to report it in, as
+/// [GenerateResponse::version] does.
+const X_MODEL_VERSION: &str = "x-model-version";
 
-use axum::{extract::State, http::StatusCode, Json};
+/// Quantization of the model that served a response, alongside [X_MODEL_VERSION]. Left out if unknown.
+const X_MODEL_QUANTIZATION: &str = "x-model-quantization";
+
+/// Formats the completion endpoint can respond with, picked from the request's `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompletionFormat {
+    /// A [GenerateResponse] as `application/json`.
+    Json,
+
+    /// Just the completion text as `text/plain`.
+    PlainText,
+}
+
+impl CompletionFormat {
+    /// Use the first media range in the `Accept` header that we can serve, in the order the client
+    /// listed them. Quality values are not taken into account. Defaults to JSON.
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+
+        for media_range in accept.split(',') {
+            let media_type = media_range.split(';').next().unwrap_or_default().trim();
+            match media_type {
+                "text/plain" | "text/*" => return CompletionFormat::PlainText,
+                "application/json" | "application/*" | "*/*" => return CompletionFormat::Json,
+                _ => continue,
+            }
+        }
+
+        CompletionFormat::Json
+    }
+}
 
 #[axum::debug_handler]
+#[utoipa::path(
+    post, path = "/v1/complete", tag = "complete",
+    request_body = GenerateRequest,
+    responses(
+        (status = 200, body = GenerateResponse, description = "A ChoicesResponse instead when `response_format` is `choices`, or the bare completion as `text/plain` when that's preferred by the Accept header, with the model's version and quantization in the x-model-version and x-model-quantization headers"),
+        (status = 400, body = ErrorResponse, description = "The body is an ErrorResponse when a field can't be resolved against the model's vocabulary"),
+        (status = 404),
+        (status = 422, body = ErrorResponse, description = "The model is over its memory limit, or the prompt doesn't fit in its context"),
+        (status = 503, description = "Every generation slot is taken")
+    )
+)]
 pub async fn generate(
     State(app_state): State<AppState>,
+    headers: HeaderMap,
     Json(params): Json<GenerateRequest>,
-) -> Result<Json<GenerateResponse>, StatusCode> {
-    let model = app_state.model;
-    let completion = {
-        let mut model = model.model.lock().await;
-        model.generate(&params.prompt)
+) -> Result<Response, ApiError> {
+    let format = CompletionFormat::negotiate(&headers);
+    let prompt = match &params.continue_from {
+        Some(continue_from) => {
+            continuation_prompt(&app_state, continue_from, &params.prompt).await?
+        }
+        None => params.prompt.clone(),
+    };
+    let continuation_key = params
+        .continue_from
+        .as_ref()
+        .and_then(|continue_from| continue_from.continuation_key.as_deref())
+        .map(|key| parse_continuation_key(key).ok_or(StatusCode::BAD_REQUEST))
+        .transpose()?;
+    let self_consistency = params
+        .self_consistency
+        .as_ref()
+        .map(|vote| {
+            if vote.n == 0 || vote.n > MAX_SELF_CONSISTENCY_N {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let regex = Regex::new(&vote.extract_regex)
+                .context("invalid self-consistency regex")
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            Ok((vote.n, regex))
+        })
+        .transpose()?;
+    if params.top_alternatives > llamacpp::MAX_TOP_ALTERNATIVES {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let slot = acquire_generation_slot(&app_state)?;
+    let inflight = app_state.inflight.register(&params.model_id);
+    let selector = VersionSelector {
+        version: params.version.clone(),
+        quantization: params.quantization.clone(),
     };
+    let model = get_model(&app_state, &params.model_id, &selector).await?;
+    let sampling = llamacpp::SamplingParams::from(model.sampling(params.sampling.clone()));
+    sampling
+        .validate()
+        .context("invalid sampling params")
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let generate_params = GenerateParams {
+        reserve_tokens: params.reserve_tokens,
+        sampling,
+        raw_tokens: params.raw_tokens,
+        shared_prefix_tokens: params.shared_prefix_tokens,
+        continuation_key,
+        control: Some(inflight.control()),
+        timings: params.timings,
+        top_alternatives: params.top_alternatives,
+        ..Default::default()
+    };
+    let logged_prompt = prompt.clone();
+    let logit_bias = params.logit_bias.clone();
+    let guidance = params.guidance.clone();
+    let stop = params.stop.clone();
+    let (completion, vote) = model
+        .lock_for_generation()
+        .await
+        .run_blocking(move |model| {
+            let (_slot, _inflight) = (slot, inflight);
+            let mut logit_bias = resolve_logit_bias(model, &logit_bias)
+                .context("invalid logit bias")
+                .map_err(|err| invalid_request("invalid_logit_bias", err))?;
+            if let Some(guidance) = &guidance {
+                resolve_guidance(model, guidance, &mut logit_bias)
+                    .context("invalid guidance")
+                    .map_err(|err| invalid_request("invalid_guidance", err))?;
+            }
+
+            let (stop_sequences, stop_tokens) = resolve_stops(model, &stop)
+                .context("invalid stop sequence")
+                .map_err(|err| invalid_request("invalid_stop", err))?;
+
+            let generate_params = GenerateParams {
+                logit_bias,
+                stop_sequences,
+                stop_tokens,
+                ..generate_params
+            };
+            let Some((n, regex)) = self_consistency else {
+                return model
+                    .generate(&prompt, &generate_params)
+                    .map(|completion| (completion, None))
+                    .map_err(generation_error);
+            };
+
+            let mut completions = Vec::with_capacity(n as usize);
+            for i in 0..n {
+                let sampling = llamacpp::SamplingParams {
+                    seed: generate_params
+                        .sampling
+                        .seed
+                        .map(|seed| seed.wrapping_add(i)),
+                    ..generate_params.sampling.clone()
+                };
+                let completion = model
+                    .generate(
+                        &prompt,
+                        &GenerateParams {
+                            sampling,
+                            ..generate_params.clone()
+                        },
+                    )
+                    .map_err(generation_error)?;
+                completions.push(completion);
+            }
+
+            let texts: Vec<_> = completions.iter().map(|c| c.text.as_str()).collect();
+            let (vote, winner) = tally_answers(&regex, &texts);
+            // Only the last completion is left in the KV cache to be continued.
+            let last = completions.len() - 1;
+            let mut completion = completions.swap_remove(winner);
+            if winner != last {
+                completion.continuation_key = None;
+            }
+
+            Ok::<_, ApiError>((completion, Some(vote)))
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    app_state
+        .redactor
+        .log_completion(&params.model_id, &logged_prompt, &completion.text);
+
+    if format == CompletionFormat::PlainText {
+        // Caches must not hand this to a client that asked for JSON, or the other way around.
+        return Ok((
+            AppendHeaders([(VARY, "accept")]),
+            served_by(&model),
+            completion.text,
+        )
+            .into_response());
+    }
+
+    if params.response_format == GenerateResponseFormat::Choices {
+        let res = ChoicesResponse {
+            model_id: params.model_id.clone(),
+            version: model.version.clone(),
+            quantization: model.quantization.clone(),
+            choices: vec![CompletionChoice {
+                text: completion.text,
+                index: 0,
+                logprobs: None,
+                finish_reason: completion.finish_reason.into(),
+            }],
+            prompt_truncated: completion.prompt_truncated,
+        };
+
+        return Ok(Json(res).into_response());
+    }
 
     let res = GenerateResponse {
         model_id: params.model_id.clone(),
-        completion,
+        version: model.version.clone(),
+        quantization: model.quantization.clone(),
+        completion: completion.text,
+        finish_reason: completion.finish_reason.into(),
+        prompt_truncated: completion.prompt_truncated,
+        sampling: completion.sampling.into(),
+        timings: completion.timings.map(Timings::from),
+        continuation_key: completion.continuation_key.map(format_continuation_key),
+        cache_reused: completion.cache_reused,
+        self_consistency: vote,
+        top_alternatives: (params.top_alternatives > 0).then(|| {
+            completion
+                .alternatives
+                .into_iter()
+                .map(|step| step.into_iter().map(TokenAlternative::from).collect())
+                .collect()
+        }),
+    };
+
+    Ok(Json(res).into_response())
+}
+
+/// 400 with an [ErrorResponse] giving `code` and the full chain of `err` as its message.
+fn invalid_request(code: &str, err: anyhow::Error) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, code, format!("{:#}", err))
+}
+
+/// Map a failed generation to its response: 422 with the reason for a prompt that doesn't fit in
+/// the model's context, 500 for anything else.
+pub(crate) fn generation_error(err: anyhow::Error) -> ApiError {
+    match err.downcast_ref::<PromptError>() {
+        Some(err) => ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_prompt",
+            err.to_string(),
+        ),
+        None => {
+            error!("failed to generate completion: {:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into()
+        }
+    }
+}
+
+/// The full text for a continued completion: the earlier prompt and its completion, followed by
+/// whatever new `prompt` was sent along.
+async fn continuation_prompt(
+    app_state: &AppState,
+    continue_from: &ContinueFrom,
+    prompt: &str,
+) -> Result<String, StatusCode> {
+    let earlier = match &continue_from.source {
+        ContinuationSource::Completion {
+            prompt: earlier_prompt,
+            completion,
+        } => format!("{}{}", earlier_prompt, completion),
+        ContinuationSource::Experiment { experiment_id } => {
+            let experiment = app_state
+                .db
+                .get_experiment(experiment_id)
+                .await
+                .map_err(|err| match err.downcast_ref::<rusqlite::Error>() {
+                    Some(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND,
+                    _ => {
+                        error!("failed to find experiment to continue: {:#}", err);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                })?;
+            format!("{}{}", experiment.prompt, experiment.output)
+        }
+    };
+
+    Ok(earlier + prompt)
+}
+
+/// Pull the answer out of a completion for a [crate::api_types::SelfConsistency] vote: the first capture group of the
+/// last match of `regex`, or the whole match if it has no groups. Blank answers don't count.
+fn extract_answer(regex: &Regex, completion: &str) -> Option<String> {
+    let captures = regex.captures_iter(completion).last()?;
+    let answer = captures.get(1).or_else(|| captures.get(0))?.as_str().trim();
+
+    (!answer.is_empty()).then(|| answer.to_owned())
+}
+
+/// Count the answers in `completions`, most votes first with ties going to the answer seen first.
+/// Also returns the index of the first completion giving the winning answer, or 0 if none answered.
+fn tally_answers(regex: &Regex, completions: &[&str]) -> (SelfConsistencyResult, usize) {
+    let answers: Vec<_> = completions
+        .iter()
+        .map(|completion| extract_answer(regex, completion))
+        .collect();
+
+    let mut votes: Vec<AnswerVotes> = Vec::new();
+    for answer in answers.iter().flatten() {
+        match votes.iter_mut().find(|votes| &votes.answer == answer) {
+            Some(votes) => votes.votes += 1,
+            None => votes.push(AnswerVotes {
+                answer: answer.clone(),
+                votes: 1,
+            }),
+        }
+    }
+    // Stable, so equal counts stay in the order they were first seen.
+    votes.sort_by_key(|votes| Reverse(votes.votes));
+
+    let answer = votes.first().map(|votes| votes.answer.clone());
+    let winner = answers
+        .iter()
+        .position(|candidate| candidate.is_some() && *candidate == answer)
+        .unwrap_or(0);
+    let unanswered = answers.iter().filter(|answer| answer.is_none()).count() as u32;
+
+    (
+        SelfConsistencyResult {
+            answer,
+            votes,
+            unanswered,
+        },
+        winner,
+    )
+}
+
+/// Continuation keys are sent to clients as 16 hex digits.
+fn format_continuation_key(key: u64) -> String {
+    format!("{:016x}", key)
+}
+
+fn parse_continuation_key(key: &str) -> Option<u64> {
+    u64::from_str_radix(key, 16).ok()
+}
+
+/// Complete one prompt with each of a list of seeds, reporting every seed with its completion so any
+/// variant can be reproduced later.
+#[utoipa::path(
+    post, path = "/v1/complete/sweep", tag = "complete",
+    request_body = SweepRequest,
+    responses(
+        (status = 200, body = SweepResponse),
+        (status = 400),
+        (status = 404),
+        (status = 422, body = ErrorResponse, description = "The model is over its memory limit, or the prompt doesn't fit in its context"),
+        (status = 503)
+    )
+)]
+pub async fn generate_sweep(
+    State(app_state): State<AppState>,
+    Json(params): Json<SweepRequest>,
+) -> Result<Json<SweepResponse>, ApiError> {
+    let seeds = sweep_seeds(&params).ok_or(StatusCode::BAD_REQUEST)?;
+    let selector = VersionSelector {
+        version: params.version.clone(),
+        quantization: params.quantization.clone(),
+    };
+
+    let slot = acquire_generation_slot(&app_state)?;
+    let inflight = app_state.inflight.register(&params.model_id);
+    let control = inflight.control();
+    let model = get_model(&app_state, &params.model_id, &selector).await?;
+    let sampling = llamacpp::SamplingParams::from(model.sampling(params.sampling.clone()));
+    sampling
+        .validate()
+        .context("invalid sampling params")
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let prompt = params.prompt.clone();
+    let completions = model
+        .lock_for_generation()
+        .await
+        .run_blocking(move |model| {
+            let (_slot, _inflight) = (slot, inflight);
+            let mut completions = Vec::with_capacity(seeds.len());
+            for seed in seeds {
+                let completion = model
+                    .generate(
+                        &prompt,
+                        &GenerateParams {
+                            sampling: llamacpp::SamplingParams {
+                                seed: Some(seed),
+                                ..sampling.clone()
+                            },
+                            control: Some(Arc::clone(&control)),
+                            ..Default::default()
+                        },
+                    )
+                    .map_err(generation_error)?;
+
+                completions.push(SweepCompletion {
+                    seed,
+                    completion: completion.text,
+                    finish_reason: completion.finish_reason.into(),
+                });
+            }
+
+            Ok::<_, ApiError>(completions)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    for completion in &completions {
+        app_state
+            .redactor
+            .log_completion(&params.model_id, &params.prompt, &completion.completion);
+    }
+
+    Ok(Json(SweepResponse {
+        model_id: params.model_id,
+        version: model.version.clone(),
+        quantization: model.quantization.clone(),
+        completions,
+    }))
+}
+
+/// Seeds a sweep asked for, or `None` if the request doesn't give exactly one of `seeds` and `count`,
+/// or asks for too many.
+fn sweep_seeds(params: &SweepRequest) -> Option<Vec<u32>> {
+    let seeds: Vec<u32> = match (&params.seeds, params.count) {
+        (Some(seeds), None) => seeds.clone(),
+        (None, Some(count)) => {
+            let first = params
+                .sampling
+                .as_ref()
+                .and_then(|sampling| sampling.seed)
+                .unwrap_or(0);
+            (0..count.min(MAX_SWEEP_SEEDS as u32 + 1))
+                .map(|i| first.wrapping_add(i))
+                .collect()
+        }
+        _ => return None,
+    };
+
+    (!seeds.is_empty() && seeds.len() <= MAX_SWEEP_SEEDS).then_some(seeds)
+}
+
+impl From<llamacpp::FinishReason> for FinishReason {
+    fn from(reason: llamacpp::FinishReason) -> Self {
+        match reason {
+            llamacpp::FinishReason::Stop => FinishReason::Stop,
+            llamacpp::FinishReason::Length => FinishReason::Length,
+            llamacpp::FinishReason::Cancelled => FinishReason::Cancelled,
+        }
+    }
+}
+
+impl From<llamacpp::TokenAlternative> for TokenAlternative {
+    fn from(alternative: llamacpp::TokenAlternative) -> Self {
+        Self {
+            token_id: alternative.token,
+            text: alternative.text,
+            probability: alternative.probability,
+        }
+    }
+}
+
+impl From<llamacpp::Timings> for Timings {
+    fn from(timings: llamacpp::Timings) -> Self {
+        Self {
+            prompt_eval_ms: timings.prompt_eval_ms,
+            prompt_tokens: timings.n_prompt_eval,
+            eval_ms: timings.eval_ms,
+            eval_tokens: timings.n_eval,
+            sample_ms: timings.sample_ms,
+            token_ms: timings.token_ms,
+        }
+    }
+}
+
+impl From<SamplingParams> for llamacpp::SamplingParams {
+    fn from(params: SamplingParams) -> Self {
+        Self {
+            seed: params.seed,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            repeat_penalty: params.repeat_penalty,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
+        }
+    }
+}
+
+impl From<llamacpp::SamplingParams> for SamplingParams {
+    fn from(params: llamacpp::SamplingParams) -> Self {
+        Self {
+            seed: params.seed,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            repeat_penalty: params.repeat_penalty,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
+        }
+    }
+}
+
+/// Claim one of the server-wide generation slots, see [crate::state::GenerationLimiter]. Rejects the
+/// request with 503 when they're all taken, so clients back off instead of piling up.
+pub(crate) fn acquire_generation_slot(
+    app_state: &AppState,
+) -> Result<OwnedSemaphorePermit, StatusCode> {
+    app_state
+        .generations
+        .try_acquire()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Get a version of a model from the pool, loading it if needed. A model too big for its memory limit
+/// is rejected with 422 and a `memory_limit_exceeded` error carrying its estimated footprint.
+pub(crate) async fn get_model(
+    app_state: &AppState,
+    model_name: &str,
+    selector: &VersionSelector,
+) -> Result<Arc<ManagedModel>, ApiError> {
+    app_state
+        .pool
+        .get(model_name, selector)
+        .await
+        .map_err(|err| match err.downcast_ref::<PoolError>() {
+            Some(PoolError::ModelNotFound | PoolError::VersionNotFound) => {
+                StatusCode::NOT_FOUND.into()
+            }
+            // Loading it would run the server out of memory, which retrying won't change.
+            Some(PoolError::MemoryLimitExceeded(exceeded)) => {
+                let mut body = ErrorResponse::new("memory_limit_exceeded", exceeded.to_string());
+                body.error.memory = Some(MemoryLimit {
+                    estimated_bytes: exceeded.estimated_bytes,
+                    limit_bytes: exceeded.limit_bytes,
+                });
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into()
+            }
+            None => {
+                error!("failed to load model {}: {:#}", model_name, err);
+                StatusCode::INTERNAL_SERVER_ERROR.into()
+            }
+        })
+}
+
+/// Map logit bias entries onto token IDs, tokenizing the text-keyed ones. Text must tokenize to exactly
+/// one token. Biases for the same token add up.
+fn resolve_logit_bias(
+    model: &mut llamacpp::Model,
+    logit_bias: &[LogitBias],
+) -> anyhow::Result<HashMap<i32, f32>> {
+    let mut resolved = HashMap::new();
+    for entry in logit_bias {
+        let token_id = match &entry.token {
+            LogitBiasToken::Id { token_id } => {
+                if *token_id < 0 || *token_id as u32 >= model.n_vocab() {
+                    anyhow::bail!("token ID {} is not in the vocabulary", token_id);
+                }
+                *token_id
+            }
+            LogitBiasToken::Text { text } => match model.tokenize(text)?.as_slice() {
+                [token_id] => *token_id,
+                tokens => anyhow::bail!(
+                    "{:?} tokenizes to {} tokens, expected exactly one",
+                    text,
+                    tokens.len()
+                ),
+            },
+        };
+        *resolved.entry(token_id).or_default() += entry.bias;
+    }
+
+    Ok(resolved)
+}
+
+/// Add the bias [Guidance] puts on the tokens of its reference text to `logit_bias`.
+fn resolve_guidance(
+    model: &mut llamacpp::Model,
+    guidance: &Guidance,
+    logit_bias: &mut HashMap<i32, f32>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(guidance.scale.is_finite(), "scale must be finite");
+    anyhow::ensure!(!guidance.text.is_empty(), "reference text is empty");
+
+    let tokens = model.tokenize(&guidance.text)?;
+    apply_guidance(logit_bias, &tokens, guidance.scale);
+
+    Ok(())
+}
+
+/// Add `scale` to the bias of every distinct token in `tokens`. A token that comes up often in the
+/// reference gets the same boost as one that comes up once, so repetition in the reference doesn't
+/// push the completion into repeating it.
+fn apply_guidance(logit_bias: &mut HashMap<i32, f32>, tokens: &[i32], scale: f32) {
+    let distinct: HashSet<i32> = tokens.iter().copied().collect();
+    for token_id in distinct {
+        *logit_bias.entry(token_id).or_default() += scale;
+    }
+}
+
+/// Split stop sequences into the text ones, matched against the completion, and token IDs, matched
+/// against the sampled tokens. Named tokens are looked up in the vocabulary, falling back to the
+/// single token their text tokenizes to.
+fn resolve_stops(
+    model: &mut llamacpp::Model,
+    stop: &[StopSequence],
+) -> anyhow::Result<(Vec<String>, HashSet<i32>)> {
+    let mut stop_sequences = Vec::new();
+    let mut stop_tokens = HashSet::new();
+    for entry in stop {
+        match entry {
+            StopSequence::Text(text) => {
+                if text.is_empty() {
+                    anyhow::bail!("stop sequences can't be empty");
+                }
+                stop_sequences.push(text.clone());
+            }
+            StopSequence::TokenId { token_id } => {
+                if *token_id < 0 || *token_id as u32 >= model.n_vocab() {
+                    anyhow::bail!("token ID {} is not in the vocabulary", token_id);
+                }
+                stop_tokens.insert(*token_id);
+            }
+            StopSequence::Token { token } => {
+                let token_id = match model.find_token(token) {
+                    Some(token_id) => token_id,
+                    None => match model.tokenize(token)?.as_slice() {
+                        [token_id] => *token_id,
+                        _ => anyhow::bail!("{:?} is not a token in the vocabulary", token),
+                    },
+                };
+                stop_tokens.insert(token_id);
+            }
+        }
+    }
+
+    Ok((stop_sequences, stop_tokens))
+}
+
+/// Stream completions for a batch of prompts back over SSE. See [BatchStreamEvent] for the wire format.
+///
+/// Every event goes out as its own body chunk, so it's written to the socket as soon as it's produced.
+/// Proxies can still hold on to the chunks, so the response asks nginx-style proxies not to buffer it,
+/// and sends `: keepalive` comments while the stream is otherwise quiet, see [crate::state::StreamConfig].
+#[axum::debug_handler]
+#[utoipa::path(
+    post, path = "/v1/complete/batch/stream", tag = "complete",
+    request_body = BatchGenerateRequest,
+    responses(
+        (status = 200, body = BatchStreamEvent, content_type = "text/event-stream", description = "One event per SSE message. The model's version and quantization are sent in the x-model-version and x-model-quantization headers"),
+        (status = 404),
+        (status = 422, body = ErrorResponse, description = "The model is over its memory limit"),
+        (status = 503)
+    )
+)]
+pub async fn generate_batch_stream(
+    State(app_state): State<AppState>,
+    Json(params): Json<BatchGenerateRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (sender, receiver) = channel(128);
+    let selector = VersionSelector {
+        version: params.version.clone(),
+        quantization: params.quantization.clone(),
     };
+    let slot = acquire_generation_slot(&app_state)?;
+    let inflight = app_state.inflight.register(&params.model_id);
+    let model = get_model(&app_state, &params.model_id, &selector).await?;
+    let served_by = served_by(&model);
+
+    let generate_params = GenerateParams {
+        sampling: model.sampling(None).into(),
+        raw_bytes: params.raw_bytes,
+        raw_tokens: params.raw_tokens,
+        control: Some(inflight.control()),
+        ..Default::default()
+    };
+    let runtime = Handle::current();
+    tokio::spawn(async move {
+        // The model owns a single context, so the prompts are completed one after another.
+        let result = model
+            .lock_for_generation()
+            .await
+            .run_blocking(move |model| {
+                let (_slot, _inflight) = (slot, inflight);
+                for (index, prompt) in params.prompts.iter().enumerate() {
+                    // Generation runs on this thread, and hands its tokens to a task on the runtime
+                    // that forwards them to the client.
+                    let (token_sender, token_receiver) = channel(16);
+                    let forward =
+                        runtime.spawn(forward_tokens(index, token_receiver, sender.clone()));
+                    let result = runtime.block_on(model.generate_stream(
+                        prompt,
+                        &generate_params,
+                        token_sender,
+                    ));
+                    let _ = runtime.block_on(forward);
+                    if let Err(err) = result {
+                        // Still close out the item, so the client isn't left waiting on it.
+                        error!("batch generation failed for index={}: {:#}", index, err);
+                        let done = BatchStreamEvent::Done { index };
+                        if runtime.block_on(send_event(&sender, &done)).is_err() {
+                            return;
+                        }
+                    }
+
+                    if sender.is_closed() {
+                        return;
+                    }
+                }
+            })
+            .await;
+        if let Err(err) = result {
+            error!("batch generation failed: {:#}", err);
+        }
+    });
+
+    let mut sse = Sse::new(ReceiverStream::new(receiver));
+    if let Some(interval) = app_state.stream_config.heartbeat_interval {
+        sse = sse.keep_alive(KeepAlive::new().interval(interval).text(" keepalive"));
+    }
+
+    Ok((AppendHeaders([(X_ACCEL_BUFFERING, "no")]), served_by, sse))
+}
+
+/// [X_MODEL_VERSION] and [X_MODEL_QUANTIZATION] headers for `model`.
+fn served_by(model: &ManagedModel) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Ok(version) = HeaderValue::from_str(&model.version.to_string()) {
+        headers.insert(X_MODEL_VERSION, version);
+    }
+    if let Some(Ok(quantization)) = model.quantization.as_deref().map(HeaderValue::from_str) {
+        headers.insert(X_MODEL_QUANTIZATION, quantization);
+    }
+
+    headers
+}
 
-    Ok(Json(res))
+/// Forward the tokens generated for the prompt at `index` to the client as [BatchStreamEvent]s.
+/// Owns the token receiver, so when the client goes away it's dropped as soon as sending fails,
+/// which stops the generation instead of leaving it blocked on a full channel.
+async fn forward_tokens(
+    index: usize,
+    mut token_receiver: Receiver<StreamMessage>,
+    sender: Sender<Result<Event, Infallible>>,
+) {
+    while let Some(msg) = token_receiver.recv().await {
+        let event = match msg {
+            StreamMessage::NextToken(token) => BatchStreamEvent::Token { index, token },
+            StreamMessage::NextTokenBytes(bytes) => BatchStreamEvent::TokenBytes { index, bytes },
+            StreamMessage::Done => BatchStreamEvent::Done { index },
+        };
+        if send_event(&sender, &event).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn send_event(
+    sender: &Sender<Result<Event, Infallible>>,
+    event: &BatchStreamEvent,
+) -> anyhow::Result<()> {
+    let event = Event::default().json_data(event)?;
+    sender.send(Ok(event)).await?;
+
+    Ok(())
 }
 
 // New websocket
@@ -86,3 +879,228 @@ pub async fn generate(
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Duration};
+
+    use axum::{
+        http::{header::ACCEPT, HeaderMap, HeaderValue, StatusCode},
+        response::IntoResponse,
+    };
+    use llamacpp::{PromptError, StreamMessage};
+    use tokio::sync::mpsc::channel;
+
+    use crate::api_types::{
+        ContinuationSource, ContinueFrom, GenerateRequest, SamplingParams, SweepRequest,
+    };
+
+    use regex::Regex;
+
+    use super::{
+        apply_guidance, extract_answer, format_continuation_key, forward_tokens, generation_error,
+        parse_continuation_key, sweep_seeds, tally_answers, CompletionFormat, MAX_SWEEP_SEEDS,
+    };
+
+    fn negotiate(accept: Option<&'static str>) -> CompletionFormat {
+        let mut headers = HeaderMap::new();
+        if let Some(accept) = accept {
+            headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        }
+
+        CompletionFormat::negotiate(&headers)
+    }
+
+    #[test]
+    fn test_apply_guidance() {
+        let mut logit_bias = HashMap::from([(7, -100.0), (13, 1.5)]);
+        apply_guidance(&mut logit_bias, &[13, 42, 13, 13, 99], 2.0);
+
+        // Repeated tokens are only boosted once, and add up with the explicit biases.
+        assert_eq!(
+            logit_bias,
+            HashMap::from([(7, -100.0), (13, 3.5), (42, 2.0), (99, 2.0)])
+        );
+
+        apply_guidance(&mut logit_bias, &[42], -2.0);
+        assert_eq!(logit_bias[&42], 0.0);
+    }
+
+    #[test]
+    fn test_negotiate_completion_format() {
+        assert_eq!(negotiate(None), CompletionFormat::Json);
+        assert_eq!(negotiate(Some("*/*")), CompletionFormat::Json);
+        assert_eq!(negotiate(Some("text/plain")), CompletionFormat::PlainText);
+        assert_eq!(
+            negotiate(Some(
+                "text/html, text/plain;charset=utf-8, application/json"
+            )),
+            CompletionFormat::PlainText
+        );
+        assert_eq!(
+            negotiate(Some("application/json, text/plain")),
+            CompletionFormat::Json
+        );
+        assert_eq!(negotiate(Some("image/png")), CompletionFormat::Json);
+    }
+
+    #[test]
+    fn test_sweep_seeds() {
+        let request =
+            |seeds: Option<Vec<u32>>, count: Option<u32>, seed: Option<u32>| SweepRequest {
+                model_id: "my-model".to_owned(),
+                prompt: "Once upon a time".to_owned(),
+                version: None,
+                quantization: None,
+                seeds,
+                count,
+                sampling: Some(SamplingParams {
+                    seed,
+                    ..Default::default()
+                }),
+            };
+
+        assert_eq!(
+            sweep_seeds(&request(Some(vec![7, 3]), None, None)),
+            Some(vec![7, 3])
+        );
+        assert_eq!(
+            sweep_seeds(&request(None, Some(3), None)),
+            Some(vec![0, 1, 2])
+        );
+        assert_eq!(
+            sweep_seeds(&request(None, Some(2), Some(40))),
+            Some(vec![40, 41])
+        );
+
+        assert_eq!(sweep_seeds(&request(None, None, None)), None);
+        assert_eq!(sweep_seeds(&request(Some(vec![1]), Some(1), None)), None);
+        assert_eq!(sweep_seeds(&request(Some(vec![]), None, None)), None);
+        assert_eq!(
+            sweep_seeds(&request(None, Some(MAX_SWEEP_SEEDS as u32 + 1), None)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_continue_from() {
+        let request: GenerateRequest = serde_json::from_str(
+            r#"{"model_id": "m", "continue_from": {"prompt": "Once", "completion": " upon", "continuation_key": "00000000000000ff"}}"#,
+        )
+        .unwrap();
+        assert_eq!(request.prompt, "");
+        assert_eq!(
+            request.continue_from,
+            Some(ContinueFrom {
+                source: ContinuationSource::Completion {
+                    prompt: "Once".to_owned(),
+                    completion: " upon".to_owned(),
+                },
+                continuation_key: Some("00000000000000ff".to_owned()),
+            })
+        );
+
+        let request: GenerateRequest = serde_json::from_str(
+            r#"{"model_id": "m", "prompt": " a time", "continue_from": {"experiment_id": "67e55044-10b1-426f-9247-bb680e5fe0c8"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            request.continue_from.unwrap().source,
+            ContinuationSource::Experiment { .. }
+        ));
+
+        assert_eq!(format_continuation_key(255), "00000000000000ff");
+        for key in [0, 255, u64::MAX] {
+            assert_eq!(
+                parse_continuation_key(&format_continuation_key(key)),
+                Some(key)
+            );
+        }
+        assert_eq!(parse_continuation_key("not a key"), None);
+    }
+
+    #[test]
+    fn test_self_consistency_vote() {
+        let regex = Regex::new(r"answer is (\d+)").unwrap();
+        assert_eq!(
+            extract_answer(&regex, "answer is 3, no wait, the answer is 4"),
+            Some("4".to_owned())
+        );
+        assert_eq!(extract_answer(&regex, "no idea"), None);
+        // Without a capture group the whole match is the answer.
+        let whole = Regex::new(r"\d+").unwrap();
+        assert_eq!(extract_answer(&whole, "about 12 "), Some("12".to_owned()));
+
+        let (vote, winner) = tally_answers(
+            &regex,
+            &[
+                "the answer is 5",
+                "hmm",
+                "so the answer is 7",
+                "the answer is 7",
+                "answer is 5",
+            ],
+        );
+        // 5 and 7 tie, and 5 came up first.
+        assert_eq!(vote.answer.as_deref(), Some("5"));
+        assert_eq!(winner, 0);
+        assert_eq!(vote.votes.len(), 2);
+        assert_eq!(vote.votes[1].answer, "7");
+        assert_eq!(vote.votes[1].votes, 2);
+        assert_eq!(vote.unanswered, 1);
+
+        let (vote, winner) =
+            tally_answers(&regex, &["x", "answer is 1", "answer is 2", "answer is 2"]);
+        assert_eq!(vote.answer.as_deref(), Some("2"));
+        assert_eq!(winner, 2);
+
+        let (vote, winner) = tally_answers(&regex, &["x", "y"]);
+        assert_eq!(vote.answer, None);
+        assert!(vote.votes.is_empty());
+        assert_eq!(vote.unanswered, 2);
+        assert_eq!(winner, 0);
+    }
+
+    #[test]
+    fn test_generation_error() {
+        let too_long = anyhow::Error::from(PromptError::TooLong {
+            n_tokens: 600,
+            n_ctx: 512,
+        })
+        .context("failed to generate completion");
+        assert_eq!(
+            generation_error(too_long).into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            generation_error(anyhow::anyhow!("llama_eval returned non-zero"))
+                .into_response()
+                .status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_tokens_releases_generation_on_disconnect() {
+        let (sender, receiver) = channel(1);
+        let (token_sender, token_receiver) = channel(1);
+        let forward = tokio::spawn(forward_tokens(0, token_receiver, sender));
+
+        // Stands in for the generation, which keeps sending until the receiver hangs up.
+        let generation = tokio::spawn(async move {
+            while token_sender
+                .send(StreamMessage::NextToken("a".into()))
+                .await
+                .is_ok()
+            {}
+        });
+
+        // The client disconnects while tokens are still coming.
+        drop(receiver);
+        tokio::time::timeout(Duration::from_secs(5), generation)
+            .await
+            .expect("generation stayed blocked after the client disconnected")
+            .unwrap();
+        forward.await.unwrap();
+    }
+}
@@ -7,6 +7,14 @@ use time::OffsetDateTime;
 use crate::api_types::{HFFile, ListHFFilesResponse};
 
 #[axum::debug_handler]
+#[utoipa::path(
+    get, path = "/hf/ls/{community}/{repo_name}", tag = "hfhub",
+    params(
+        ("community" = String, Path, description = "Owner of the Hugging Face repo"),
+        ("repo_name" = String, Path, description = "Name of the Hugging Face repo")
+    ),
+    responses((status = 200, body = ListHFFilesResponse))
+)]
 pub async fn ls_repo_files(
     Path((community, repo_name)): Path<(String, String)>,
 ) -> Result<Json<ListHFFilesResponse>, StatusCode> {
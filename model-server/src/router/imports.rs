@@ -1,41 +1,51 @@
 use crate::{
-    api_types::{GetAllJobStatusResponse, ImportJob, ImportJobId, ImportJobStatus, Locator},
+    api_types::{
+        CancelAllImportsResponse, GetAllJobStatusResponse, ImportJob, ImportJobId, ImportJobStatus,
+        ImportJobsQuery, Locator,
+    },
+    import::ImportError,
     state::AppState,
 };
 use anyhow::Context;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     Json,
 };
 
 #[axum::debug_handler]
+#[utoipa::path(
+    post, path = "/v1/imports", tag = "imports",
+    request_body = Locator,
+    responses(
+        (status = 200, body = uuid::Uuid, description = "ID of the new import job"),
+        (status = 409, description = "Another job importing from the same source is still running")
+    )
+)]
 pub async fn import_model(
     State(app_state): State<AppState>,
     Json(locator): Json<Locator>,
 ) -> Result<Json<ImportJobId>, StatusCode> {
-    let import_job = match locator {
-        Locator::DISK(disk_locator) => ImportJob::DISK {
-            locator: disk_locator,
-        },
-        Locator::HF(hf_locator) => ImportJob::HF {
-            locator: hf_locator,
-        },
-    };
-
-    let result = {
-        let importer = app_state.importer;
-        importer.start_import(import_job).await
-    };
+    let import_job = ImportJob::from(locator);
 
-    let job_id = result.unwrap();
-    // .context("failed to start import")
-    // .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let job_id = app_state
+        .importer
+        .start_import(import_job)
+        .await
+        .map_err(|err| match err.downcast_ref::<ImportError>() {
+            Some(ImportError::DuplicateJob) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
 
     Ok(Json(job_id))
 }
 
 #[axum::debug_handler]
+#[utoipa::path(
+    get, path = "/v1/imports/{job_id}", tag = "imports",
+    params(("job_id" = uuid::Uuid, Path, description = "ID of the import job")),
+    responses((status = 200, body = ImportJobStatus))
+)]
 pub async fn import_job_status(
     Path(job_id): Path<ImportJobId>,
     State(app_state): State<AppState>,
@@ -50,15 +60,107 @@ pub async fn import_job_status(
     Ok(Json(task_status))
 }
 
+#[utoipa::path(
+    get, path = "/v1/imports", tag = "imports",
+    params(ImportJobsQuery),
+    responses((status = 200, body = GetAllJobStatusResponse), (status = 400))
+)]
 pub async fn import_job_status_all(
     State(app_state): State<AppState>,
+    Query(query): Query<ImportJobsQuery>,
 ) -> Result<Json<GetAllJobStatusResponse>, StatusCode> {
-    let import_jobs = app_state
+    let import_jobs = match query.source {
+        Some(source) => {
+            let locator = serde_json::from_str::<Locator>(&source)
+                .context("failed to parse source locator")
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+            app_state
+                .importer
+                .find_jobs_by_source(&locator)
+                .await
+                .context("failed to find import jobs by source")
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .into_iter()
+                .collect()
+        }
+        None => app_state
+            .importer
+            .get_all_job_status()
+            .await
+            .context("failed to retrieve import job status")
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+
+    Ok(Json(GetAllJobStatusResponse { import_jobs }))
+}
+
+/// Cancel all queued and in-progress imports, e.g. before shutting down for maintenance.
+#[utoipa::path(
+    delete, path = "/v1/imports", tag = "imports",
+    responses((status = 200, body = CancelAllImportsResponse))
+)]
+pub async fn cancel_all_imports(
+    State(app_state): State<AppState>,
+) -> Result<Json<CancelAllImportsResponse>, StatusCode> {
+    let cancelled = app_state
         .importer
-        .get_all_job_status()
+        .cancel_all()
         .await
-        .context("failed to retrieve import job status")
+        .context("failed to cancel import jobs")
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(GetAllJobStatusResponse { import_jobs }))
+    Ok(Json(CancelAllImportsResponse { cancelled }))
+}
+
+/// Cancel a queued or in-progress import.
+#[utoipa::path(
+    delete, path = "/v1/imports/{job_id}", tag = "imports",
+    params(("job_id" = uuid::Uuid, Path, description = "ID of the import job")),
+    responses(
+        (status = 204),
+        (status = 404),
+        (status = 409, description = "The job has already finished")
+    )
+)]
+pub async fn cancel_import(
+    Path(job_id): Path<ImportJobId>,
+    State(app_state): State<AppState>,
+) -> StatusCode {
+    match app_state.importer.cancel(&job_id).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => match err.downcast_ref::<ImportError>() {
+            Some(ImportError::JobNotFound) => StatusCode::NOT_FOUND,
+            Some(ImportError::InvalidJobState | ImportError::DuplicateJob) => StatusCode::CONFLICT,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    }
+}
+
+/// Re-run a failed or cancelled import as a new job, returning the ID of the new job.
+#[axum::debug_handler]
+#[utoipa::path(
+    post, path = "/v1/imports/{job_id}/retry", tag = "imports",
+    params(("job_id" = uuid::Uuid, Path, description = "ID of the import job")),
+    responses(
+        (status = 200, body = uuid::Uuid, description = "ID of the new import job"),
+        (status = 404),
+        (status = 409, description = "The job hasn't failed or been cancelled, or another job importing from the same source is still running")
+    )
+)]
+pub async fn retry_import(
+    Path(job_id): Path<ImportJobId>,
+    State(app_state): State<AppState>,
+) -> Result<Json<ImportJobId>, StatusCode> {
+    let retry_id = app_state
+        .importer
+        .retry_import(&job_id)
+        .await
+        .map_err(|err| match err.downcast_ref::<ImportError>() {
+            Some(ImportError::JobNotFound) => StatusCode::NOT_FOUND,
+            Some(ImportError::InvalidJobState | ImportError::DuplicateJob) => StatusCode::CONFLICT,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    Ok(Json(retry_id))
 }
@@ -0,0 +1,20 @@
+use axum::{extract::State, Json};
+
+use crate::{
+    api_types::{GenerationMetrics, MetricsResponse},
+    state::AppState,
+};
+
+/// Server-wide utilization, for dashboards and autoscalers. Per-model numbers are under
+/// `/v1/models/:model_name/load-stats`.
+pub async fn get_metrics(State(app_state): State<AppState>) -> Json<MetricsResponse> {
+    let generations = &app_state.generations;
+
+    Json(MetricsResponse {
+        generations: GenerationMetrics {
+            limit: generations.limit(),
+            in_flight: generations.in_flight(),
+            rejected: generations.rejected(),
+        },
+    })
+}
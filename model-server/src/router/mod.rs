@@ -1,28 +1,126 @@
 use axum::{
+    http::{HeaderName, Method, Request, StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Json, Router,
 };
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tracing::Level;
 
-use crate::state::AppState;
+use crate::{api_types::ErrorResponse, state::AppState};
+use cors::{with_cors, CorsConfig};
 
+pub mod admin;
+pub mod chat;
+pub mod cors;
+pub mod encoding;
+pub mod experiments;
 pub mod generate;
 pub mod hfhub;
 pub mod imports;
+pub mod metrics;
 pub mod models;
+pub mod openapi;
+pub mod runtimes;
+pub mod tokenize;
 
 async fn healthz() -> Json<String> {
     Json("healthy".to_string())
 }
 
+/// Error of handlers that can fail with an [ErrorResponse] body as well as with a bare status code,
+/// which converts into it with `?`. Boxed, so results carrying it stay small.
+pub struct ApiError(Box<Response>);
+
+impl ApiError {
+    /// An [ErrorResponse] with `code` and `message`, sent with `status`.
+    pub fn new(status: StatusCode, code: &str, message: impl Into<String>) -> Self {
+        (status, Json(ErrorResponse::new(code, message))).into()
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        Self(Box::new(status.into_response()))
+    }
+}
+
+impl From<(StatusCode, Json<ErrorResponse>)> for ApiError {
+    fn from(error: (StatusCode, Json<ErrorResponse>)) -> Self {
+        Self(Box::new(error.into_response()))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        *self.0
+    }
+}
+
+/// Fallback for requests that don't match any route.
+async fn not_found(method: Method, uri: Uri) -> (StatusCode, Json<ErrorResponse>) {
+    let mut body = ErrorResponse::new(
+        "not_found",
+        format!("no route for {} {}", method, uri.path()),
+    );
+    body.error.path = Some(uri.path().to_owned());
+
+    (StatusCode::NOT_FOUND, Json(body))
+}
+
+/// Settings for the middleware wrapped around every route.
+#[derive(Debug, Clone)]
+pub struct RouterConfig {
+    /// Header carrying the request ID. Taken from the request if the client set it, generated
+    /// otherwise, recorded on the request's tracing span and echoed back in the response.
+    pub request_id_header: HeaderName,
+
+    pub cors: CorsConfig,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            request_id_header: HeaderName::from_static("x-request-id"),
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
 /// Main router for the application, with all API and health endpoints attached
-pub fn app_router() -> Router<AppState> {
-    Router::new()
+pub fn app_router(config: &RouterConfig) -> Router<AppState> {
+    let request_id_header = config.request_id_header.clone();
+    let make_span = move |request: &Request<_>| {
+        let request_id = request
+            .headers()
+            .get(&request_id_header)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            request_id,
+        )
+    };
+
+    let router = Router::new()
         .route("/healthz", get(healthz))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/v1/runtimes", get(runtimes::list_runtimes))
+        .route("/v1/metrics", get(metrics::get_metrics))
         //
         // CRUD operations on models and versions
         //
         .route("/v1/models", get(models::get_models))
+        .route("/v1/models/bulk", post(models::register_models))
+        .route("/v1/models/orphans", get(models::get_orphans))
         .route(
             "/v1/models/:model_name/description",
             get(models::get_model_description),
@@ -33,31 +131,129 @@ pub fn app_router() -> Router<AppState> {
         )
         .route("/v1/models/:model_name/name", post(models::rename_model))
         .route("/v1/models/:model_name", delete(models::delete_model))
+        .route(
+            "/v1/models/:model_name/load-stats",
+            get(models::get_load_stats),
+        )
+        .route("/v1/models/:model_name/vocab", get(models::get_vocab))
+        .route(
+            "/v1/models/:model_name/capabilities",
+            get(models::get_model_capabilities),
+        )
+        .route(
+            "/v1/models/:model_name/default-quantization",
+            put(models::set_default_quantization),
+        )
+        .route(
+            "/v1/models/:model_name/max-versions",
+            put(models::set_max_versions),
+        )
+        .route(
+            "/v1/models/:model_name/max-memory",
+            put(models::set_max_memory),
+        )
         .route(
             "/v1/models/:model_name/versions/:version",
             delete(models::delete_model_version),
         )
+        .route(
+            "/v1/models/:model_name/versions/:version/rename",
+            post(models::rename_model_version),
+        )
+        .route(
+            "/v1/models/:model_name/versions/:version/params",
+            get(models::get_model_version_params).put(models::update_model_version_params),
+        )
         //
         // ML model execution
         //
         .route("/v1/complete", post(generate::generate))
+        .route(
+            "/v1/complete/batch/stream",
+            post(generate::generate_batch_stream),
+        )
+        .route("/v1/complete/sweep", post(generate::generate_sweep))
+        .route("/v1/chat/render", post(chat::render_chat))
+        .route("/v1/tokenize/preview", post(tokenize::preview_tokens))
+        .route("/v1/tokenize/count", post(tokenize::count_tokens))
+        //
+        // Saved experiments
+        //
+        .route("/v1/experiments", post(experiments::save_experiment))
+        .route(
+            "/v1/experiments/:id/replay",
+            post(experiments::replay_experiment),
+        )
         //
         // Import flow
         //
         .route("/v1/imports", post(imports::import_model))
         .route("/v1/imports", get(imports::import_job_status_all))
-        .route("/v1/imports/:job_id", get(imports::import_job_status))
+        .route("/v1/imports", delete(imports::cancel_all_imports))
+        .route(
+            "/v1/imports/:job_id",
+            get(imports::import_job_status).delete(imports::cancel_import),
+        )
+        .route("/v1/imports/:job_id/retry", post(imports::retry_import))
         //
         // HF Browser endpoint for import flow
         //
         .route("/hf/ls/:community/:repo_name", get(hfhub::ls_repo_files))
         //
-        // Enable all of the CORS flags
+        // Maintenance
+        //
+        .route("/admin/vacuum", post(admin::vacuum_db))
+        .fallback(not_found)
+        //
+        // MessagePack for clients that ask for it, see [encoding]
+        //
+        .layer(middleware::from_fn(encoding::negotiate_encoding))
+        //
+        // Tracing, with the request ID set before the span is created so the span can record it
         //
+        .layer(PropagateRequestIdLayer::new(
+            config.request_id_header.clone(),
+        ))
         .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_headers(Any)
-                .allow_methods(Any),
+            TraceLayer::new_for_http()
+                .make_span_with(make_span)
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Micros),
+                ),
         )
+        .layer(SetRequestIdLayer::new(
+            config.request_id_header.clone(),
+            MakeRequestUuid,
+        ));
+
+    // Outermost, so preflights and rejected origins never reach the routes or their tracing.
+    with_cors(
+        router,
+        &config.cors,
+        std::slice::from_ref(&config.request_id_header),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        http::{Method, StatusCode, Uri},
+        Json,
+    };
+
+    use super::not_found;
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let (status, Json(body)) =
+            not_found(Method::GET, Uri::from_static("/v2/models?page=2")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            r#"{"error":{"code":"not_found","message":"no route for GET /v2/models","path":"/v2/models"}}"#
+        );
+    }
 }
@@ -1,19 +1,81 @@
-use crate::{api_types::GetRegisteredModelsResponse, state::AppState};
+use std::{
+    fs::File,
+    io::{self, Read},
+    sync::Arc,
+};
+
+use crate::{
+    api_types::{
+        Acceleration, BulkRegisterRequest, BulkRegisterResponse, BulkRegisterResult,
+        CapabilitiesQuery, CompletionModelParams, DiskLocator, ErrorResponse, FieldError,
+        GetRegisteredModelsResponse, ImportMetadata, ImportSource, LoadStatsResponse,
+        ModelCapabilities, ModelParams, ModelType, OrphansResponse, RegisterModelRequest,
+        RenameVersionRequest, Runtime, SetDefaultQuantizationRequest, SetMaxMemoryRequest,
+        SetMaxVersionsRequest, VocabQuery, VocabResponse, VocabToken,
+    },
+    capabilities,
+    db::tables::{DBError, DB},
+    orphans,
+    quantization::{read_quantization, select_version, VersionSelector},
+    router::{generate::get_model, ApiError},
+    state::AppState,
+};
 use anyhow::Context;
 use axum::{
-    body::HttpBody,
-    extract::{Path, RawBody, State},
-    http::StatusCode,
+    body::{Bytes, HttpBody, StreamBody},
+    extract::{Path, Query, RawBody, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
     Json,
 };
+use llamacpp::{gguf::GgufMetadata, system};
+use log::error;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Magic bytes at the start of every GGUF file.
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Media type of the streamed model list.
+const NDJSON: &str = "application/x-ndjson";
+
+/// Models read from the DB at a time when streaming the model list.
+const MODEL_STREAM_PAGE: usize = 256;
 
+/// Largest page of tokens [get_vocab] will return.
+const MAX_VOCAB_PAGE: u32 = 10_000;
+
+/// List every registered model. Clients that send `Accept: application/x-ndjson` get the models
+/// streamed back one per line instead, see [stream_models].
+#[utoipa::path(
+    get, path = "/v1/models", tag = "models",
+    responses((
+        status = 200,
+        body = GetRegisteredModelsResponse,
+        description = "Or one RegisteredModel per line as `application/x-ndjson`, when that's preferred by the Accept header"
+    ))
+)]
 pub async fn get_models(
     State(AppState {
         db,
-        model: _,
+        pool: _,
         importer: _,
+        stream_config: _,
+        descriptions: _,
+        generations: _,
+        inflight: _,
+        redactor: _,
+        models_dir: _,
     }): State<AppState>,
-) -> Result<Json<GetRegisteredModelsResponse>, StatusCode> {
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if wants_ndjson(&headers) {
+        return Ok(stream_models(db));
+    }
+
     // TODO(aduffy): use central error type in the BE that can map back to StatusCode easily
     let result = db
         .get_models()
@@ -21,28 +83,111 @@ pub async fn get_models(
         .with_context(|| "failed to execute get_models")
         .unwrap();
 
-    Ok(Json(GetRegisteredModelsResponse { models: result }))
+    Ok(Json(GetRegisteredModelsResponse { models: result }).into_response())
+}
+
+/// Whether the `Accept` header lists NDJSON before any JSON media range.
+fn wants_ndjson(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+
+    for media_range in accept.split(',') {
+        match media_range.split(';').next().unwrap_or_default().trim() {
+            NDJSON => return true,
+            "application/json" | "application/*" | "*/*" => return false,
+            _ => continue,
+        }
+    }
+
+    false
+}
+
+/// Stream the catalog as NDJSON, reading it from the DB a page at a time so large catalogs are never
+/// held in memory all at once. A DB error part way through aborts the response, so clients can tell
+/// a truncated list from a complete one.
+fn stream_models(db: Arc<DB>) -> Response {
+    let (sender, receiver) = channel::<io::Result<Bytes>>(4);
+    tokio::spawn(async move {
+        let mut after: Option<String> = None;
+        loop {
+            let page = match db
+                .get_models_page(after.as_deref(), MODEL_STREAM_PAGE)
+                .await
+            {
+                Ok(page) => page,
+                Err(err) => {
+                    error!("failed to read page of models after {:?}: {:#}", after, err);
+                    let _ = sender.send(Err(io::Error::other(err.to_string()))).await;
+                    return;
+                }
+            };
+
+            let mut lines = Vec::new();
+            for model in &page {
+                serde_json::to_writer(&mut lines, model).expect("serialize model");
+                lines.push(b'\n');
+            }
+            if sender.send(Ok(Bytes::from(lines))).await.is_err() || page.len() < MODEL_STREAM_PAGE
+            {
+                return;
+            }
+            after = page.last().map(|model| model.name.clone());
+        }
+    });
+
+    (
+        [(CONTENT_TYPE, NDJSON)],
+        StreamBody::new(ReceiverStream::new(receiver)),
+    )
+        .into_response()
 }
 
+#[utoipa::path(
+    get, path = "/v1/models/{model_name}/description", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    responses((status = 200, body = String), (status = 404))
+)]
 pub async fn get_model_description(
     State(AppState {
-        db,
-        model: _,
+        db: _,
+        pool: _,
         importer: _,
+        stream_config: _,
+        descriptions,
+        generations: _,
+        inflight: _,
+        redactor: _,
+        models_dir: _,
     }): State<AppState>,
     Path(model_name): Path<String>,
 ) -> Result<Json<String>, StatusCode> {
     // TODO(aduffy): actually make this return the right error type
-    let desc = db.get_model_description(&model_name).await.unwrap();
+    let desc = descriptions.get(&model_name).await.unwrap();
 
     Ok(Json(desc))
 }
 
+/// Set a model's description. Depending on configuration it may be committed after a short delay,
+/// see [crate::descriptions].
+#[utoipa::path(
+    put, path = "/v1/models/{model_name}/description", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    request_body(content = String, content_type = "text/plain"),
+    responses((status = 204))
+)]
 pub async fn update_model_description(
     State(AppState {
-        db,
-        model: _,
+        db: _,
+        pool: _,
         importer: _,
+        stream_config: _,
+        descriptions,
+        generations: _,
+        inflight: _,
+        redactor: _,
+        models_dir: _,
     }): State<AppState>,
     Path(model_name): Path<String>,
     RawBody(mut updated_desc): RawBody,
@@ -51,24 +196,40 @@ pub async fn update_model_description(
     let desc = String::from_utf8(data.to_vec()).unwrap();
 
     // NOTE: this will fail at runtime which is bad
-    db.update_model_description(&model_name, &desc)
-        .await
-        .unwrap();
+    descriptions.update(&model_name, desc).await.unwrap();
 
     StatusCode::NO_CONTENT
 }
 
+#[utoipa::path(
+    post, path = "/v1/models/{model_name}/name", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    request_body(content = String, content_type = "text/plain", description = "The new name"),
+    responses((status = 204))
+)]
 pub async fn rename_model(
     State(AppState {
         db,
-        model: _,
+        pool: _,
         importer: _,
+        stream_config: _,
+        descriptions,
+        generations: _,
+        inflight: _,
+        redactor: _,
+        models_dir: _,
     }): State<AppState>,
     Path(model_name): Path<String>,
     RawBody(mut new_name): RawBody,
 ) -> StatusCode {
     let data = new_name.data().await.unwrap().unwrap();
     let new_name = String::from_utf8(data.to_vec()).unwrap();
+    // Pending descriptions are keyed by name, so commit this one before the name changes.
+    descriptions
+        .flush(&model_name)
+        .await
+        .context("failed to write pending description")
+        .unwrap();
     db.rename_model(&model_name, &new_name)
         .await
         .context("DB::rename_model failed")
@@ -77,11 +238,22 @@ pub async fn rename_model(
     StatusCode::NO_CONTENT
 }
 
+#[utoipa::path(
+    delete, path = "/v1/models/{model_name}/versions/{version}", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model"), ("version" = String, Path, description = "Semver of the model version")),
+    responses((status = 204))
+)]
 pub async fn delete_model_version(
     State(AppState {
         db,
-        model: _,
+        pool: _,
         importer: _,
+        stream_config: _,
+        descriptions: _,
+        generations: _,
+        inflight: _,
+        redactor: _,
+        models_dir: _,
     }): State<AppState>,
     Path((model_name, version)): Path<(String, semver::Version)>,
 ) -> StatusCode {
@@ -92,24 +264,597 @@ pub async fn delete_model_version(
     StatusCode::NO_CONTENT
 }
 
+/// Relabel a version of a model with a different version number, e.g. one registered with the wrong
+/// semver, without re-importing it.
+#[utoipa::path(
+    post, path = "/v1/models/{model_name}/versions/{version}/rename", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model"), ("version" = String, Path, description = "Semver of the model version")),
+    request_body = RenameVersionRequest,
+    responses(
+        (status = 204),
+        (status = 404, description = "No such model or version"),
+        (status = 409, description = "The new version is already registered")
+    )
+)]
+pub async fn rename_model_version(
+    State(app_state): State<AppState>,
+    Path((model_name, version)): Path<(String, semver::Version)>,
+    Json(request): Json<RenameVersionRequest>,
+) -> StatusCode {
+    let result = app_state
+        .db
+        .rename_model_version(&model_name, &version, &request.new_version)
+        .await;
+    if let Err(err) = result {
+        return match err.downcast_ref::<DBError>() {
+            Some(DBError::VersionNotFound) => StatusCode::NOT_FOUND,
+            Some(DBError::VersionExists) => StatusCode::CONFLICT,
+            None => match err.downcast_ref::<rusqlite::Error>() {
+                Some(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+        };
+    }
+
+    // The pool keys loaded models by version, drop the stale entry.
+    app_state.pool.unload(&model_name).await;
+
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    delete, path = "/v1/models/{model_name}", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    responses((status = 204))
+)]
 pub async fn delete_model(
     State(AppState {
         db,
-        model: _,
+        pool: _,
         importer: _,
+        stream_config: _,
+        descriptions,
+        generations: _,
+        inflight: _,
+        redactor: _,
+        models_dir: _,
     }): State<AppState>,
     Path(model_name): Path<String>,
 ) -> StatusCode {
+    descriptions.discard(&model_name).await;
     db.delete_model(&model_name).await.unwrap();
 
     StatusCode::NO_CONTENT
 }
 
+/// Register models that are already on disk from a manifest, without importing them. The manifest
+/// is registered in one transaction: if any entry fails, none are registered.
+#[utoipa::path(
+    post, path = "/v1/models/bulk", tag = "models",
+    request_body = BulkRegisterRequest,
+    responses(
+        (status = 200, body = BulkRegisterResponse),
+        (status = 400, body = BulkRegisterResponse, description = "Some entry failed, nothing was registered")
+    )
+)]
+pub async fn register_models(
+    State(app_state): State<AppState>,
+    Json(request): Json<BulkRegisterRequest>,
+) -> Result<(StatusCode, Json<BulkRegisterResponse>), StatusCode> {
+    let imported_at = time::OffsetDateTime::now_utc();
+    let mut results: Vec<Option<BulkRegisterResult>> = Vec::with_capacity(request.models.len());
+    let mut register_requests = Vec::new();
+
+    // Catch missing and unloadable files up front, the DB would register them regardless. Checking
+    // the files reads from disk, so every entry is checked on a blocking thread.
+    let checked = tokio::task::spawn_blocking(move || {
+        request
+            .models
+            .into_iter()
+            .map(|entry| {
+                let params = ModelParams::COMPLETION(CompletionModelParams {
+                    model_path: entry.model_path.clone(),
+                    default_sampling: entry.default_sampling.clone(),
+                });
+                let errors = validate_model_params(Runtime::Ggml, &params);
+                // Only the header is read, so this is cheap next to hashing.
+                let quantization = read_quantization(&entry.model_path).ok().flatten();
+                (entry, params, errors, quantization)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for (entry, params, errors, quantization) in checked {
+        if !errors.is_empty() {
+            let error = errors
+                .iter()
+                .map(|error| format!("{}: {}", error.field, error.message))
+                .collect::<Vec<_>>()
+                .join("; ");
+            results.push(Some(BulkRegisterResult::Failed { error }));
+            continue;
+        }
+
+        results.push(None);
+        register_requests.push(RegisterModelRequest {
+            model: entry.model,
+            version: entry.version,
+            model_type: ModelType::Completion,
+            runtime: Runtime::Ggml,
+            import_metadata: ImportMetadata {
+                imported_at,
+                source: ImportSource::DISK {
+                    source: DiskLocator {
+                        path: entry.model_path,
+                    },
+                },
+            },
+            internal_params: params,
+            // Hashing every file would make bulk registration slow. The size and checksum are filled
+            // in the first time each model is loaded instead.
+            file: None,
+            quantization,
+        });
+    }
+
+    let any_invalid = results.iter().any(Option::is_some);
+    let registered = if any_invalid {
+        // Nothing would be committed, so don't touch the DB.
+        Vec::new()
+    } else {
+        app_state
+            .db
+            .register_models(&register_requests)
+            .await
+            .context("failed to register models")
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    };
+    let committed = !any_invalid && registered.iter().all(|result| result.is_ok());
+
+    let mut registered = registered.into_iter();
+    let results: Vec<BulkRegisterResult> = results
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|| match registered.next() {
+                Some(Ok(model_id)) if committed => BulkRegisterResult::Registered { model_id },
+                Some(Err(err)) => BulkRegisterResult::Failed {
+                    error: format!("{:#}", err),
+                },
+                _ => BulkRegisterResult::RolledBack,
+            })
+        })
+        .collect();
+
+    let status = if committed {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+
+    Ok((status, Json(BulkRegisterResponse { committed, results })))
+}
+
+/// Set the quantization requests get when they don't pick a version or quantization themselves. It
+/// must be the quantization of one of the model's versions.
+#[utoipa::path(
+    put, path = "/v1/models/{model_name}/default-quantization", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    request_body = SetDefaultQuantizationRequest,
+    responses((status = 204), (status = 400, description = "No version has that quantization"), (status = 404))
+)]
+pub async fn set_default_quantization(
+    State(app_state): State<AppState>,
+    Path(model_name): Path<String>,
+    Json(request): Json<SetDefaultQuantizationRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if let Some(quantization) = &request.quantization {
+        let (_, versions) = app_state
+            .db
+            .get_version_quantizations(&model_name)
+            .await
+            .context("failed to look up quantizations")
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        // A typo would otherwise leave every request that relies on the default without a version.
+        if !versions
+            .iter()
+            .any(|(_, candidate)| candidate.as_ref() == Some(quantization))
+        {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    app_state
+        .db
+        .set_default_quantization(&model_name, request.quantization.as_deref())
+        .await
+        .context("failed to set default quantization")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Report registered model versions whose files are missing from disk, and files in `MODELS_DIR`
+/// that no version is registered from.
+#[utoipa::path(
+    get, path = "/v1/models/orphans", tag = "models",
+    responses((status = 200, body = OrphansResponse))
+)]
+pub async fn get_orphans(
+    State(app_state): State<AppState>,
+) -> Result<Json<OrphansResponse>, StatusCode> {
+    let registered = app_state
+        .db
+        .get_model_files()
+        .await
+        .context("failed to list model files")
+        .map_err(|err| {
+            error!("{:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let models_dir = app_state.models_dir;
+    let orphans = tokio::task::spawn_blocking(move || {
+        orphans::find_orphans(registered, models_dir.as_deref())
+    })
+    .await
+    .context("orphan scan panicked")
+    .and_then(|orphans| orphans)
+    .map_err(|err| {
+        error!("failed to scan for orphans: {:#}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(orphans))
+}
+
+/// Set how many versions of a model are kept as new ones are registered.
+#[utoipa::path(
+    put, path = "/v1/models/{model_name}/max-versions", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    request_body = SetMaxVersionsRequest,
+    responses((status = 204), (status = 400), (status = 404))
+)]
+pub async fn set_max_versions(
+    State(app_state): State<AppState>,
+    Path(model_name): Path<String>,
+    Json(request): Json<SetMaxVersionsRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if request.max_versions == Some(0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    app_state
+        .db
+        .set_max_versions(&model_name, request.max_versions)
+        .await
+        .context("failed to set max versions")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Set the most memory a model may take once loaded. Unloads it, so the next request loads it under
+/// the new limit.
+#[utoipa::path(
+    put, path = "/v1/models/{model_name}/max-memory", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    request_body = SetMaxMemoryRequest,
+    responses((status = 204), (status = 400), (status = 404))
+)]
+pub async fn set_max_memory(
+    State(app_state): State<AppState>,
+    Path(model_name): Path<String>,
+    Json(request): Json<SetMaxMemoryRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if request.max_memory_bytes == Some(0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    app_state
+        .db
+        .set_max_memory(&model_name, request.max_memory_bytes)
+        .await
+        .context("failed to set max memory")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    app_state.pool.unload(&model_name).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Page through the vocabulary of the latest version of a model, loading it if needed.
+#[utoipa::path(
+    get, path = "/v1/models/{model_name}/vocab", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model"), VocabQuery),
+    responses(
+        (status = 200, body = VocabResponse),
+        (status = 400),
+        (status = 404),
+        (status = 422, body = ErrorResponse, description = "The model is over its memory limit")
+    )
+)]
+pub async fn get_vocab(
+    State(app_state): State<AppState>,
+    Path(model_name): Path<String>,
+    Query(query): Query<VocabQuery>,
+) -> Result<Json<VocabResponse>, ApiError> {
+    if query.limit == 0 || query.limit > MAX_VOCAB_PAGE {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let model = get_model(&app_state, &model_name, &VersionSelector::default()).await?;
+    let ids = query.offset..query.offset.saturating_add(query.limit);
+    let (n_vocab, vocab) = model
+        .lock_for_generation()
+        .await
+        .run_blocking(move |model| (model.n_vocab(), model.vocab(ids)))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let tokens = vocab
+        .into_iter()
+        .map(|(id, text)| VocabToken { id, text })
+        .collect();
+
+    Ok(Json(VocabResponse {
+        n_vocab,
+        offset: query.offset,
+        tokens,
+    }))
+}
+
+/// Describe what a version of a model supports: its context size, chat template, sampling features
+/// and so on. Reads the model file's GGUF metadata, but doesn't load the model. The context size is
+/// the one the model is loaded with, which may be less than it was trained with.
+#[utoipa::path(
+    get, path = "/v1/models/{model_name}/capabilities", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model"), CapabilitiesQuery),
+    responses((status = 200, body = ModelCapabilities), (status = 404))
+)]
+pub async fn get_model_capabilities(
+    State(app_state): State<AppState>,
+    Path(model_name): Path<String>,
+    Query(query): Query<CapabilitiesQuery>,
+) -> Result<Json<ModelCapabilities>, StatusCode> {
+    let (default_quantization, versions) = app_state
+        .db
+        .get_version_quantizations(&model_name)
+        .await
+        .context("failed to look up model")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let selector = VersionSelector {
+        version: query.version,
+        quantization: query.quantization,
+    };
+    let version = select_version(&versions, default_quantization.as_deref(), &selector)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let quantization = versions
+        .into_iter()
+        .find(|(candidate, _)| *candidate == version)
+        .and_then(|(_, quantization)| quantization);
+
+    let (runtime, ModelParams::COMPLETION(params)) = app_state
+        .db
+        .get_model_version_params(&model_name, &version)
+        .await
+        .context("failed to get model params")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // A missing or unreadable file still has its stored capabilities described.
+    let model_path = params.model_path.clone();
+    let load_params = app_state.pool.load_params().clone();
+    let (metadata, n_ctx) = tokio::task::spawn_blocking(move || {
+        (
+            GgufMetadata::read(&model_path),
+            load_params.n_ctx(&model_path),
+        )
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let metadata = metadata
+        .map_err(|err| {
+            error!(
+                "failed to read metadata of {}@{}: {:#}",
+                model_name, version, err
+            )
+        })
+        .ok();
+    let n_ctx = n_ctx
+        .map_err(|err| {
+            error!(
+                "failed to work out the context size of {}@{}: {:#}",
+                model_name, version, err
+            )
+        })
+        .ok();
+
+    Ok(Json(capabilities::describe(
+        &model_name,
+        &version,
+        runtime,
+        quantization,
+        &params,
+        metadata.as_ref(),
+        n_ctx,
+        system::acceleration()
+            .into_iter()
+            .map(Acceleration::from)
+            .collect(),
+    )))
+}
+
+/// Queued and in-flight requests for a model, and their recent latency, across all of its loaded
+/// versions. Doesn't load the model.
+#[utoipa::path(
+    get, path = "/v1/models/{model_name}/load-stats", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model")),
+    responses((status = 200, body = LoadStatsResponse), (status = 404))
+)]
+pub async fn get_load_stats(
+    State(app_state): State<AppState>,
+    Path(model_name): Path<String>,
+) -> Result<Json<LoadStatsResponse>, StatusCode> {
+    let loaded = app_state.pool.get_loaded(&model_name).await;
+    if loaded.is_empty() {
+        app_state
+            .db
+            .get_model_params(&model_name)
+            .await
+            .context("failed to look up model")
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+
+        return Ok(Json(LoadStatsResponse {
+            loaded: false,
+            queued: 0,
+            in_flight: 0,
+            avg_latency_ms: None,
+        }));
+    }
+
+    let latencies: Vec<f64> = loaded
+        .iter()
+        .filter_map(|model| model.load_stats.avg_latency())
+        .map(|latency| latency.as_secs_f64() * 1000.0)
+        .collect();
+    Ok(Json(LoadStatsResponse {
+        loaded: true,
+        queued: loaded.iter().map(|model| model.load_stats.queued()).sum(),
+        in_flight: loaded
+            .iter()
+            .map(|model| model.load_stats.in_flight())
+            .sum(),
+        avg_latency_ms: (!latencies.is_empty())
+            .then(|| latencies.iter().sum::<f64>() / latencies.len() as f64),
+    }))
+}
+
+#[utoipa::path(
+    get, path = "/v1/models/{model_name}/versions/{version}/params", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model"), ("version" = String, Path, description = "Semver of the model version")),
+    responses((status = 200, body = ModelParams), (status = 404))
+)]
+pub async fn get_model_version_params(
+    State(app_state): State<AppState>,
+    Path((model_name, version)): Path<(String, semver::Version)>,
+) -> Result<Json<ModelParams>, StatusCode> {
+    let (_, params) = app_state
+        .db
+        .get_model_version_params(&model_name, &version)
+        .await
+        .context("failed to get model params")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(Json(params))
+}
+
+/// Replace the params of a model version. The params are validated first, so a bad write can't leave
+/// the model unable to load. The model is unloaded if it was loaded, to pick up the new params.
+#[utoipa::path(
+    put, path = "/v1/models/{model_name}/versions/{version}/params", tag = "models",
+    params(("model_name" = String, Path, description = "Name of the registered model"), ("version" = String, Path, description = "Semver of the model version")),
+    request_body = ModelParams,
+    responses((status = 204), (status = 400, body = ErrorResponse), (status = 404))
+)]
+pub async fn update_model_version_params(
+    State(app_state): State<AppState>,
+    Path((model_name, version)): Path<(String, semver::Version)>,
+    Json(params): Json<ModelParams>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let (runtime, _) = app_state
+        .db
+        .get_model_version_params(&model_name, &version)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse::new(
+                    "not_found",
+                    format!("model {}@{} not found", model_name, version),
+                )),
+            )
+        })?;
+
+    let fields = validate_model_params(runtime, &params);
+    if !fields.is_empty() {
+        let mut body = ErrorResponse::new("invalid_params", "model params failed validation");
+        body.error.fields = fields;
+        return Err((StatusCode::BAD_REQUEST, Json(body)));
+    }
+
+    app_state
+        .db
+        .update_model_version_params(&model_name, &version, &params)
+        .await
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse::new("internal", format!("{:#}", err))),
+            )
+        })?;
+    app_state.pool.unload(&model_name).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Check that params describe a model the runtime can load, returning a problem for each bad field.
+fn validate_model_params(runtime: Runtime, params: &ModelParams) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let mut field_error = |field: &str, message: String| {
+        errors.push(FieldError {
+            field: field.to_owned(),
+            message,
+        })
+    };
+
+    let ModelParams::COMPLETION(params) = params;
+    let path = &params.model_path;
+    if !path.is_file() {
+        field_error("model_path", format!("{:?} is not a file", path));
+    } else {
+        match runtime {
+            Runtime::Ggml => {
+                let mut magic = [0u8; 4];
+                let is_gguf = File::open(path)
+                    .and_then(|mut file| file.read_exact(&mut magic))
+                    .is_ok()
+                    && &magic == GGUF_MAGIC;
+                if !is_gguf {
+                    field_error(
+                        "runtime",
+                        format!(
+                            "the ggml runtime can only load GGUF files, {:?} isn't one",
+                            path
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(sampling) = &params.default_sampling {
+        if let Err(err) = llamacpp::SamplingParams::from(sampling.clone()).validate() {
+            field_error("default_sampling", err.to_string());
+        }
+    }
+
+    errors
+}
+
 #[cfg(test)]
 mod test {
     use std::path::PathBuf;
 
-    use crate::api_types::{HFLocator, Locator, ModelType, RegisteredModel, Runtime};
+    use tempdir::TempDir;
+    use time::macros::datetime;
+
+    use super::validate_model_params;
+
+    use crate::api_types::{
+        BatchStreamEvent, ChoicesResponse, CompletionChoice, CompletionModelParams, DiskLocator,
+        FinishReason, GenerateResponse, HFLocator, ImportMetadata, ImportSource, Locator,
+        LogitBias, LogitBiasToken, ModelParams, ModelType, RegisteredModel, Runtime,
+        SamplingParams, StopSequence,
+    };
 
     #[test]
     pub fn api_serde() {
@@ -119,6 +864,7 @@ mod test {
             runtime: Runtime::Ggml,
             name: "my-model".to_owned(),
             versions: vec![],
+            default_quantization: None,
         };
 
         assert_eq!(
@@ -162,4 +908,188 @@ mod test {
             locator
         );
     }
+
+    #[test]
+    pub fn batch_stream_event_serde() {
+        assert_eq!(
+            r#"{"type":"token","index":1,"token":" Hello"}"#,
+            serde_json::to_string(&BatchStreamEvent::Token {
+                index: 1,
+                token: " Hello".to_owned(),
+            })
+            .unwrap()
+        );
+
+        assert_eq!(
+            r#"{"type":"token_bytes","index":2,"bytes":[226,150]}"#,
+            serde_json::to_string(&BatchStreamEvent::TokenBytes {
+                index: 2,
+                bytes: vec![0xe2, 0x96],
+            })
+            .unwrap()
+        );
+
+        assert_eq!(
+            r#"{"type":"done","index":0}"#,
+            serde_json::to_string(&BatchStreamEvent::Done { index: 0 }).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn import_metadata_rfc3339_serde() {
+        let metadata = ImportMetadata {
+            imported_at: datetime!(2023-09-01 12:34:56.123456789 +05:30),
+            source: ImportSource::DISK {
+                source: DiskLocator {
+                    path: PathBuf::from("/models/model.gguf"),
+                },
+            },
+        };
+        let json = r#"{"imported_at":"2023-09-01T12:34:56.123456789+05:30","source":{"type":"importv1/disk","source":{"path":"/models/model.gguf"}}}"#;
+
+        assert_eq!(json, serde_json::to_string(&metadata).unwrap());
+
+        let parsed = serde_json::from_str::<ImportMetadata>(json).unwrap();
+        assert_eq!(parsed, metadata);
+        assert_eq!(parsed.imported_at.offset(), metadata.imported_at.offset());
+    }
+
+    #[test]
+    pub fn logit_bias_serde() {
+        let logit_bias = serde_json::from_str::<Vec<LogitBias>>(
+            r#"[{"token_id": 13, "bias": -100.0}, {"text": " Paris", "bias": 5.0}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            logit_bias,
+            vec![
+                LogitBias {
+                    token: LogitBiasToken::Id { token_id: 13 },
+                    bias: -100.0,
+                },
+                LogitBias {
+                    token: LogitBiasToken::Text {
+                        text: " Paris".to_owned()
+                    },
+                    bias: 5.0,
+                },
+            ]
+        );
+
+        assert!(serde_json::from_str::<LogitBias>(r#"{"bias": 1.0}"#).is_err());
+    }
+
+    #[test]
+    pub fn stop_sequence_serde() {
+        let stop = serde_json::from_str::<Vec<StopSequence>>(
+            r#"["\n\n", {"token": "<|im_end|>"}, {"token_id": 2}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            stop,
+            vec![
+                StopSequence::Text("\n\n".to_owned()),
+                StopSequence::Token {
+                    token: "<|im_end|>".to_owned()
+                },
+                StopSequence::TokenId { token_id: 2 },
+            ]
+        );
+
+        assert!(serde_json::from_str::<StopSequence>(r#"{"id": 2}"#).is_err());
+    }
+
+    #[test]
+    pub fn choices_response_serde() {
+        let mut response = ChoicesResponse {
+            model_id: "my-model".to_owned(),
+            version: semver::Version::new(0, 2, 0),
+            quantization: Some("Q4_K_M".to_owned()),
+            choices: vec![CompletionChoice {
+                text: " world".to_owned(),
+                index: 0,
+                logprobs: None,
+                finish_reason: FinishReason::Length,
+            }],
+            prompt_truncated: false,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"model_id":"my-model","version":"0.2.0","quantization":"Q4_K_M","choices":[{"text":" world","index":0,"logprobs":null,"finish_reason":"length"}],"prompt_truncated":false}"#
+        );
+
+        response.quantization = None;
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"model_id":"my-model","version":"0.2.0","choices":[{"text":" world","index":0,"logprobs":null,"finish_reason":"length"}],"prompt_truncated":false}"#
+        );
+    }
+
+    #[test]
+    pub fn generate_response_version() {
+        let mut response = GenerateResponse {
+            model_id: "my-model".to_owned(),
+            version: semver::Version::new(0, 2, 0),
+            quantization: Some("Q4_K_M".to_owned()),
+            completion: " world".to_owned(),
+            finish_reason: FinishReason::Length,
+            prompt_truncated: false,
+            sampling: SamplingParams::default(),
+            timings: None,
+            continuation_key: None,
+            cache_reused: false,
+            self_consistency: None,
+            top_alternatives: None,
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["version"], "0.2.0");
+        assert_eq!(json["quantization"], "Q4_K_M");
+
+        // Versions registered without a quantization leave it out.
+        response.quantization = None;
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json.get("quantization").is_none());
+    }
+
+    #[test]
+    pub fn test_validate_model_params() {
+        let dir = TempDir::new("params_test").unwrap();
+        let gguf_path = dir.path().join("model.gguf");
+        std::fs::write(&gguf_path, b"GGUF\x02\x00\x00\x00").unwrap();
+        let bin_path = dir.path().join("model.bin");
+        std::fs::write(&bin_path, b"ggjt").unwrap();
+
+        let params = |model_path: PathBuf, default_sampling: Option<SamplingParams>| {
+            ModelParams::COMPLETION(CompletionModelParams {
+                model_path,
+                default_sampling,
+            })
+        };
+        let fields = |params: &ModelParams| -> Vec<String> {
+            validate_model_params(Runtime::Ggml, params)
+                .into_iter()
+                .map(|error| error.field)
+                .collect()
+        };
+
+        assert!(fields(&params(gguf_path.clone(), Some(SamplingParams::default()))).is_empty());
+        assert_eq!(
+            fields(&params(dir.path().join("missing.gguf"), None)),
+            vec!["model_path"]
+        );
+        assert_eq!(fields(&params(bin_path, None)), vec!["runtime"]);
+        assert_eq!(
+            fields(&params(
+                gguf_path,
+                Some(SamplingParams {
+                    top_p: 1.5,
+                    ..Default::default()
+                })
+            )),
+            vec!["default_sampling"]
+        );
+    }
 }
@@ -0,0 +1,166 @@
+use axum::Json;
+use utoipa::OpenApi;
+
+use crate::api_types::{
+    Acceleration, AnswerVotes, BatchGenerateRequest, BatchStreamEvent, BulkRegisterEntry,
+    BulkRegisterRequest, BulkRegisterResponse, BulkRegisterResult, CancelAllImportsResponse,
+    ChatMessage, ChatRenderRequest, ChatRenderResponse, ChatRole, ChoicesResponse,
+    CompletionChoice, CompletionModelParams, ContinuationSource, ContinueFrom, DiskLocator,
+    ErrorDetail, ErrorResponse, FieldError, FinishReason, GenerateRequest, GenerateResponse,
+    GenerateResponseFormat, GetAllJobStatusResponse, GetRegisteredModelsResponse, Guidance, HFFile,
+    HFLocator, ImportJobStatus, ImportMetadata, ImportSource, ListHFFilesResponse,
+    LoadStatsResponse, Locator, LogitBias, LogitBiasToken, MemoryLimit, ModelCapabilities,
+    ModelFile, ModelParams, ModelType, ModelVersion, OrphansResponse, RegisteredModel,
+    RegisteredModelFile, RenameVersionRequest, Runtime, SamplingFeature, SamplingParams,
+    SelfConsistency, SelfConsistencyResult, SetDefaultQuantizationRequest, SetMaxMemoryRequest,
+    SetMaxVersionsRequest, StopSequence, SweepCompletion, SweepRequest, SweepResponse, Timings,
+    TokenAlternative, VocabResponse, VocabToken,
+};
+
+use super::{chat, generate, hfhub, imports, models};
+
+/// OpenAPI description of the API, for generating clients. Handlers are annotated with
+/// `#[utoipa::path]`, and every type their requests and responses refer to must be listed here.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "models.rs", description = "Serve and manage local language models"),
+    paths(
+        models::get_models,
+        models::register_models,
+        models::get_orphans,
+        models::get_model_description,
+        models::update_model_description,
+        models::rename_model,
+        models::delete_model,
+        models::get_load_stats,
+        models::get_vocab,
+        models::get_model_capabilities,
+        models::set_default_quantization,
+        models::set_max_versions,
+        models::set_max_memory,
+        models::delete_model_version,
+        models::rename_model_version,
+        models::get_model_version_params,
+        models::update_model_version_params,
+        generate::generate,
+        generate::generate_batch_stream,
+        generate::generate_sweep,
+        chat::render_chat,
+        imports::import_model,
+        imports::import_job_status_all,
+        imports::cancel_all_imports,
+        imports::import_job_status,
+        imports::cancel_import,
+        imports::retry_import,
+        hfhub::ls_repo_files,
+    ),
+    components(schemas(
+        Acceleration,
+        AnswerVotes,
+        BatchGenerateRequest,
+        BatchStreamEvent,
+        BulkRegisterEntry,
+        BulkRegisterRequest,
+        BulkRegisterResponse,
+        BulkRegisterResult,
+        CancelAllImportsResponse,
+        ChatMessage,
+        ChatRenderRequest,
+        ChatRenderResponse,
+        ChatRole,
+        ChoicesResponse,
+        CompletionChoice,
+        CompletionModelParams,
+        ContinuationSource,
+        ContinueFrom,
+        DiskLocator,
+        ErrorDetail,
+        ErrorResponse,
+        FieldError,
+        MemoryLimit,
+        FinishReason,
+        GenerateRequest,
+        GenerateResponse,
+        GenerateResponseFormat,
+        GetAllJobStatusResponse,
+        GetRegisteredModelsResponse,
+        Guidance,
+        HFFile,
+        HFLocator,
+        ImportJobStatus,
+        ImportMetadata,
+        ImportSource,
+        ListHFFilesResponse,
+        LoadStatsResponse,
+        Locator,
+        LogitBias,
+        LogitBiasToken,
+        ModelCapabilities,
+        ModelFile,
+        ModelParams,
+        ModelType,
+        ModelVersion,
+        OrphansResponse,
+        RegisteredModel,
+        RegisteredModelFile,
+        RenameVersionRequest,
+        Runtime,
+        SamplingFeature,
+        SamplingParams,
+        SelfConsistency,
+        SelfConsistencyResult,
+        SetDefaultQuantizationRequest,
+        SetMaxMemoryRequest,
+        SetMaxVersionsRequest,
+        StopSequence,
+        SweepCompletion,
+        SweepRequest,
+        SweepResponse,
+        Timings,
+        TokenAlternative,
+        VocabResponse,
+        VocabToken,
+    )),
+    tags(
+        (name = "models", description = "Registered models and their versions"),
+        (name = "complete", description = "Running completions"),
+        (name = "chat", description = "Previewing the prompts conversations render to"),
+        (name = "imports", description = "Importing model files"),
+        (name = "hfhub", description = "Browsing Hugging Face repos to import from"),
+    )
+)]
+pub struct ApiDoc;
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+
+    #[test]
+    fn test_schema_refs_resolve() {
+        let doc = ApiDoc::openapi();
+        let schemas = &doc.components.as_ref().unwrap().schemas;
+        let json = doc.to_json().unwrap();
+
+        // A handler's types that weren't listed in the components would leave dangling refs.
+        let prefix = "\"#/components/schemas/";
+        for reference in json.split(prefix).skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(schemas.contains_key(name), "missing schema for {}", name);
+        }
+
+        for path in [
+            "/v1/models",
+            "/v1/complete",
+            "/v1/imports",
+            "/hf/ls/{community}/{repo_name}",
+        ] {
+            assert!(doc.paths.paths.contains_key(path), "missing path {}", path);
+        }
+    }
+}
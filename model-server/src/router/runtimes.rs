@@ -0,0 +1,35 @@
+use axum::Json;
+use llamacpp::system;
+
+use crate::api_types::{Acceleration, Runtime, RuntimeInfo, RuntimesResponse};
+
+/// List the runtimes models can be registered with, and what each can use on this machine.
+pub async fn list_runtimes() -> Json<RuntimesResponse> {
+    let ggml = RuntimeInfo {
+        runtime: Runtime::Ggml,
+        available: true,
+        acceleration: system::acceleration()
+            .into_iter()
+            .map(Acceleration::from)
+            .collect(),
+        cpu_features: system::cpu_features()
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+    };
+
+    Json(RuntimesResponse {
+        runtimes: vec![ggml],
+    })
+}
+
+impl From<system::Acceleration> for Acceleration {
+    fn from(acceleration: system::Acceleration) -> Self {
+        match acceleration {
+            system::Acceleration::Accelerate => Acceleration::Accelerate,
+            system::Acceleration::Blas => Acceleration::Blas,
+            system::Acceleration::Cuda => Acceleration::Cuda,
+            system::Acceleration::Metal => Acceleration::Metal,
+        }
+    }
+}
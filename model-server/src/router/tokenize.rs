@@ -0,0 +1,86 @@
+use anyhow::Context;
+use axum::{extract::State, http::StatusCode, Json};
+use llamacpp::TOKENIZE_CHUNK_BYTES;
+use log::debug;
+
+use crate::{
+    api_types::{
+        PreviewToken, TokenCountRequest, TokenCountResponse, TokenizePreviewRequest,
+        TokenizePreviewResponse,
+    },
+    quantization::VersionSelector,
+    state::AppState,
+};
+
+use super::{generate::get_model, ApiError};
+
+/// Split text into the model's tokens, with the part of the text each one covers, so a UI can
+/// highlight the token boundaries.
+pub async fn preview_tokens(
+    State(app_state): State<AppState>,
+    Json(params): Json<TokenizePreviewRequest>,
+) -> Result<Json<TokenizePreviewResponse>, ApiError> {
+    let model = get_model(&app_state, &params.model_id, &VersionSelector::default()).await?;
+    let text = params.text;
+    let pieces = model
+        .lock_for_generation()
+        .await
+        .run_blocking(move |model| model.token_pieces(&text))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .context("failed to tokenize text")
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let tokens: Vec<PreviewToken> = pieces
+        .into_iter()
+        .map(|piece| PreviewToken {
+            id: piece.id,
+            text: piece.text,
+            start: piece.offset.as_ref().map(|offset| offset.start),
+            end: piece.offset.map(|offset| offset.end),
+        })
+        .collect();
+
+    Ok(Json(TokenizePreviewResponse {
+        model_id: params.model_id,
+        n_tokens: tokens.len(),
+        tokens,
+    }))
+}
+
+/// Count the tokens of a text, e.g. to check a prompt fits before sending it. The text is tokenized a
+/// segment at a time on a blocking thread, only keeping count, so even documents of many megabytes
+/// neither need a buffer for all of their tokens nor hold up the runtime.
+pub async fn count_tokens(
+    State(app_state): State<AppState>,
+    Json(params): Json<TokenCountRequest>,
+) -> Result<Json<TokenCountResponse>, ApiError> {
+    let model = get_model(&app_state, &params.model_id, &VersionSelector::default()).await?;
+    let text = params.text;
+    let n_tokens = model
+        .lock_for_generation()
+        .await
+        .run_blocking(move |model| {
+            let mut n_tokens = 0;
+            for chunk in model.tokenize_chunks(&text, TOKENIZE_CHUNK_BYTES) {
+                let chunk = chunk?;
+                n_tokens += chunk.tokens.len();
+                debug!(
+                    "tokenized {}/{} bytes into {} tokens",
+                    chunk.bytes_done,
+                    text.len(),
+                    n_tokens
+                );
+            }
+            anyhow::Ok(n_tokens)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .context("failed to tokenize text")
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    Ok(Json(TokenCountResponse {
+        model_id: params.model_id,
+        n_tokens,
+    }))
+}
@@ -1,19 +1,248 @@
+use anyhow::Context;
 use rusqlite::Connection;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
 
-use crate::{db::tables::DB, import::Importer};
+use crate::{
+    api_types::SamplingParams, db::tables::DB, descriptions::DescriptionWriter, import::Importer,
+    inflight::InFlightGenerations, pool::ModelPool, redaction::Redactor,
+};
 
 pub struct ManagedModel {
-    pub model: Mutex<llamacpp::Model>,
+    pub model: Arc<Mutex<llamacpp::Model>>,
+
+    /// When the model was last handed out by the [ModelPool].
+    last_used_at: std::sync::Mutex<Instant>,
+
+    pub load_stats: Arc<LoadStats>,
+
+    /// The version of the model that was loaded, and its quantization if known.
+    pub version: semver::Version,
+    pub quantization: Option<String>,
+
+    /// Sampling params for completions that don't give their own, from the version's params.
+    pub default_sampling: Option<SamplingParams>,
 }
 
 impl ManagedModel {
-    pub fn new(model: llamacpp::Model) -> Self {
+    pub fn new(
+        model: llamacpp::Model,
+        version: semver::Version,
+        quantization: Option<String>,
+        default_sampling: Option<SamplingParams>,
+    ) -> Self {
         ManagedModel {
-            model: Mutex::new(model),
+            model: Arc::new(Mutex::new(model)),
+            last_used_at: std::sync::Mutex::new(Instant::now()),
+            load_stats: Arc::default(),
+            version,
+            quantization,
+            default_sampling,
+        }
+    }
+
+    /// The sampling params to complete with: the request's own if it gave any, else the model's.
+    pub fn sampling(&self, requested: Option<SamplingParams>) -> SamplingParams {
+        requested
+            .or_else(|| self.default_sampling.clone())
+            .unwrap_or_default()
+    }
+
+    /// Wait for exclusive use of the model to run a generation, keeping [ManagedModel::load_stats]
+    /// up to date. The request counts as queued until the model is free, then as in flight until the
+    /// returned guard is dropped.
+    ///
+    /// The guard owns the lock rather than borrowing the [ManagedModel], so it can be moved onto a
+    /// blocking thread, see [GenerationGuard::run_blocking].
+    pub async fn lock_for_generation(&self) -> GenerationGuard {
+        let enqueued_at = Instant::now();
+        let queued = QueuedGuard::new(&self.load_stats);
+        let model = Arc::clone(&self.model).lock_owned().await;
+        drop(queued);
+        self.load_stats.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        GenerationGuard {
+            model,
+            load_stats: Arc::clone(&self.load_stats),
+            enqueued_at,
+        }
+    }
+
+    /// Record that the model was just used.
+    pub fn touch(&self) {
+        *self.last_used_at.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since the model was last used.
+    pub fn idle_for(&self) -> Duration {
+        self.last_used_at.lock().unwrap().elapsed()
+    }
+}
+
+/// Number of recent requests [LoadStats::avg_latency] averages over.
+const LATENCY_WINDOW: usize = 32;
+
+/// Request counters for a loaded model, for autoscalers and load shedding.
+#[derive(Default)]
+pub struct LoadStats {
+    queued: AtomicUsize,
+    in_flight: AtomicUsize,
+    recent_latencies: std::sync::Mutex<VecDeque<Duration>>,
+}
+
+impl LoadStats {
+    /// Requests waiting for the model to become free.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Requests currently generating. The model runs one generation at a time, so this is 0 or 1.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Average time from being queued to finishing, over the most recent requests. `None` until a
+    /// request has finished.
+    pub fn avg_latency(&self) -> Option<Duration> {
+        let latencies = self.recent_latencies.lock().unwrap();
+        if latencies.is_empty() {
+            return None;
+        }
+
+        Some(latencies.iter().sum::<Duration>() / latencies.len() as u32)
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.recent_latencies.lock().unwrap();
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+}
+
+/// Counts a request as queued in [LoadStats] until dropped, including when the request gives up
+/// waiting for the model, e.g. because the client went away.
+struct QueuedGuard<'a> {
+    load_stats: &'a LoadStats,
+}
+
+impl<'a> QueuedGuard<'a> {
+    fn new(load_stats: &'a LoadStats) -> Self {
+        load_stats.queued.fetch_add(1, Ordering::Relaxed);
+        Self { load_stats }
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.load_stats.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Exclusive access to a model for one request, see [ManagedModel::lock_for_generation].
+pub struct GenerationGuard {
+    model: OwnedMutexGuard<llamacpp::Model>,
+    load_stats: Arc<LoadStats>,
+    enqueued_at: Instant,
+}
+
+impl GenerationGuard {
+    /// Run `f` against the model on a blocking thread, handing the guard over to it. Generation
+    /// blocks for as long as it takes to evaluate every token, so it mustn't run on the async
+    /// runtime, and no task is left holding the lock across it. The model is released once `f`
+    /// returns, even if the caller stopped waiting for the result.
+    pub async fn run_blocking<F, T>(self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut llamacpp::Model) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || {
+            let mut guard = self;
+            f(&mut guard)
+        })
+        .await
+        .context("model task panicked")
+    }
+}
+
+impl Deref for GenerationGuard {
+    type Target = llamacpp::Model;
+
+    fn deref(&self) -> &Self::Target {
+        &self.model
+    }
+}
+
+impl DerefMut for GenerationGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.model
+    }
+}
+
+impl Drop for GenerationGuard {
+    fn drop(&mut self) {
+        self.load_stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.load_stats.record_latency(self.enqueued_at.elapsed());
+    }
+}
+
+/// Server-wide cap on concurrent generations across all models, on top of each model running one
+/// generation at a time, so loading more models doesn't oversubscribe the CPU.
+pub struct GenerationLimiter {
+    permits: Arc<Semaphore>,
+    limit: usize,
+    rejected: AtomicU64,
+}
+
+impl GenerationLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit,
+            rejected: AtomicU64::new(0),
         }
     }
+
+    /// As many generations as there are sets of [llamacpp::N_THREADS] cores.
+    pub fn default_limit() -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        (cores / llamacpp::N_THREADS as usize).max(1)
+    }
+
+    /// Claim a slot for one generation, held until the permit is dropped. `None` when every slot is
+    /// taken, in which case the request should be turned away rather than queued.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = Arc::clone(&self.permits).try_acquire_owned().ok();
+        if permit.is_none() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        permit
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Generations holding a slot, including those still waiting for their model to become free.
+    pub fn in_flight(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    /// Requests turned away since startup because every slot was taken.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
 }
 
 unsafe impl Send for ManagedModel {}
@@ -31,16 +260,94 @@ impl ManagedConnection {
     }
 }
 
-type ModelHandle = Arc<ManagedModel>;
+type PoolHandle = Arc<ModelPool>;
 type DBHandle = Arc<DB>;
 type ImporterHandle = Arc<dyn Importer + Sync + Send>;
 
+/// Settings for the SSE streaming endpoints.
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    /// How often to send a `: keepalive` comment on an otherwise quiet stream, so proxies and load
+    /// balancers don't close the connection while a slow generation is running. `None` disables
+    /// the heartbeat.
+    pub heartbeat_interval: Option<Duration>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Some(Duration::from_secs(15)),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    pub model: ModelHandle,
+    pub pool: PoolHandle,
     pub db: DBHandle,
     pub importer: ImporterHandle,
+    pub stream_config: StreamConfig,
+    pub descriptions: Arc<DescriptionWriter>,
+    pub generations: Arc<GenerationLimiter>,
+    pub inflight: Arc<InFlightGenerations>,
+    pub redactor: Arc<Redactor>,
+
+    /// Directory of model files the server manages, checked for files no model is registered from.
+    pub models_dir: Option<Arc<Path>>,
 }
 
 unsafe impl Send for AppState {}
 unsafe impl Sync for AppState {}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{GenerationLimiter, LoadStats, QueuedGuard, LATENCY_WINDOW};
+
+    #[test]
+    fn test_avg_latency_window() {
+        let stats = LoadStats::default();
+        assert_eq!(stats.avg_latency(), None);
+
+        stats.record_latency(Duration::from_millis(100));
+        stats.record_latency(Duration::from_millis(300));
+        assert_eq!(stats.avg_latency(), Some(Duration::from_millis(200)));
+
+        // Only the most recent requests count.
+        for _ in 0..LATENCY_WINDOW {
+            stats.record_latency(Duration::from_millis(50));
+        }
+        assert_eq!(stats.avg_latency(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_queued_guard() {
+        let stats = LoadStats::default();
+        let first = QueuedGuard::new(&stats);
+        let second = QueuedGuard::new(&stats);
+        assert_eq!(stats.queued(), 2);
+
+        // A request that stops waiting no longer counts as queued.
+        drop(first);
+        assert_eq!(stats.queued(), 1);
+        drop(second);
+        assert_eq!(stats.queued(), 0);
+    }
+
+    #[test]
+    fn test_generation_limiter() {
+        let limiter = GenerationLimiter::new(2);
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert_eq!(limiter.in_flight(), 2);
+
+        // Saturated requests are turned away, not queued.
+        assert!(limiter.try_acquire().is_none());
+        assert_eq!(limiter.rejected(), 1);
+
+        drop(first);
+        assert_eq!(limiter.in_flight(), 1);
+        assert!(limiter.try_acquire().is_some());
+    }
+}